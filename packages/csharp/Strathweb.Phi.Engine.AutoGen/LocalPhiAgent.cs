@@ -26,7 +26,7 @@ public class LocalPhiAgent : IAgent
         var conversationContext = GetConversationContext(messages);
         var inferenceOptions = GetInferenceOptions(options);
 
-        var response = PhiEngine.RunInference(prompt, conversationContext, inferenceOptions);
+        var response = PhiEngine.RunInference(prompt, conversationContext, inferenceOptions, null);
         var textMessage = new TextMessage(AutoGenRole.Assistant, response.resultText, Name);
         return Task.FromResult(textMessage as IMessage);
     }
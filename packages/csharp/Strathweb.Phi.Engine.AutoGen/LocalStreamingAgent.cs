@@ -28,7 +28,7 @@ public class LocalStreamingPhiAgent : LocalPhiAgent, IStreamingAgent
         {
             try
             {
-                var response = PhiEngine.RunInference(prompt, conversationContext, inferenceOptions);
+                var response = PhiEngine.RunInference(prompt, conversationContext, inferenceOptions, null);
             }
             catch (Exception)
             {
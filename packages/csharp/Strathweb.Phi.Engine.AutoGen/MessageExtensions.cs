@@ -47,6 +47,19 @@ static class InferenceOptionsBuilderExtensions
         builder.WithRepeatLastN(options.repeatLastN);
         builder.WithSeed(options.seed);
 
+        if (options.loraAdapter != null)
+        {
+            builder.WithLoraAdapter(options.loraAdapter);
+        }
+
+        if (options.requestId != null)
+        {
+            builder.WithRequestId(options.requestId);
+        }
+
+        builder.WithLogprobs(options.logprobs);
+        builder.WithStopSequences(options.stopSequences);
+
         return builder;
     }
 }
\ No newline at end of file
@@ -34,7 +34,7 @@ public class PhiEngineChatClient : IChatClient
         var conversationContext = GetConversationContext(messagesList);
         var inferenceOptions = GetInferenceOptions(options);
 
-        var response = _phiEngine.RunInference(prompt, conversationContext, inferenceOptions);
+        var response = _phiEngine.RunInference(prompt, conversationContext, inferenceOptions, null);
         var textMessage = new ChatMessage(ChatRole.Assistant, response.resultText);
         return Task.FromResult(new ChatResponse(new[] { textMessage }));
     }
@@ -56,7 +56,7 @@ public class PhiEngineChatClient : IChatClient
         {
             try
             {
-                _phiEngine.RunInference(prompt, conversationContext, inferenceOptions);
+                _phiEngine.RunInference(prompt, conversationContext, inferenceOptions, null);
             }
             catch (Exception)
             {
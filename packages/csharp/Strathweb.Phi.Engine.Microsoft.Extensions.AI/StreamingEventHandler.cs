@@ -7,18 +7,18 @@ public class StreamingEventHandler : PhiEventHandler
     private Channel<string> _tokenChannel;
     private TaskCompletionSource<bool> _inferenceStartedTcs = new();
 
-    public void OnInferenceToken(string token)
+    public void OnInferenceToken(string requestId, string token)
     {
         _tokenChannel?.Writer.TryWrite(token);
     }
 
-    public void OnInferenceStarted()
+    public void OnInferenceStarted(string requestId)
     {
         _tokenChannel = Channel.CreateUnbounded<string>();
         _inferenceStartedTcs.TrySetResult(true);
     }
 
-    public void OnInferenceEnded()
+    public void OnInferenceEnded(string requestId)
     {
         _tokenChannel?.Writer.Complete();
         _tokenChannel = null;
@@ -29,6 +29,18 @@ public class StreamingEventHandler : PhiEventHandler
     {
     }
 
+    public void OnDownloadStarted(string fileName, ulong totalBytes)
+    {
+    }
+
+    public void OnDownloadProgress(string fileName, ulong downloadedBytes)
+    {
+    }
+
+    public void OnDownloadCompleted(string fileName)
+    {
+    }
+
     public async IAsyncEnumerable<string> GetInferenceTokensAsync()
     {
         await _inferenceStartedTcs.Task;
@@ -22,27 +22,27 @@ var cacheDir = Path.Combine(Directory.GetCurrentDirectory(), "..", "..", "..", "
 var modelBuilder = new PhiEngineBuilder();
 
 PhiModelProvider modelProvider = isNonQuantizedMode ?  
-    new PhiModelProvider.HuggingFace("microsoft/Phi-3-mini-4k-instruct", "main") :
-    new PhiModelProvider.HuggingFaceGguf("microsoft/Phi-3-mini-4k-instruct-gguf", "Phi-3-mini-4k-instruct-q4.gguf", "main");
+    new PhiModelProvider.HuggingFace("microsoft/Phi-3-mini-4k-instruct", "main", new Dictionary<string, string>()) :
+    new PhiModelProvider.HuggingFaceGguf("microsoft/Phi-3-mini-4k-instruct-gguf", "Phi-3-mini-4k-instruct-q4.gguf", "main", null);
 
 modelBuilder.WithEventHandler(new ModelEventsHandler());
 modelBuilder.WithModelProvider(modelProvider);
 var model = modelBuilder.BuildStateful(cacheDir, "You are a hockey poet");
 
-var result = model.RunInference("Write a haiku about ice hockey", inferenceOptions);
+var result = model.RunInference("Write a haiku about ice hockey", inferenceOptions, null);
 Console.WriteLine($"{Environment.NewLine}Tokens Generated: {result.tokenCount}{Environment.NewLine}Tokens per second: {result.tokensPerSecond}{Environment.NewLine}Duration: {result.duration}s");
 
 class ModelEventsHandler : PhiEventHandler
 {
-    public void OnInferenceEnded()
+    public void OnInferenceEnded(string requestId)
     {
     }
 
-    public void OnInferenceStarted()
+    public void OnInferenceStarted(string requestId)
     {
     }
 
-    public void OnInferenceToken(string token)
+    public void OnInferenceToken(string requestId, string token)
     {
         Console.Write(token);
     }
@@ -51,4 +51,18 @@ class ModelEventsHandler : PhiEventHandler
     {
         Console.WriteLine("Model loaded!");
     }
+
+    public void OnDownloadStarted(string fileName, ulong totalBytes)
+    {
+        Console.WriteLine($"Downloading {fileName} ({totalBytes / (1024 * 1024)} MB)...");
+    }
+
+    public void OnDownloadProgress(string fileName, ulong downloadedBytes)
+    {
+    }
+
+    public void OnDownloadCompleted(string fileName)
+    {
+        Console.WriteLine($"Downloaded {fileName}");
+    }
 }
\ No newline at end of file
@@ -29,7 +29,7 @@ class Phi3ViewModel: ObservableObject {
             self.isLoadingEngine = true
         }
         
-        let modelProvider = PhiModelProvider.huggingFaceGguf(modelRepo: "microsoft/Phi-3-mini-4k-instruct-gguf", modelFileName: "Phi-3-mini-4k-instruct-q4.gguf", modelRevision: "main") 
+        let modelProvider = PhiModelProvider.huggingFaceGguf(modelRepo: "microsoft/Phi-3-mini-4k-instruct-gguf", modelFileName: "Phi-3-mini-4k-instruct-q4.gguf", modelRevision: "main", modelSha256: nil) 
         let engineBuilder = PhiEngineBuilder()
         try! engineBuilder.withModelProvider(modelProvider: modelProvider)
         try! engineBuilder.withEventHandler(eventHandler: ModelEventsHandler(parent: self))
@@ -67,11 +67,11 @@ class Phi3ViewModel: ObservableObject {
             self.parent = parent
         }
 
-        func onInferenceStarted() {}
+        func onInferenceStarted(requestId: String) {}
 
-        func onInferenceEnded() {}
+        func onInferenceEnded(requestId: String) {}
         
-        func onInferenceToken(token: String) throws {
+        func onInferenceToken(requestId: String, token: String) throws {
             DispatchQueue.main.async {
                 if let lastMessage = self.parent.messages.last {
                     let updatedText = lastMessage.text + token
@@ -85,5 +85,11 @@ class Phi3ViewModel: ObservableObject {
         func onModelLoaded() throws {
             print("MODEL LOADED")
         }
+
+        func onDownloadStarted(fileName: String, totalBytes: UInt64) throws {}
+
+        func onDownloadProgress(fileName: String, downloadedBytes: UInt64) throws {}
+
+        func onDownloadCompleted(fileName: String) throws {}
     }
 }
@@ -15,13 +15,15 @@ fun main(args: Array<String>) {
     val modelProvider = if (isNonQuantizedMode) {
         PhiModelProvider.HuggingFace(
             modelRepo = "microsoft/Phi-3-mini-4k-instruct",
-            modelRevision = "main"
+            modelRevision = "main",
+            modelSha256 = emptyMap()
         )
     } else {
         PhiModelProvider.HuggingFaceGguf(
             modelRepo = "microsoft/Phi-3-mini-4k-instruct-gguf",
             modelFileName = "Phi-3-mini-4k-instruct-q4.gguf",
-            modelRevision = "main"
+            modelRevision = "main",
+            modelSha256 = null
         )
     }
 
@@ -33,11 +35,11 @@ fun main(args: Array<String>) {
     val cacheDir = File(File(File(File(System.getProperty("user.dir"), ".."), ".."), ".."), ".cache").absolutePath
 
     class ModelEventsHandler : PhiEventHandler {
-        override fun onInferenceStarted() {}
+        override fun onInferenceStarted(requestId: String) {}
         
-        override fun onInferenceEnded() {}
+        override fun onInferenceEnded(requestId: String) {}
 
-        override fun onInferenceToken(token: String) {
+        override fun onInferenceToken(requestId: String, token: String) {
             print(token)
         }
 
@@ -49,6 +51,16 @@ fun main(args: Array<String>) {
                 """.trimIndent()
             )
         }
+
+        override fun onDownloadStarted(fileName: String, totalBytes: ULong) {
+            println("Downloading $fileName (${totalBytes / (1024u * 1024u)} MB)...")
+        }
+
+        override fun onDownloadProgress(fileName: String, downloadedBytes: ULong) {}
+
+        override fun onDownloadCompleted(fileName: String) {
+            println("Downloaded $fileName")
+        }
     }
 
     val modelBuilder = PhiEngineBuilder()
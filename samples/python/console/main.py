@@ -10,13 +10,13 @@ inference_options = inference_options_builder.build()
 cache_dir = os.path.join(os.getcwd(), "..", "..", "..", ".cache")
 
 class ModelEventsHandler(PhiEventHandler):
-    def on_inference_token(self, token: str):
+    def on_inference_token(self, request_id: str, token: str):
         print(token, end="")
 
-    def on_inference_started(self):
+    def on_inference_started(self, request_id: str):
         pass
 
-    def on_inference_ended(self):
+    def on_inference_ended(self, request_id: str):
         pass
 
     def on_model_loaded(self):
@@ -25,6 +25,15 @@ class ModelEventsHandler(PhiEventHandler):
 ****************************************
 """)
 
+    def on_download_started(self, file_name: str, total_bytes: int):
+        print(f"Downloading {file_name} ({total_bytes // (1024 * 1024)} MB)...")
+
+    def on_download_progress(self, file_name: str, downloaded_bytes: int):
+        pass
+
+    def on_download_completed(self, file_name: str):
+        print(f"Downloaded {file_name}")
+
 model_builder = PhiEngineBuilder()
 model_builder.with_event_handler(event_handler=ModelEventsHandler())
 gpu_enabled = model_builder.try_use_gpu()
@@ -17,23 +17,27 @@ let modelProvider = switch (isGgufMode, isPhi4) {
         PhiModelProvider.huggingFaceGguf(
             modelRepo: "microsoft/phi-4-gguf",
             modelFileName: "phi-4-Q4_0.gguf",
-            modelRevision: "main"
+            modelRevision: "main",
+            modelSha256: nil
         )
     case (true, false):
         PhiModelProvider.huggingFaceGguf(
             modelRepo: "microsoft/Phi-3-mini-4k-instruct-gguf",
             modelFileName: "Phi-3-mini-4k-instruct-q4.gguf",
-            modelRevision: "main"
+            modelRevision: "main",
+            modelSha256: nil
         )
     case (false, true):
         PhiModelProvider.huggingFace(
             modelRepo: "microsoft/Phi-4-mini-instruct",
-            modelRevision: "main"
+            modelRevision: "main",
+            modelSha256: [:]
         )
     case (false, false):
         PhiModelProvider.huggingFace(
             modelRepo: "microsoft/Phi-3.5-mini-instruct",
-            modelRevision: "main"
+            modelRevision: "main",
+            modelSha256: [:]
         )
 }
 
@@ -48,13 +52,13 @@ let inferenceOptions = try! inferenceOptionsBuilder.build()
 let cacheDir = FileManager.default.currentDirectoryPath.appending("/../../../.cache")
 
 class ModelEventsHandler: PhiEventHandler {
-    func onInferenceStarted() {
+    func onInferenceStarted(requestId: String) {
         print(" ℹ️ Inference started...")
     }
-    func onInferenceEnded() {
+    func onInferenceEnded(requestId: String) {
         print("\n ℹ️ Inference ended.")
     }
-    func onInferenceToken(token: String) {
+    func onInferenceToken(requestId: String, token: String) {
         print(token, terminator: "")
     }
     func onModelLoaded() {
@@ -63,6 +67,13 @@ class ModelEventsHandler: PhiEventHandler {
         ****************************************
         """)
     }
+    func onDownloadStarted(fileName: String, totalBytes: UInt64) {
+        print(" ⬇️ Downloading \(fileName) (\(totalBytes / (1024 * 1024)) MB)...")
+    }
+    func onDownloadProgress(fileName: String, downloadedBytes: UInt64) {}
+    func onDownloadCompleted(fileName: String) {
+        print(" ✅ Downloaded \(fileName)")
+    }
 }
 
 let modelBuilder = PhiEngineBuilder()
@@ -72,7 +83,9 @@ try! modelBuilder.withModelProvider(modelProvider: modelProvider)
 if isPhi4 {
     try! modelBuilder.withTokenizerProvider(tokenizerProvider: .huggingFace(
         tokenizerRepo: "microsoft/phi-4",
-        tokenizerFileName: "tokenizer.json"
+        tokenizerFileName: "tokenizer.json",
+        tokenizerRevision: "main",
+        tokenizerSha256: nil
     ))
 }
 
@@ -0,0 +1,285 @@
+use crate::host::KeepAlive;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use strathweb_phi_engine::engine::{
+    ChatFormat, ModelDType, PhiEngine, PhiEngineBuilder, PhiModelProvider, TokenizerProvider,
+};
+use strathweb_phi_engine::PhiError;
+
+/// The TOML configuration of the server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// The number of HTTP requests handled at the same time.
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    /// The id of the model in `/v1/models` and the responses.
+    #[serde(default = "default_model_id")]
+    pub model_id: String,
+    /// Where models downloaded from the Hugging Face Hub are cached.
+    pub cache_dir: String,
+    /// How long the engine stays loaded after a request which does not set `keep_alive`, by default
+    /// until the server stops.
+    pub keep_alive: Option<KeepAlive>,
+    pub model: ModelConfig,
+    #[serde(default)]
+    pub tokenizer: TokenizerConfig,
+    #[serde(default)]
+    pub engine: EngineConfig,
+    #[serde(default)]
+    pub defaults: DefaultsConfig,
+}
+
+/// The `PhiModelProvider` to load the model from.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ModelConfig {
+    HuggingFace {
+        model_repo: String,
+        #[serde(default = "default_revision")]
+        model_revision: String,
+        #[serde(default)]
+        model_sha256: HashMap<String, String>,
+    },
+    HuggingFaceGguf {
+        model_repo: String,
+        model_file_name: String,
+        #[serde(default = "default_revision")]
+        model_revision: String,
+        model_sha256: Option<String>,
+    },
+    FileSystem {
+        index_path: String,
+        config_path: String,
+        #[serde(default)]
+        model_sha256: HashMap<String, String>,
+    },
+    FileSystemGguf {
+        model_path: String,
+        model_sha256: Option<String>,
+    },
+}
+
+/// The `TokenizerProvider` to load the tokenizer from, by default the one of the model.
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TokenizerConfig {
+    HuggingFace {
+        tokenizer_repo: String,
+        #[serde(default = "default_tokenizer_file_name")]
+        tokenizer_file_name: String,
+        #[serde(default = "default_revision")]
+        tokenizer_revision: String,
+        tokenizer_sha256: Option<String>,
+    },
+    FileSystem {
+        tokenizer_path: String,
+        tokenizer_sha256: Option<String>,
+    },
+    #[default]
+    FromModel,
+}
+
+/// The options of the `PhiEngineBuilder`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineConfig {
+    pub context_window: Option<u16>,
+    #[serde(default)]
+    pub use_gpu: bool,
+    #[serde(default)]
+    pub use_flash_attention: bool,
+    #[serde(default)]
+    pub use_mmap: bool,
+    /// `f32`, `f16` or `bf16`
+    pub dtype: Option<String>,
+    pub cpu_threads: Option<u16>,
+    /// Enables continuous batching of concurrent requests.
+    pub max_batch_size: Option<u16>,
+    pub offline: Option<bool>,
+    pub hf_endpoint: Option<String>,
+    pub hf_token: Option<String>,
+}
+
+/// What is used when a request does not set it.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultsConfig {
+    pub max_tokens: u16,
+    pub temperature: f64,
+    /// `llama2` (Phi-3) or `chatml` (Phi-4)
+    pub chat_format: String,
+    /// Used for chat completions without a system message.
+    pub system_instruction: Option<String>,
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 512,
+            temperature: 0.7,
+            chat_format: "llama2".to_string(),
+            system_instruction: None,
+        }
+    }
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_threads() -> usize {
+    4
+}
+
+fn default_model_id() -> String {
+    "phi".to_string()
+}
+
+fn default_revision() -> String {
+    "main".to_string()
+}
+
+fn default_tokenizer_file_name() -> String {
+    "tokenizer.json".to_string()
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+        let config: Self = toml::from_str(&text).map_err(|e| format!("Error parsing {}: {}", path, e))?;
+        config.chat_format()?;
+        Ok(config)
+    }
+
+    pub fn chat_format(&self) -> Result<ChatFormat, String> {
+        match self.defaults.chat_format.to_lowercase().as_str() {
+            "llama2" => Ok(ChatFormat::Llama2),
+            "chatml" => Ok(ChatFormat::ChatML),
+            other => Err(format!("Unknown chat format {}, expected llama2 or chatml", other)),
+        }
+    }
+
+    pub fn build_engine(&self) -> Result<Arc<PhiEngine>, PhiError> {
+        let builder = PhiEngineBuilder::new();
+        builder.with_model_provider(self.model.provider())?;
+        builder.with_tokenizer_provider(self.tokenizer.provider())?;
+
+        let engine = &self.engine;
+        if let Some(context_window) = engine.context_window {
+            builder.with_context_window(context_window)?;
+        }
+        if engine.use_gpu && !builder.try_use_gpu()? {
+            return Err(PhiError::GpuNotSupported);
+        }
+        builder.with_flash_attention(engine.use_flash_attention)?;
+        builder.with_mmap(engine.use_mmap)?;
+        if let Some(dtype) = &engine.dtype {
+            let dtype = match dtype.to_lowercase().as_str() {
+                "f32" => ModelDType::F32,
+                "f16" => ModelDType::F16,
+                "bf16" => ModelDType::BF16,
+                other => {
+                    return Err(PhiError::InitalizationError {
+                        error_text: format!("Unknown dtype {}, expected f32, f16 or bf16", other),
+                    })
+                }
+            };
+            builder.with_dtype(dtype)?;
+        }
+        if let Some(cpu_threads) = engine.cpu_threads {
+            builder.with_cpu_threads(cpu_threads)?;
+        }
+        if let Some(max_batch_size) = engine.max_batch_size {
+            builder.with_continuous_batching(max_batch_size)?;
+        }
+        if let Some(offline) = engine.offline {
+            builder.with_offline(offline)?;
+        }
+        if let Some(hf_endpoint) = &engine.hf_endpoint {
+            builder.with_hf_endpoint(hf_endpoint.clone())?;
+        }
+        if let Some(hf_token) = &engine.hf_token {
+            builder.with_hf_token(hf_token.clone())?;
+        }
+        builder.build(self.cache_dir.clone())
+    }
+}
+
+impl ModelConfig {
+    fn provider(&self) -> PhiModelProvider {
+        match self {
+            ModelConfig::HuggingFace {
+                model_repo,
+                model_revision,
+                model_sha256,
+            } => PhiModelProvider::HuggingFace {
+                model_repo: model_repo.clone(),
+                model_revision: model_revision.clone(),
+                model_sha256: model_sha256.clone(),
+            },
+            ModelConfig::HuggingFaceGguf {
+                model_repo,
+                model_file_name,
+                model_revision,
+                model_sha256,
+            } => PhiModelProvider::HuggingFaceGguf {
+                model_repo: model_repo.clone(),
+                model_file_name: model_file_name.clone(),
+                model_revision: model_revision.clone(),
+                model_sha256: model_sha256.clone(),
+            },
+            ModelConfig::FileSystem {
+                index_path,
+                config_path,
+                model_sha256,
+            } => PhiModelProvider::FileSystem {
+                index_path: index_path.clone(),
+                config_path: config_path.clone(),
+                model_sha256: model_sha256.clone(),
+            },
+            ModelConfig::FileSystemGguf {
+                model_path,
+                model_sha256,
+            } => PhiModelProvider::FileSystemGguf {
+                model_path: model_path.clone(),
+                model_sha256: model_sha256.clone(),
+            },
+        }
+    }
+}
+
+impl TokenizerConfig {
+    fn provider(&self) -> TokenizerProvider {
+        match self {
+            TokenizerConfig::HuggingFace {
+                tokenizer_repo,
+                tokenizer_file_name,
+                tokenizer_revision,
+                tokenizer_sha256,
+            } => TokenizerProvider::HuggingFace {
+                tokenizer_repo: tokenizer_repo.clone(),
+                tokenizer_file_name: tokenizer_file_name.clone(),
+                tokenizer_revision: tokenizer_revision.clone(),
+                tokenizer_sha256: tokenizer_sha256.clone(),
+            },
+            TokenizerConfig::FileSystem {
+                tokenizer_path,
+                tokenizer_sha256,
+            } => TokenizerProvider::FileSystem {
+                tokenizer_path: tokenizer_path.clone(),
+                tokenizer_sha256: tokenizer_sha256.clone(),
+            },
+            TokenizerConfig::FromModel => TokenizerProvider::FromModel,
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use crate::config::ServerConfig;
+use serde::Deserialize;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+use strathweb_phi_engine::engine::{ModelInfo, PhiEngine};
+use strathweb_phi_engine::PhiError;
+
+/// How long the engine stays loaded after a request, in the format of Ollama's `keep_alive`: a
+/// number of seconds, or a duration such as `"10m"` or `"1h30m"`. Negative values keep it loaded
+/// until the server stops, `0` unloads it right away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeepAlive {
+    Forever,
+    For(Duration),
+}
+
+impl KeepAlive {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value.starts_with('-') {
+            return Ok(KeepAlive::Forever);
+        }
+        let invalid = || format!("Invalid keep_alive duration `{}`", value);
+        if let Ok(seconds) = value.parse::<f64>() {
+            return Self::from_secs(seconds).ok_or_else(invalid);
+        }
+        let mut total_seconds = 0.;
+        let mut rest = value;
+        while !rest.is_empty() {
+            let number_len = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .ok_or_else(invalid)?;
+            let number = rest[..number_len].parse::<f64>().map_err(|_| invalid())?;
+            rest = &rest[number_len..];
+            let unit_len = rest
+                .find(|c: char| c.is_ascii_digit() || c == '.')
+                .unwrap_or(rest.len());
+            let seconds = match &rest[..unit_len] {
+                "h" => 3600.,
+                "m" => 60.,
+                "s" => 1.,
+                "ms" => 0.001,
+                _ => return Err(invalid()),
+            };
+            total_seconds += number * seconds;
+            rest = &rest[unit_len..];
+        }
+        Self::from_secs(total_seconds).ok_or_else(invalid)
+    }
+
+    // None for NaN, durations too long to represent are as good as forever
+    fn from_secs(seconds: f64) -> Option<Self> {
+        if seconds.is_nan() {
+            return None;
+        }
+        if seconds < 0. {
+            return Some(KeepAlive::Forever);
+        }
+        Some(Duration::try_from_secs_f64(seconds).map_or(KeepAlive::Forever, KeepAlive::For))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeepAlive {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Seconds(f64),
+            Text(String),
+        }
+        match Value::deserialize(deserializer)? {
+            Value::Seconds(seconds) => KeepAlive::from_secs(seconds)
+                .ok_or_else(|| serde::de::Error::custom(format!("Invalid keep_alive duration `{}`", seconds))),
+            Value::Text(text) => KeepAlive::parse(&text).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// What the host needs from the engine it keeps loaded.
+pub trait HostedEngine: Send + Sync + 'static {
+    fn get_model_info(&self) -> ModelInfo;
+}
+
+impl HostedEngine for PhiEngine {
+    fn get_model_info(&self) -> ModelInfo {
+        PhiEngine::get_model_info(self)
+    }
+}
+
+type Loader<E> = Box<dyn Fn() -> Result<Arc<E>, PhiError> + Send + Sync>;
+
+pub struct ModelHost<E = PhiEngine> {
+    config: Arc<ServerConfig>,
+    load: Loader<E>,
+    default_keep_alive: KeepAlive,
+    state: Mutex<HostState<E>>,
+    changed: Condvar,
+}
+
+struct HostState<E> {
+    engine: Option<Arc<E>>,
+    // kept after unloading, so that the model can be described without loading it again
+    model_info: Option<ModelInfo>,
+    // requests using the engine, which is never unloaded under them
+    active: usize,
+    // set while a request is loading the engine, without holding the lock
+    loading: bool,
+    // when the engine is unloaded once it is idle, never when `None`
+    expires_at: Option<SystemTime>,
+}
+
+/// The engine, held by a request. Dropping the lease starts the keep alive.
+pub struct EngineLease<E: HostedEngine = PhiEngine> {
+    host: Arc<ModelHost<E>>,
+    engine: Arc<E>,
+    keep_alive: KeepAlive,
+    /// How long loading the engine took, when the request had to.
+    pub load_duration: Option<Duration>,
+}
+
+impl ModelHost {
+    pub fn start(config: Arc<ServerConfig>) -> Arc<Self> {
+        let engine_config = config.clone();
+        Self::start_with_loader(config, Box::new(move || engine_config.build_engine()))
+    }
+}
+
+impl<E: HostedEngine> ModelHost<E> {
+    /// Starts a host which loads the engine with `load`.
+    pub fn start_with_loader(config: Arc<ServerConfig>, load: Loader<E>) -> Arc<Self> {
+        let host = Arc::new(Self {
+            default_keep_alive: config.keep_alive.unwrap_or(KeepAlive::Forever),
+            config,
+            load,
+            state: Mutex::new(HostState {
+                engine: None,
+                model_info: None,
+                active: 0,
+                loading: false,
+                expires_at: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let reaper = host.clone();
+        std::thread::spawn(move || reaper.unload_when_expired());
+        host
+    }
+
+    /// Returns the engine, loading it first if needed. `None` uses the keep alive of the configuration.
+    pub fn acquire(self: &Arc<Self>, keep_alive: Option<KeepAlive>) -> Result<EngineLease<E>, PhiError> {
+        let keep_alive = keep_alive.unwrap_or(self.default_keep_alive);
+        let mut state = self.lock()?;
+        loop {
+            if let Some(engine) = state.engine.clone() {
+                state.active += 1;
+                return Ok(self.lease(engine, keep_alive, None));
+            }
+            if !state.loading {
+                break;
+            }
+            // concurrent requests wait for a single load
+            state = self.changed.wait(state).map_err(|e| PhiError::LockingError {
+                error_text: e.to_string(),
+            })?;
+        }
+        state.loading = true;
+        drop(state);
+
+        // loading takes a while, the other endpoints can still use the state meanwhile
+        let loading = Loading(self);
+        let start = Instant::now();
+        let engine = (self.load)()?;
+        println!("Loaded {}", self.config.model_id);
+        let mut state = self.lock()?;
+        state.model_info = Some(engine.get_model_info());
+        state.engine = Some(engine.clone());
+        state.active += 1;
+        drop(state);
+        drop(loading);
+        Ok(self.lease(engine, keep_alive, Some(start.elapsed())))
+    }
+
+    /// Unloads the engine as soon as no request is using it anymore.
+    pub fn unload(&self) -> Result<(), PhiError> {
+        let mut state = self.lock()?;
+        state.expires_at = Some(SystemTime::now());
+        self.unload_if_expired(&mut state);
+        Ok(())
+    }
+
+    /// Describes the model, loading it if it has never been loaded.
+    pub fn model_info(self: &Arc<Self>) -> Result<ModelInfo, PhiError> {
+        if let Some(model_info) = self.lock()?.model_info.clone() {
+            return Ok(model_info);
+        }
+        let lease = self.acquire(None)?;
+        Ok(lease.engine().get_model_info())
+    }
+
+    /// `None` when the engine is not loaded, otherwise when it is going to be unloaded (`None` if it
+    /// is kept loaded, or is in use).
+    pub fn loaded_until(&self) -> Result<Option<Option<SystemTime>>, PhiError> {
+        let state = self.lock()?;
+        Ok(state.engine.as_ref().map(|_| match state.active {
+            0 => state.expires_at,
+            // the keep alive starts once the running requests have completed
+            _ => None,
+        }))
+    }
+
+    fn release(&self, keep_alive: KeepAlive) {
+        let Ok(mut state) = self.lock() else {
+            return;
+        };
+        state.active -= 1;
+        // the keep alive of the last request applies
+        state.expires_at = match keep_alive {
+            KeepAlive::Forever => None,
+            KeepAlive::For(duration) => Some(SystemTime::now() + duration),
+        };
+        self.unload_if_expired(&mut state);
+        self.changed.notify_all();
+    }
+
+    fn unload_if_expired(&self, state: &mut HostState<E>) {
+        let expired = state
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now());
+        if expired && state.active == 0 && state.engine.take().is_some() {
+            state.expires_at = None;
+            println!("Unloaded {}", self.config.model_id);
+        }
+    }
+
+    fn unload_when_expired(&self) {
+        let Ok(mut state) = self.lock() else {
+            return;
+        };
+        loop {
+            self.unload_if_expired(&mut state);
+            let timeout = match (&state.engine, state.expires_at) {
+                (Some(_), Some(expires_at)) => expires_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+                // nothing to unload until a request changes the state
+                _ => Duration::from_secs(3600),
+            };
+            state = match self.changed.wait_timeout(state, timeout) {
+                Ok((state, _)) => state,
+                Err(_) => return,
+            };
+        }
+    }
+
+    fn lease(self: &Arc<Self>, engine: Arc<E>, keep_alive: KeepAlive, load_duration: Option<Duration>) -> EngineLease<E> {
+        EngineLease {
+            host: self.clone(),
+            engine,
+            keep_alive,
+            load_duration,
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HostState<E>>, PhiError> {
+        self.state.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })
+    }
+}
+
+// clears `loading` and wakes the waiting requests, also when loading fails
+struct Loading<'a, E: HostedEngine>(&'a ModelHost<E>);
+
+impl<E: HostedEngine> Drop for Loading<'_, E> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.loading = false;
+        }
+        self.0.changed.notify_all();
+    }
+}
+
+impl<E: HostedEngine> EngineLease<E> {
+    pub fn engine(&self) -> &Arc<E> {
+        &self.engine
+    }
+}
+
+impl<E: HostedEngine> Drop for EngineLease<E> {
+    fn drop(&mut self) {
+        self.host.release(self.keep_alive);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::sync::atomic::Ordering;
+
+    fn seconds(seconds: u64) -> Result<KeepAlive, String> {
+        Ok(KeepAlive::For(Duration::from_secs(seconds)))
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(KeepAlive::parse("5m"), seconds(300));
+        assert_eq!(KeepAlive::parse("1h30m"), seconds(5400));
+        assert_eq!(KeepAlive::parse("90"), seconds(90));
+        assert_eq!(KeepAlive::parse("1.5s"), Ok(KeepAlive::For(Duration::from_millis(1500))));
+        assert_eq!(KeepAlive::parse("250ms"), Ok(KeepAlive::For(Duration::from_millis(250))));
+        assert_eq!(KeepAlive::parse("0"), seconds(0));
+    }
+
+    #[test]
+    fn negative_durations_keep_the_model_loaded() {
+        assert_eq!(KeepAlive::parse("-1"), Ok(KeepAlive::Forever));
+        assert_eq!(KeepAlive::parse("-5m"), Ok(KeepAlive::Forever));
+    }
+
+    #[test]
+    fn durations_too_long_to_represent_keep_the_model_loaded() {
+        assert_eq!(KeepAlive::parse("1e300"), Ok(KeepAlive::Forever));
+        assert_eq!(KeepAlive::parse("inf"), Ok(KeepAlive::Forever));
+        assert_eq!(KeepAlive::parse(&format!("{}h", "9".repeat(400))), Ok(KeepAlive::Forever));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(KeepAlive::parse("NaN").is_err());
+        assert!(KeepAlive::parse("5x").is_err());
+        assert!(KeepAlive::parse("m").is_err());
+        assert!(KeepAlive::parse("5m3").is_err());
+    }
+
+    #[test]
+    fn deserializes_numbers_and_strings() {
+        assert_eq!(serde_json::from_str::<KeepAlive>("300").map_err(|e| e.to_string()), seconds(300));
+        assert_eq!(serde_json::from_str::<KeepAlive>("1e300").ok(), Some(KeepAlive::Forever));
+        assert_eq!(serde_json::from_str::<KeepAlive>("-1").ok(), Some(KeepAlive::Forever));
+        assert_eq!(serde_json::from_str::<KeepAlive>("\"10m\"").ok(), Some(KeepAlive::For(Duration::from_secs(600))));
+        assert!(serde_json::from_str::<KeepAlive>("\"NaN\"").is_err());
+    }
+
+    fn host(load_time: Duration) -> (Arc<ModelHost<test_util::StubEngine>>, Arc<std::sync::atomic::AtomicUsize>) {
+        test_util::stub_host(test_util::config(""), load_time)
+    }
+
+    fn for_millis(millis: u64) -> Option<KeepAlive> {
+        Some(KeepAlive::For(Duration::from_millis(millis)))
+    }
+
+    #[test]
+    fn concurrent_requests_share_a_single_load() {
+        let (host, loads) = host(Duration::from_millis(100));
+        let leases = std::thread::scope(|scope| {
+            let requests = (0..4).map(|_| scope.spawn(|| host.acquire(None).unwrap())).collect::<Vec<_>>();
+            requests.into_iter().map(|request| request.join().unwrap()).collect::<Vec<_>>()
+        });
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(leases.iter().all(|lease| Arc::ptr_eq(lease.engine(), leases[0].engine())));
+        // only the request which loaded the engine waited for it
+        assert_eq!(leases.iter().filter(|lease| lease.load_duration.is_some()).count(), 1);
+        assert_eq!(host.lock().unwrap().active, 4);
+        drop(leases);
+        assert_eq!(host.lock().unwrap().active, 0);
+        // loaded until the server stops
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+    }
+
+    #[test]
+    fn engines_in_use_are_never_unloaded() {
+        let (host, loads) = host(Duration::ZERO);
+        let first = host.acquire(for_millis(0)).unwrap();
+        let second = host.acquire(None).unwrap();
+        host.unload().unwrap();
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+        drop(first);
+        // the keep alive of the first request is over, but the second one still runs
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+        drop(second);
+        // the keep alive of the last request replaces the unload requested meanwhile
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+        host.unload().unwrap();
+        assert_eq!(host.loaded_until().unwrap(), None);
+
+        // the model is described without loading it again, and loaded again for the next request
+        assert_eq!(host.model_info().unwrap().parameter_count, 1000);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        drop(host.acquire(for_millis(0)).unwrap());
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(host.loaded_until().unwrap(), None);
+    }
+
+    #[test]
+    fn idle_engines_are_unloaded_once_the_keep_alive_expires() {
+        let (host, _) = host(Duration::ZERO);
+        drop(host.acquire(for_millis(300)).unwrap());
+        let expires_at = host.loaded_until().unwrap().unwrap().unwrap();
+        assert!(expires_at > SystemTime::now());
+
+        // nothing but the reaper unloads it
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while host.loaded_until().unwrap().is_some() {
+            assert!(Instant::now() < deadline, "the engine was not unloaded");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(SystemTime::now() >= expires_at);
+    }
+
+    #[test]
+    fn running_requests_postpone_the_keep_alive() {
+        let (host, _) = host(Duration::ZERO);
+        drop(host.acquire(for_millis(100)).unwrap());
+        let lease = host.acquire(for_millis(100)).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+        drop(lease);
+        assert!(host.loaded_until().unwrap().unwrap().is_some());
+    }
+}
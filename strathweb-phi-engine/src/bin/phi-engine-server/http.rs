@@ -0,0 +1,150 @@
+use serde_json::{json, Value};
+use std::io::{self, Cursor, Write};
+use strathweb_phi_engine::PhiError;
+use tiny_http::{Header, Request, Response};
+
+/// What a handler answers with.
+pub enum Reply {
+    Json(Value),
+    Text(&'static str),
+    /// Server-sent events, each sent as soon as the iterator yields it and followed by `[DONE]`.
+    Events(Box<dyn Iterator<Item = Value> + Send>),
+    /// Newline-delimited JSON, each line sent as soon as the iterator yields it.
+    Lines(Box<dyn Iterator<Item = Value> + Send>),
+}
+
+/// The API a request was made to, which decides the format of its errors.
+#[derive(Clone, Copy)]
+pub enum Api {
+    OpenAi,
+    Ollama,
+}
+
+/// An error in the format of the OpenAI API.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+    pub kind: &'static str,
+    pub code: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            message: message.into(),
+            kind: "invalid_request_error",
+            code: None,
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self {
+            status: 404,
+            message: message.into(),
+            kind: "invalid_request_error",
+            code: None,
+        }
+    }
+
+    pub fn to_json(&self, api: Api) -> Value {
+        match api {
+            Api::OpenAi => json!({
+                "error": {
+                    "message": self.message,
+                    "type": self.kind,
+                    "param": null,
+                    "code": self.code,
+                }
+            }),
+            Api::Ollama => json!({ "error": self.message }),
+        }
+    }
+}
+
+impl From<PhiError> for ApiError {
+    fn from(e: PhiError) -> Self {
+        match e {
+            PhiError::ContextOverflow { .. } => Self {
+                status: 400,
+                message: e.to_string(),
+                kind: "invalid_request_error",
+                code: Some("context_length_exceeded"),
+            },
+            _ => Self {
+                status: 500,
+                message: e.to_string(),
+                kind: "server_error",
+                code: None,
+            },
+        }
+    }
+}
+
+pub fn read_body(request: &mut Request) -> Result<String, ApiError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| ApiError::invalid_request(format!("Error reading the request body: {}", e)))?;
+    Ok(body)
+}
+
+pub fn respond(request: Request, reply: Result<Reply, ApiError>, api: Api) -> io::Result<()> {
+    match reply {
+        Ok(Reply::Json(value)) => request.respond(json_response(200, &value)),
+        Ok(Reply::Text(text)) => request.respond(Response::from_string(text)),
+        Ok(Reply::Events(events)) => {
+            let mut stream = ChunkedStream::start(request, "text/event-stream")?;
+            for event in events {
+                stream.send(&format!("data: {}\n\n", event))?;
+            }
+            stream.send("data: [DONE]\n\n")?;
+            stream.finish()
+        }
+        Ok(Reply::Lines(lines)) => {
+            let mut stream = ChunkedStream::start(request, "application/x-ndjson")?;
+            for line in lines {
+                stream.send(&format!("{}\n", line))?;
+            }
+            stream.finish()
+        }
+        Err(e) => request.respond(json_response(e.status, &e.to_json(api))),
+    }
+}
+
+fn json_response(status: u16, value: &Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+// tiny_http buffers chunked responses, so streamed responses are written to the connection
+// directly, with their own chunked framing, and flushed one chunk at a time
+struct ChunkedStream {
+    writer: Box<dyn Write + Send>,
+}
+
+impl ChunkedStream {
+    fn start(request: Request, content_type: &str) -> io::Result<Self> {
+        let mut writer = request.into_writer();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\n\r\n",
+            content_type
+        )?;
+        writer.flush()?;
+        Ok(Self { writer })
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.writer, "{:x}\r\n{}\r\n", data.len(), data)?;
+        self.writer.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,131 @@
+use std::process::ExitCode;
+use std::sync::Arc;
+use strathweb_phi_engine::engine::ChatFormat;
+use tiny_http::{Method, Request, Server};
+
+mod config;
+mod host;
+mod http;
+mod ollama;
+mod openai;
+#[cfg(test)]
+mod test_util;
+
+use config::ServerConfig;
+use host::ModelHost;
+use http::{Api, ApiError, Reply};
+
+const USAGE: &str = "usage: phi-engine-server <config.toml>
+
+Serves the model named in the configuration over an OpenAI-compatible API
+(/v1/chat/completions, /v1/completions and /v1/models) and an Ollama-compatible API
+(/api/generate, /api/chat, /api/tags, /api/show and /api/ps).";
+
+pub struct AppState {
+    pub host: Arc<ModelHost>,
+    pub config: Arc<ServerConfig>,
+    pub chat_format: ChatFormat,
+    /// When the server was started, as a unix timestamp.
+    pub started: u64,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [config_path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let config = match ServerConfig::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if std::env::var_os("PHI_ENGINE_TRACING").is_some() {
+        strathweb_phi_engine::enable_tracing();
+    }
+
+    let config = Arc::new(config);
+    let host = ModelHost::start(config.clone());
+    // the model is loaded right away, so that a configuration which does not work fails at startup
+    println!("Loading the model...");
+    if let Err(e) = host.acquire(None) {
+        eprintln!("Error loading the model: {}", e);
+        return ExitCode::FAILURE;
+    }
+    let server = match Server::http((config.host.as_str(), config.port)) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("Error listening on {}:{}: {}", config.host, config.port, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "Serving {} on http://{host}:{port}/v1 and http://{host}:{port}/api",
+        config.model_id,
+        host = config.host,
+        port = config.port
+    );
+
+    let state = Arc::new(AppState {
+        host,
+        chat_format: config.chat_format().unwrap_or(ChatFormat::Llama2),
+        started: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default(),
+        config,
+    });
+    // every worker handles one request at a time, inference included
+    let workers = (0..state.config.threads.max(1))
+        .map(|_| {
+            let server = server.clone();
+            let state = state.clone();
+            std::thread::spawn(move || {
+                while let Ok(request) = server.recv() {
+                    handle(&state, request);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    ExitCode::SUCCESS
+}
+
+fn handle(state: &AppState, mut request: Request) {
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+    let api = match path.starts_with("/api/") {
+        true => Api::Ollama,
+        false => Api::OpenAi,
+    };
+    let reply = match (request.method(), path.as_str()) {
+        (Method::Get, "/v1/models") => Ok(openai::models(state)),
+        (Method::Get, path) if path.starts_with("/v1/models/") => {
+            openai::model(state, &path["/v1/models/".len()..])
+        }
+        (Method::Post, "/v1/chat/completions") => {
+            http::read_body(&mut request).and_then(|body| openai::chat_completions(state, &body))
+        }
+        (Method::Post, "/v1/completions") => {
+            http::read_body(&mut request).and_then(|body| openai::completions(state, &body))
+        }
+        (Method::Get | Method::Head, "/") => Ok(Reply::Text("Ollama is running")),
+        (Method::Get, "/api/version") => Ok(ollama::version()),
+        (Method::Get, "/api/tags") => ollama::tags(state),
+        (Method::Get, "/api/ps") => ollama::ps(state),
+        (Method::Post, "/api/show") => http::read_body(&mut request).and_then(|body| ollama::show(state, &body)),
+        (Method::Post, "/api/generate") => {
+            http::read_body(&mut request).and_then(|body| ollama::generate(state, &body))
+        }
+        (Method::Post, "/api/chat") => http::read_body(&mut request).and_then(|body| ollama::chat(state, &body)),
+        (method, path) => Err(ApiError::not_found(format!("Unknown endpoint {} {}", method, path))),
+    };
+    if let Err(e) = &reply {
+        eprintln!("{} {}: {}", request.method(), path, e.message);
+    }
+    // the client may have gone away, there is nobody left to tell
+    let _ = http::respond(request, reply, api);
+}
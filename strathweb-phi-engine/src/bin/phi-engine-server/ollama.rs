@@ -0,0 +1,544 @@
+use crate::config::ModelConfig;
+use crate::host::{EngineLease, HostedEngine, KeepAlive, ModelHost};
+use crate::http::{Api, ApiError, Reply};
+use crate::AppState;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use strathweb_phi_engine::engine::{
+    ConversationContext, ConversationMessage, FinishReason, InferenceOptions, InferenceOptionsBuilder, InferenceResult,
+    ModelInfo, Role,
+};
+use strathweb_phi_engine::stream::{InferenceStream, TokenChunk};
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The `options` of a request, unknown options (such as `num_ctx`) are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct ModelOptions {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u64>,
+    /// Negative values generate until the model stops or the context is full.
+    pub num_predict: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    #[serde(default)]
+    pub images: Vec<String>,
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub options: ModelOptions,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub options: ModelOptions,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShowRequest {
+    // older clients send `name`
+    #[serde(alias = "name")]
+    pub model: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Endpoint {
+    Generate,
+    Chat,
+}
+
+pub fn version() -> Reply {
+    Reply::Json(json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
+
+pub fn tags(state: &AppState) -> Result<Reply, ApiError> {
+    let model_info = state.host.model_info()?;
+    Ok(Reply::Json(json!({
+        "models": [{
+            "name": model_name(state),
+            "model": model_name(state),
+            "modified_at": rfc3339(UNIX_EPOCH + Duration::from_secs(state.started)),
+            "details": details(state, &model_info),
+        }]
+    })))
+}
+
+pub fn ps(state: &AppState) -> Result<Reply, ApiError> {
+    let models = match state.host.loaded_until()? {
+        None => vec![],
+        Some(expires_at) => {
+            let model_info = state.host.model_info()?;
+            vec![json!({
+                "name": model_name(state),
+                "model": model_name(state),
+                "details": details(state, &model_info),
+                "expires_at": expires_at.map(rfc3339),
+            })]
+        }
+    };
+    Ok(Reply::Json(json!({ "models": models })))
+}
+
+pub fn show(state: &AppState, body: &str) -> Result<Reply, ApiError> {
+    let request: ShowRequest = serde_json::from_str(body).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    check_model(state, &request.model)?;
+    let model_info = state.host.model_info()?;
+    let architecture = &model_info.architecture;
+    Ok(Reply::Json(json!({
+        "modelfile": "",
+        "parameters": "",
+        "template": "",
+        "details": details(state, &model_info),
+        "model_info": {
+            "general.architecture": architecture,
+            "general.parameter_count": model_info.parameter_count,
+            format!("{}.context_length", architecture): model_info.max_context,
+            format!("{}.vocab_size", architecture): model_info.vocab_size,
+        },
+        "capabilities": ["completion"],
+    })))
+}
+
+pub fn generate(state: &AppState, body: &str) -> Result<Reply, ApiError> {
+    let request: GenerateRequest =
+        serde_json::from_str(body).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    check_model(state, &request.model)?;
+    if !request.images.is_empty() {
+        return Err(ApiError::invalid_request("Images are not supported"));
+    }
+    if request.prompt.is_empty() {
+        return load_or_unload(&state.host, Response::new(state, Endpoint::Generate), request.keep_alive);
+    }
+    let conversation_context = ConversationContext {
+        system_instruction: request.system,
+        messages: vec![],
+    };
+    let stream = request.stream.unwrap_or(true);
+    run(state, Endpoint::Generate, request.prompt, conversation_context, &request.options, stream, request.keep_alive)
+}
+
+pub fn chat(state: &AppState, body: &str) -> Result<Reply, ApiError> {
+    let request: ChatRequest = serde_json::from_str(body).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    check_model(state, &request.model)?;
+    if request.messages.is_empty() {
+        return load_or_unload(&state.host, Response::new(state, Endpoint::Chat), request.keep_alive);
+    }
+
+    let mut system_instructions = Vec::new();
+    let mut messages = Vec::new();
+    for message in request.messages {
+        if !message.images.is_empty() {
+            return Err(ApiError::invalid_request("Images are not supported"));
+        }
+        let text = message.content;
+        match message.role.as_str() {
+            "system" => system_instructions.push(text),
+            "user" => messages.push(ConversationMessage { role: Role::User, text }),
+            "assistant" => messages.push(ConversationMessage {
+                role: Role::Assistant,
+                text,
+            }),
+            role => return Err(ApiError::invalid_request(format!("Unsupported message role `{}`", role))),
+        }
+    }
+    let prompt = match messages.pop() {
+        Some(ConversationMessage { role: Role::User, text }) => text,
+        _ => return Err(ApiError::invalid_request("The last message has to be a user message")),
+    };
+    let conversation_context = ConversationContext {
+        system_instruction: match system_instructions.is_empty() {
+            true => state.config.defaults.system_instruction.clone(),
+            false => Some(system_instructions.join("\n")),
+        },
+        messages,
+    };
+    let stream = request.stream.unwrap_or(true);
+    run(state, Endpoint::Chat, prompt, conversation_context, &request.options, stream, request.keep_alive)
+}
+
+// the model is `model_id`, which is also accepted with the `latest` tag
+fn check_model(state: &AppState, model: &str) -> Result<(), ApiError> {
+    let model_id = &state.config.model_id;
+    if model == model_id || model.strip_suffix(":latest") == Some(model_id) {
+        return Ok(());
+    }
+    Err(ApiError::not_found(format!("model '{}' not found", model)))
+}
+
+// Ollama model names always have a tag
+fn model_name(state: &AppState) -> String {
+    match state.config.model_id.contains(':') {
+        true => state.config.model_id.clone(),
+        false => format!("{}:latest", state.config.model_id),
+    }
+}
+
+fn details(state: &AppState, model_info: &ModelInfo) -> Value {
+    let format = match state.config.model {
+        ModelConfig::HuggingFaceGguf { .. } | ModelConfig::FileSystemGguf { .. } => "gguf",
+        ModelConfig::HuggingFace { .. } | ModelConfig::FileSystem { .. } => "safetensors",
+    };
+    let parameter_size = match model_info.parameter_count as f64 {
+        count if count >= 1e9 => format!("{:.1}B", count / 1e9),
+        count => format!("{:.0}M", count / 1e6),
+    };
+    json!({
+        "format": format,
+        "family": model_info.architecture,
+        "families": [model_info.architecture],
+        "parameter_size": parameter_size,
+        "quantization_level": model_info.quantization.as_deref().unwrap_or(&model_info.dtype),
+    })
+}
+
+// requests without a prompt only load the model, or unload it when `keep_alive` is zero
+fn load_or_unload<E: HostedEngine>(
+    host: &Arc<ModelHost<E>>,
+    response: Response,
+    keep_alive: Option<KeepAlive>,
+) -> Result<Reply, ApiError> {
+    let done_reason = match keep_alive {
+        Some(KeepAlive::For(duration)) if duration.is_zero() => {
+            host.unload()?;
+            "unload"
+        }
+        _ => {
+            host.acquire(keep_alive)?;
+            "load"
+        }
+    };
+    let mut done = response.chunk("");
+    done["done"] = json!(true);
+    done["done_reason"] = json!(done_reason);
+    Ok(Reply::Json(done))
+}
+
+fn run(
+    state: &AppState,
+    endpoint: Endpoint,
+    prompt: String,
+    conversation_context: ConversationContext,
+    options: &ModelOptions,
+    stream: bool,
+    keep_alive: Option<KeepAlive>,
+) -> Result<Reply, ApiError> {
+    let inference_options = inference_options(state, options)?;
+    let response = Response::new(state, endpoint);
+
+    let lease = state.host.acquire(keep_alive)?;
+    if stream {
+        let stream = lease
+            .engine()
+            .clone()
+            .stream_inference(prompt, conversation_context, inference_options);
+        // errors at the start, such as a prompt which is too long, are reported with their status code
+        let first = stream.next()?;
+        let mut lines = StreamLines::new(response, lease, stream);
+        lines.push(first);
+        return Ok(Reply::Lines(Box::new(lines)));
+    }
+    let result = lease
+        .engine()
+        .run_inference(&prompt, &conversation_context, &inference_options, None)?;
+    let mut done = response.chunk(&result.result_text);
+    response.finish(&mut done, &result, lease.load_duration);
+    Ok(Reply::Json(done))
+}
+
+// maps the options of the request onto the inference options, falling back to the defaults of the configuration
+fn inference_options(state: &AppState, options: &ModelOptions) -> Result<InferenceOptions, ApiError> {
+    let defaults = &state.config.defaults;
+    let builder = InferenceOptionsBuilder::new();
+    let max_tokens = match options.num_predict {
+        None => defaults.max_tokens,
+        // the generation is cut off at the end of the context anyway
+        Some(num_predict) if num_predict < 0 => u16::MAX,
+        Some(num_predict) => num_predict.min(u16::MAX as i64) as u16,
+    };
+    builder.with_token_count(max_tokens)?;
+    builder.with_temperature(options.temperature.unwrap_or(defaults.temperature))?;
+    if let Some(top_p) = options.top_p {
+        builder.with_top_p(top_p)?;
+    }
+    if let Some(top_k) = options.top_k {
+        builder.with_top_k(top_k)?;
+    }
+    if let Some(seed) = options.seed {
+        builder.with_seed(seed)?;
+    }
+    if let Some(repeat_penalty) = options.repeat_penalty {
+        builder.with_repeat_penalty(repeat_penalty)?;
+    }
+    if let Some(repeat_last_n) = options.repeat_last_n {
+        builder.with_repeat_last_n(repeat_last_n)?;
+    }
+    builder.with_stop_sequences(options.stop.clone().unwrap_or_default())?;
+    builder.with_chat_format(state.chat_format.clone())?;
+    builder.with_request_id(format!("ollama-{}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)))?;
+    Ok(builder.build()?)
+}
+
+// the parts which are the same in every line of a response
+struct Response {
+    endpoint: Endpoint,
+    model: String,
+    started: Instant,
+}
+
+impl Response {
+    fn new(state: &AppState, endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            model: model_name(state),
+            started: Instant::now(),
+        }
+    }
+
+    fn chunk(&self, text: &str) -> Value {
+        let mut chunk = json!({
+            "model": self.model,
+            "created_at": rfc3339(SystemTime::now()),
+        });
+        match self.endpoint {
+            Endpoint::Generate => chunk["response"] = json!(text),
+            Endpoint::Chat => chunk["message"] = json!({ "role": "assistant", "content": text }),
+        }
+        chunk["done"] = json!(false);
+        chunk
+    }
+
+    // the statistics of the last line, with durations in nanoseconds
+    fn finish(&self, chunk: &mut Value, result: &InferenceResult, load_duration: Option<Duration>) {
+        let total_duration = self.started.elapsed();
+        let load_duration = load_duration.unwrap_or_default();
+        let eval_duration = Duration::from_secs_f64(result.duration);
+        let prompt_eval_duration = total_duration
+            .saturating_sub(load_duration)
+            .saturating_sub(eval_duration);
+        chunk["done"] = json!(true);
+        chunk["done_reason"] = json!(match result.finish_reason {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+        });
+        chunk["total_duration"] = json!(total_duration.as_nanos() as u64);
+        chunk["load_duration"] = json!(load_duration.as_nanos() as u64);
+        chunk["prompt_eval_count"] = json!(result.prompt_token_count);
+        chunk["prompt_eval_duration"] = json!(prompt_eval_duration.as_nanos() as u64);
+        chunk["eval_count"] = json!(result.token_count);
+        chunk["eval_duration"] = json!(eval_duration.as_nanos() as u64);
+    }
+}
+
+// turns the chunks of the inference into lines, dropping the stream (which cancels the inference)
+// when the client goes away
+struct StreamLines {
+    response: Response,
+    lease: EngineLease,
+    stream: Arc<InferenceStream>,
+    pending: VecDeque<Value>,
+    done: bool,
+}
+
+impl StreamLines {
+    fn new(response: Response, lease: EngineLease, stream: Arc<InferenceStream>) -> Self {
+        Self {
+            response,
+            lease,
+            stream,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn push(&mut self, chunk: Option<TokenChunk>) {
+        match chunk {
+            Some(chunk) if chunk.is_special || chunk.text.is_empty() => {}
+            Some(chunk) => self.pending.push_back(self.response.chunk(&chunk.text)),
+            None => self.end(),
+        }
+    }
+
+    fn end(&mut self) {
+        self.done = true;
+        let Some(result) = self.stream.get_result() else {
+            return;
+        };
+        let mut done = self.response.chunk("");
+        self.response.finish(&mut done, &result, self.lease.load_duration);
+        self.pending.push_back(done);
+    }
+}
+
+impl Iterator for StreamLines {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        while self.pending.is_empty() && !self.done {
+            match self.stream.next() {
+                Ok(chunk) => self.push(chunk),
+                Err(e) => {
+                    self.done = true;
+                    self.pending.push_back(ApiError::from(e).to_json(Api::Ollama));
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+// formats a time as RFC 3339 in UTC, e.g. `2024-05-01T12:30:00.123456789Z`
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+    // the civil date of a number of days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_nanos()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn options(options: Value) -> InferenceOptions {
+        let state = test_util::state(test_util::config("[defaults]\nmax_tokens = 64\ntemperature = 0.5\n"));
+        inference_options(&state, &serde_json::from_value(options).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn maps_the_model_options_onto_the_options() {
+        let options = options(json!({
+            "temperature": 0.2,
+            "top_p": 0.9,
+            "top_k": 40,
+            "num_predict": 100,
+            "stop": ["\n\n", "User:"],
+            "seed": 42,
+            "repeat_penalty": 1.3,
+            "repeat_last_n": 32,
+            "num_ctx": 4096,
+        }));
+        assert_eq!(options.temperature, 0.2);
+        assert_eq!(options.top_p, Some(0.9));
+        assert_eq!(options.top_k, Some(40));
+        assert_eq!(options.token_count, 100);
+        assert_eq!(options.stop_sequences, ["\n\n", "User:"]);
+        assert_eq!(options.seed, 42);
+        assert_eq!(options.repeat_penalty, 1.3);
+        assert_eq!(options.repeat_last_n, 32);
+        assert!(options.request_id.is_some_and(|request_id| request_id.starts_with("ollama-")));
+    }
+
+    #[test]
+    fn missing_model_options_use_the_defaults() {
+        let options = options(json!({}));
+        let defaults = InferenceOptionsBuilder::new().build().unwrap();
+        assert_eq!(options.temperature, 0.5);
+        assert_eq!(options.token_count, 64);
+        assert_eq!(options.top_k, None);
+        assert!(options.stop_sequences.is_empty());
+        assert_eq!(options.repeat_penalty, defaults.repeat_penalty);
+        assert_eq!(options.repeat_last_n, defaults.repeat_last_n);
+    }
+
+    #[test]
+    fn negative_num_predict_generates_until_the_end_of_the_context() {
+        assert_eq!(options(json!({ "num_predict": -1 })).token_count, u16::MAX);
+        assert_eq!(options(json!({ "num_predict": -2 })).token_count, u16::MAX);
+        assert_eq!(options(json!({ "num_predict": 1_000_000 })).token_count, u16::MAX);
+    }
+
+    fn done_reason(reply: Reply) -> Value {
+        let Reply::Json(done) = reply else {
+            panic!("expected a JSON reply");
+        };
+        assert_eq!(done["done"], true);
+        assert_eq!(done["model"], "phi-test:latest");
+        done["done_reason"].clone()
+    }
+
+    #[test]
+    fn requests_without_a_prompt_load_or_unload_the_model() {
+        let state = test_util::state(test_util::config(""));
+        let (host, loads) = test_util::stub_host(test_util::config(""), Duration::ZERO);
+        let response = || Response::new(&state, Endpoint::Generate);
+
+        let reply = load_or_unload(&host, response(), None).unwrap();
+        assert_eq!(done_reason(reply), "load");
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+
+        let reply = load_or_unload(&host, response(), Some(KeepAlive::For(Duration::ZERO))).unwrap();
+        assert_eq!(done_reason(reply), "unload");
+        assert_eq!(host.loaded_until().unwrap(), None);
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        // a keep alive other than zero loads it again, and keeps it for that long
+        let reply = load_or_unload(&host, response(), Some(KeepAlive::For(Duration::from_secs(60)))).unwrap();
+        assert_eq!(done_reason(reply), "load");
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert!(host.loaded_until().unwrap().unwrap().is_some());
+    }
+
+    #[test]
+    fn unloading_waits_for_the_running_requests() {
+        let state = test_util::state(test_util::config(""));
+        let (host, _) = test_util::stub_host(test_util::config(""), Duration::ZERO);
+        let lease = host.acquire(Some(KeepAlive::For(Duration::ZERO))).unwrap();
+        let reply = load_or_unload(&host, Response::new(&state, Endpoint::Chat), Some(KeepAlive::For(Duration::ZERO)))
+            .unwrap();
+        assert_eq!(done_reason(reply), "unload");
+        assert_eq!(host.loaded_until().unwrap(), Some(None));
+        drop(lease);
+        assert_eq!(host.loaded_until().unwrap(), None);
+    }
+}
@@ -0,0 +1,679 @@
+use crate::host::EngineLease;
+use crate::http::{Api, ApiError, Reply};
+use crate::AppState;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use strathweb_phi_engine::engine::{
+    ConversationContext, ConversationMessage, FinishReason, InferenceOptions, InferenceOptionsBuilder, InferenceResult,
+    Role,
+};
+use strathweb_phi_engine::stream::{InferenceStream, TokenChunk};
+use strathweb_phi_engine::PhiError;
+
+static NEXT_COMPLETION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The sampling fields shared by both completion endpoints, unknown fields are ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct SamplingParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    // the newer name of `max_tokens` in chat completions
+    pub max_completion_tokens: Option<u32>,
+    pub stop: Option<StopSequences>,
+    pub seed: Option<u64>,
+    pub n: Option<u32>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<MessageContent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    pub prompt: Prompt,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Endpoint {
+    ChatCompletions,
+    Completions,
+}
+
+pub fn models(state: &AppState) -> Reply {
+    Reply::Json(json!({
+        "object": "list",
+        "data": [model_json(state)],
+    }))
+}
+
+pub fn model(state: &AppState, model_id: &str) -> Result<Reply, ApiError> {
+    if model_id != state.config.model_id {
+        return Err(ApiError::not_found(format!("The model `{}` does not exist", model_id)));
+    }
+    Ok(Reply::Json(model_json(state)))
+}
+
+fn model_json(state: &AppState) -> Value {
+    json!({
+        "id": state.config.model_id,
+        "object": "model",
+        "created": state.started,
+        "owned_by": "strathweb-phi-engine",
+    })
+}
+
+pub fn chat_completions(state: &AppState, body: &str) -> Result<Reply, ApiError> {
+    let request: ChatCompletionRequest =
+        serde_json::from_str(body).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    let (prompt, conversation_context) = conversation(state, request.messages)?;
+    run(state, Endpoint::ChatCompletions, prompt, conversation_context, request.params)
+}
+
+// the last message is the prompt, the ones before it the history and the system messages the instruction
+fn conversation(state: &AppState, chat_messages: Vec<ChatMessage>) -> Result<(String, ConversationContext), ApiError> {
+    let mut system_instructions = Vec::new();
+    let mut messages = Vec::new();
+    for message in chat_messages {
+        let text = message_text(message.content)?;
+        match message.role.as_str() {
+            "system" | "developer" => system_instructions.push(text),
+            "user" => messages.push(ConversationMessage { role: Role::User, text }),
+            "assistant" => messages.push(ConversationMessage {
+                role: Role::Assistant,
+                text,
+            }),
+            role => return Err(ApiError::invalid_request(format!("Unsupported message role `{}`", role))),
+        }
+    }
+    let prompt = match messages.pop() {
+        Some(ConversationMessage { role: Role::User, text }) => text,
+        _ => return Err(ApiError::invalid_request("The last message has to be a user message")),
+    };
+    let conversation_context = ConversationContext {
+        system_instruction: match system_instructions.is_empty() {
+            true => state.config.defaults.system_instruction.clone(),
+            false => Some(system_instructions.join("\n")),
+        },
+        messages,
+    };
+    Ok((prompt, conversation_context))
+}
+
+pub fn completions(state: &AppState, body: &str) -> Result<Reply, ApiError> {
+    let request: CompletionRequest = serde_json::from_str(body).map_err(|e| ApiError::invalid_request(e.to_string()))?;
+    let prompt = match request.prompt {
+        Prompt::One(prompt) => prompt,
+        Prompt::Many(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+        Prompt::Many(_) => return Err(ApiError::invalid_request("Only a single prompt is supported")),
+    };
+    let conversation_context = ConversationContext {
+        system_instruction: None,
+        messages: vec![],
+    };
+    run(state, Endpoint::Completions, prompt, conversation_context, request.params)
+}
+
+// only text content is supported, the text parts are joined
+fn message_text(content: Option<MessageContent>) -> Result<String, ApiError> {
+    match content {
+        None => Ok(String::new()),
+        Some(MessageContent::Text(text)) => Ok(text),
+        Some(MessageContent::Parts(parts)) => parts
+            .into_iter()
+            .map(|part| match (part.kind.as_str(), part.text) {
+                ("text", Some(text)) => Ok(text),
+                (kind, _) => Err(ApiError::invalid_request(format!("Unsupported content part `{}`", kind))),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|texts| texts.join("\n")),
+    }
+}
+
+fn run(
+    state: &AppState,
+    endpoint: Endpoint,
+    prompt: String,
+    conversation_context: ConversationContext,
+    params: SamplingParams,
+) -> Result<Reply, ApiError> {
+    if params.n.is_some_and(|n| n != 1) {
+        return Err(ApiError::invalid_request("Only n = 1 is supported"));
+    }
+    let id = format!(
+        "{}-{}",
+        match endpoint {
+            Endpoint::ChatCompletions => "chatcmpl",
+            Endpoint::Completions => "cmpl",
+        },
+        NEXT_COMPLETION_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let inference_options = inference_options(state, &params, &id)?;
+    let response = Response {
+        id,
+        endpoint,
+        model: state.config.model_id.clone(),
+        created: unix_time(),
+    };
+
+    let lease = state.host.acquire(None)?;
+    if params.stream {
+        let stream = lease
+            .engine()
+            .clone()
+            .stream_inference(prompt, conversation_context, inference_options);
+        // errors at the start, such as a prompt which is too long, are reported with their status code
+        let first = stream.next()?;
+        let include_usage = params.stream_options.is_some_and(|options| options.include_usage);
+        let mut events = StreamEvents::new(response, LeasedStream { _lease: lease, stream }, include_usage);
+        events.push(first);
+        return Ok(Reply::Events(Box::new(events)));
+    }
+    let result = lease
+        .engine()
+        .run_inference(&prompt, &conversation_context, &inference_options, None)?;
+    Ok(Reply::Json(response.completion(&result)))
+}
+
+// maps the fields of the request onto the options, falling back to the defaults of the configuration
+fn inference_options(state: &AppState, params: &SamplingParams, id: &str) -> Result<InferenceOptions, ApiError> {
+    let defaults = &state.config.defaults;
+    let builder = InferenceOptionsBuilder::new();
+    let max_tokens = params
+        .max_completion_tokens
+        .or(params.max_tokens)
+        .map(|max_tokens| max_tokens.min(u16::MAX as u32) as u16)
+        .unwrap_or(defaults.max_tokens);
+    builder.with_token_count(max_tokens)?;
+    builder.with_temperature(params.temperature.unwrap_or(defaults.temperature))?;
+    if let Some(top_p) = params.top_p {
+        builder.with_top_p(top_p)?;
+    }
+    if let Some(seed) = params.seed {
+        builder.with_seed(seed)?;
+    }
+    let stop_sequences = match &params.stop {
+        None => vec![],
+        Some(StopSequences::One(stop)) => vec![stop.clone()],
+        Some(StopSequences::Many(stop)) => stop.clone(),
+    };
+    builder.with_stop_sequences(stop_sequences)?;
+    builder.with_chat_format(state.chat_format.clone())?;
+    builder.with_request_id(id.to_string())?;
+    Ok(builder.build()?)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+// the parts which are the same in every response (or chunk) of a completion
+struct Response {
+    id: String,
+    endpoint: Endpoint,
+    model: String,
+    created: u64,
+}
+
+impl Response {
+    fn object(&self, chunk: bool) -> &'static str {
+        match (self.endpoint, chunk) {
+            (Endpoint::ChatCompletions, false) => "chat.completion",
+            (Endpoint::ChatCompletions, true) => "chat.completion.chunk",
+            (Endpoint::Completions, _) => "text_completion",
+        }
+    }
+
+    fn finish_reason(result: &InferenceResult) -> &'static str {
+        match result.finish_reason {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+        }
+    }
+
+    fn usage(result: &InferenceResult) -> Value {
+        json!({
+            "prompt_tokens": result.prompt_token_count,
+            "completion_tokens": result.token_count,
+            "total_tokens": result.prompt_token_count + result.token_count as u32,
+        })
+    }
+
+    fn choice(&self, text: Option<&str>, finish_reason: Option<&str>, chunk: bool) -> Value {
+        match (self.endpoint, chunk) {
+            (Endpoint::ChatCompletions, false) => json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            (Endpoint::ChatCompletions, true) => json!({
+                "index": 0,
+                "delta": match text {
+                    Some(text) => json!({ "content": text }),
+                    None => json!({}),
+                },
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+            (Endpoint::Completions, _) => json!({
+                "index": 0,
+                "text": text.unwrap_or_default(),
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }),
+        }
+    }
+
+    fn json(&self, choices: Vec<Value>, chunk: bool) -> Value {
+        json!({
+            "id": self.id,
+            "object": self.object(chunk),
+            "created": self.created,
+            "model": self.model,
+            "system_fingerprint": null,
+            "choices": choices,
+        })
+    }
+
+    fn completion(&self, result: &InferenceResult) -> Value {
+        let choice = self.choice(Some(&result.result_text), Some(Self::finish_reason(result)), false);
+        let mut completion = self.json(vec![choice], false);
+        completion["usage"] = Self::usage(result);
+        completion
+    }
+}
+
+// where the chunks of a streamed completion come from
+trait ChunkSource: Send {
+    fn next_chunk(&self) -> Result<Option<TokenChunk>, PhiError>;
+    /// The result once the chunks have ended, `None` if the inference failed.
+    fn result(&self) -> Option<InferenceResult>;
+}
+
+// the stream of an inference, with the lease keeping its engine loaded until the stream has ended
+struct LeasedStream {
+    _lease: EngineLease,
+    stream: Arc<InferenceStream>,
+}
+
+impl ChunkSource for LeasedStream {
+    fn next_chunk(&self) -> Result<Option<TokenChunk>, PhiError> {
+        self.stream.next()
+    }
+
+    fn result(&self) -> Option<InferenceResult> {
+        self.stream.get_result()
+    }
+}
+
+// turns the chunks of the inference into the chunks of the completion, dropping the stream (which
+// cancels the inference) when the client goes away
+struct StreamEvents<S> {
+    response: Response,
+    source: S,
+    include_usage: bool,
+    pending: VecDeque<Value>,
+    done: bool,
+}
+
+impl<S: ChunkSource> StreamEvents<S> {
+    fn new(response: Response, source: S, include_usage: bool) -> Self {
+        let mut pending = VecDeque::new();
+        // chat completion streams announce the role first
+        if response.endpoint == Endpoint::ChatCompletions {
+            let mut choice = response.choice(Some(""), None, true);
+            choice["delta"]["role"] = json!("assistant");
+            pending.push_back(response.json(vec![choice], true));
+        }
+        Self {
+            response,
+            source,
+            include_usage,
+            pending,
+            done: false,
+        }
+    }
+
+    fn push(&mut self, chunk: Option<TokenChunk>) {
+        match chunk {
+            Some(chunk) if chunk.is_special || chunk.text.is_empty() => {}
+            Some(chunk) => {
+                let choice = self.response.choice(Some(&chunk.text), None, true);
+                self.pending.push_back(self.response.json(vec![choice], true));
+            }
+            None => self.end(),
+        }
+    }
+
+    fn end(&mut self) {
+        self.done = true;
+        let Some(result) = self.source.result() else {
+            return;
+        };
+        let finish_reason = Response::finish_reason(&result);
+        let choice = self.response.choice(None, Some(finish_reason), true);
+        self.pending.push_back(self.response.json(vec![choice], true));
+        if self.include_usage {
+            let mut usage = self.response.json(vec![], true);
+            usage["usage"] = Response::usage(&result);
+            self.pending.push_back(usage);
+        }
+    }
+}
+
+impl<S: ChunkSource> Iterator for StreamEvents<S> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        while self.pending.is_empty() && !self.done {
+            match self.source.next_chunk() {
+                Ok(chunk) => self.push(chunk),
+                Err(e) => {
+                    self.done = true;
+                    self.pending.push_back(ApiError::from(e).to_json(Api::OpenAi));
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use strathweb_phi_engine::engine::ChatFormat;
+
+    fn params(json: Value) -> SamplingParams {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn options(params: SamplingParams) -> InferenceOptions {
+        let state = test_util::state(test_util::config("[defaults]\nmax_tokens = 64\ntemperature = 0.5\n"));
+        inference_options(&state, &params, "chatcmpl-1").unwrap()
+    }
+
+    #[test]
+    fn maps_the_sampling_fields_onto_the_options() {
+        let options = options(params(json!({
+            "temperature": 0.2,
+            "top_p": 0.9,
+            "max_tokens": 100,
+            "seed": 42,
+            "stop": ["\n\n", "User:"],
+        })));
+        assert_eq!(options.temperature, 0.2);
+        assert_eq!(options.top_p, Some(0.9));
+        assert_eq!(options.token_count, 100);
+        assert_eq!(options.seed, 42);
+        assert_eq!(options.stop_sequences, ["\n\n", "User:"]);
+        assert_eq!(options.request_id.as_deref(), Some("chatcmpl-1"));
+        assert!(matches!(options.chat_format, ChatFormat::Llama2));
+    }
+
+    #[test]
+    fn missing_sampling_fields_use_the_defaults() {
+        let options = options(SamplingParams::default());
+        assert_eq!(options.temperature, 0.5);
+        assert_eq!(options.top_p, None);
+        assert_eq!(options.token_count, 64);
+        assert!(options.stop_sequences.is_empty());
+    }
+
+    #[test]
+    fn max_completion_tokens_wins_over_max_tokens() {
+        let options = options(params(json!({ "max_tokens": 100, "max_completion_tokens": 20 })));
+        assert_eq!(options.token_count, 20);
+    }
+
+    #[test]
+    fn max_tokens_are_capped_at_the_largest_token_count() {
+        let options = options(params(json!({ "max_tokens": 1_000_000 })));
+        assert_eq!(options.token_count, u16::MAX);
+    }
+
+    #[test]
+    fn a_single_stop_sequence_can_be_a_string() {
+        let options = options(params(json!({ "stop": "User:" })));
+        assert_eq!(options.stop_sequences, ["User:"]);
+    }
+
+    #[test]
+    fn rejects_more_than_one_choice() {
+        let state = test_util::state(test_util::config(""));
+        for n in [0, 2] {
+            let body = json!({ "prompt": "hello", "n": n }).to_string();
+            let error = completions(&state, &body).err().unwrap();
+            assert_eq!(error.status, 400);
+            assert_eq!(error.message, "Only n = 1 is supported");
+        }
+    }
+
+    fn chat_messages(messages: Value) -> Vec<ChatMessage> {
+        serde_json::from_value(messages).unwrap()
+    }
+
+    // the role and text of the messages of the history
+    fn history(conversation_context: &ConversationContext) -> Vec<(&'static str, &str)> {
+        conversation_context
+            .messages
+            .iter()
+            .map(|message| match message.role {
+                Role::User => ("user", message.text.as_str()),
+                Role::Assistant => ("assistant", message.text.as_str()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn folds_the_messages_into_the_conversation() {
+        let state = test_util::state(test_util::config(""));
+        let (prompt, conversation_context) = conversation(
+            &state,
+            chat_messages(json!([
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hi" },
+                { "role": "assistant", "content": "Hello!" },
+                { "role": "developer", "content": "Answer in French." },
+                { "role": "user", "content": [{ "type": "text", "text": "How" }, { "type": "text", "text": "are you?" }] },
+            ])),
+        )
+        .unwrap();
+        assert_eq!(prompt, "How\nare you?");
+        assert_eq!(
+            conversation_context.system_instruction.as_deref(),
+            Some("Be brief.\nAnswer in French.")
+        );
+        assert_eq!(history(&conversation_context), [("user", "Hi"), ("assistant", "Hello!")]);
+    }
+
+    #[test]
+    fn conversations_without_a_system_message_use_the_default_instruction() {
+        let state = test_util::state(test_util::config("[defaults]\nsystem_instruction = \"Be helpful.\"\n"));
+        let (prompt, conversation_context) =
+            conversation(&state, chat_messages(json!([{ "role": "user", "content": "Hi" }]))).unwrap();
+        assert_eq!(prompt, "Hi");
+        assert_eq!(conversation_context.system_instruction.as_deref(), Some("Be helpful."));
+        assert!(conversation_context.messages.is_empty());
+    }
+
+    #[test]
+    fn rejects_conversations_which_do_not_end_with_a_user_message() {
+        let state = test_util::state(test_util::config(""));
+        for messages in [
+            json!([]),
+            json!([{ "role": "user", "content": "Hi" }, { "role": "assistant", "content": "Hello!" }]),
+            json!([{ "role": "tool", "content": "42" }]),
+            json!([{ "role": "user", "content": [{ "type": "image_url" }] }]),
+        ] {
+            let error = conversation(&state, chat_messages(messages)).err().unwrap();
+            assert_eq!(error.status, 400);
+        }
+    }
+
+    // chunks played back from a list, ending with `result`
+    struct ScriptedChunks {
+        chunks: std::sync::Mutex<VecDeque<TokenChunk>>,
+        result: InferenceResult,
+    }
+
+    impl ChunkSource for ScriptedChunks {
+        fn next_chunk(&self) -> Result<Option<TokenChunk>, PhiError> {
+            Ok(self.chunks.lock().unwrap().pop_front())
+        }
+
+        fn result(&self) -> Option<InferenceResult> {
+            Some(self.result.clone())
+        }
+    }
+
+    fn chunk(index: u32, text: &str, is_special: bool) -> TokenChunk {
+        TokenChunk {
+            token_ids: vec![index],
+            text: text.to_string(),
+            byte_offset: 0,
+            logprob: None,
+            index,
+            is_special,
+        }
+    }
+
+    fn events(endpoint: Endpoint, include_usage: bool) -> Vec<Value> {
+        let response = Response {
+            id: "chatcmpl-7".to_string(),
+            endpoint,
+            model: "phi-test".to_string(),
+            created: 1700000000,
+        };
+        let source = ScriptedChunks {
+            chunks: std::sync::Mutex::new(VecDeque::from([
+                chunk(0, "Hel", false),
+                chunk(1, "lo", false),
+                chunk(2, "", true),
+            ])),
+            result: InferenceResult {
+                request_id: "chatcmpl-7".to_string(),
+                token_count: 3,
+                prompt_token_count: 5,
+                finish_reason: FinishReason::Stop,
+                result_text: "Hello".to_string(),
+                duration: 0.1,
+                tokens_per_second: 30.,
+            },
+        };
+        StreamEvents::new(response, source, include_usage).collect()
+    }
+
+    #[test]
+    fn chat_completion_streams_announce_the_role_and_end_with_the_finish_reason() {
+        let events = events(Endpoint::ChatCompletions, false);
+        for event in &events {
+            assert_eq!(event["id"], "chatcmpl-7");
+            assert_eq!(event["object"], "chat.completion.chunk");
+            assert_eq!(event["model"], "phi-test");
+            assert!(event.get("usage").is_none());
+        }
+        let deltas = events
+            .iter()
+            .map(|event| (event["choices"][0]["delta"].clone(), event["choices"][0]["finish_reason"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deltas,
+            [
+                (json!({ "role": "assistant", "content": "" }), Value::Null),
+                (json!({ "content": "Hel" }), Value::Null),
+                (json!({ "content": "lo" }), Value::Null),
+                (json!({}), json!("stop")),
+            ]
+        );
+    }
+
+    #[test]
+    fn streams_end_with_the_usage_when_it_is_included() {
+        let events = events(Endpoint::ChatCompletions, true);
+        assert_eq!(events.len(), 5);
+        let usage = &events[4];
+        assert_eq!(usage["choices"], json!([]));
+        assert_eq!(
+            usage["usage"],
+            json!({ "prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8 })
+        );
+        assert_eq!(events[3]["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[test]
+    fn completion_streams_have_only_text_chunks() {
+        let events = events(Endpoint::Completions, false);
+        let choices = events
+            .iter()
+            .map(|event| {
+                assert_eq!(event["object"], "text_completion");
+                (event["choices"][0]["text"].clone(), event["choices"][0]["finish_reason"].clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            choices,
+            [
+                (json!("Hel"), Value::Null),
+                (json!("lo"), Value::Null),
+                (json!(""), json!("stop")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Fixtures shared by the unit tests of the server.
+
+use crate::config::ServerConfig;
+use crate::host::{HostedEngine, ModelHost};
+use crate::AppState;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use strathweb_phi_engine::engine::ModelInfo;
+
+/// A configuration with the model at a path which does not exist, followed by `extra` TOML.
+pub fn config(extra: &str) -> ServerConfig {
+    let config = format!(
+        "model_id = \"phi-test\"\ncache_dir = \"cache\"\n\n[model]\nprovider = \"file_system_gguf\"\nmodel_path = \"missing.gguf\"\n\n{}",
+        extra
+    );
+    toml::from_str(&config).unwrap()
+}
+
+pub fn state(config: ServerConfig) -> AppState {
+    let config = Arc::new(config);
+    AppState {
+        host: ModelHost::start(config.clone()),
+        chat_format: config.chat_format().unwrap(),
+        started: 0,
+        config,
+    }
+}
+
+/// Stands in for the engine in the tests of the host, which only need it to be loaded or not.
+pub struct StubEngine;
+
+impl HostedEngine for StubEngine {
+    fn get_model_info(&self) -> ModelInfo {
+        ModelInfo {
+            architecture: "phi3".to_string(),
+            parameter_count: 1000,
+            quantization: None,
+            max_context: 128,
+            vocab_size: 32,
+            special_tokens: vec![],
+            device: "cpu".to_string(),
+            dtype: "f32".to_string(),
+            load_time: 0.,
+            cpu_threads: 1,
+            simd_features: vec![],
+        }
+    }
+}
+
+/// A host of `StubEngine`s, counting how many times it loaded one. Loading takes `load_time`.
+pub fn stub_host(config: ServerConfig, load_time: Duration) -> (Arc<ModelHost<StubEngine>>, Arc<AtomicUsize>) {
+    let loads = Arc::new(AtomicUsize::new(0));
+    let counted_loads = loads.clone();
+    let host = ModelHost::start_with_loader(
+        Arc::new(config),
+        Box::new(move || {
+            std::thread::sleep(load_time);
+            counted_loads.fetch_add(1, Ordering::SeqCst);
+            Ok(Arc::new(StubEngine))
+        }),
+    );
+    (host, loads)
+}
@@ -0,0 +1,315 @@
+use clap::{Args, ValueEnum};
+use std::collections::HashMap;
+use std::sync::Arc;
+use strathweb_phi_engine::engine::{
+    ChatFormat, InferenceOptions, InferenceOptionsBuilder, ModelDType, PhiEngineBuilder, PhiEventHandler,
+    PhiModelProvider, TokenizerProvider,
+};
+use strathweb_phi_engine::PhiError;
+
+/// The settings of the `PhiEngineBuilder`. Without any model flag, the default model of the builder
+/// (Phi-3 mini, 4-bit GGUF) is downloaded from the Hugging Face Hub.
+#[derive(Debug, Args)]
+pub struct EngineArgs {
+    /// Where models downloaded from the Hugging Face Hub are cached
+    #[arg(long, default_value = ".cache")]
+    pub cache_dir: String,
+
+    /// Hugging Face repository of the model
+    #[arg(long, help_heading = "Model", conflicts_with_all = ["model_path", "model_index"])]
+    pub model_repo: Option<String>,
+    /// GGUF file in --model-repo; without it the repository holds a safetensors model
+    #[arg(long, help_heading = "Model", requires = "model_repo")]
+    pub model_file: Option<String>,
+    /// Revision of --model-repo
+    #[arg(long, help_heading = "Model", default_value = "main")]
+    pub model_revision: String,
+    /// Local GGUF model file
+    #[arg(long, help_heading = "Model", conflicts_with = "model_index")]
+    pub model_path: Option<String>,
+    /// Local safetensors index (model.safetensors.index.json)
+    #[arg(long, help_heading = "Model", requires = "model_config")]
+    pub model_index: Option<String>,
+    /// Local model config (config.json) of --model-index
+    #[arg(long, help_heading = "Model", requires = "model_index")]
+    pub model_config: Option<String>,
+    /// Expected SHA-256 of the GGUF file, or FILE=SHA256 for every safetensors file
+    #[arg(long, help_heading = "Model")]
+    pub model_sha256: Vec<String>,
+
+    /// Hugging Face repository of the tokenizer, by default the tokenizer of the model is used
+    #[arg(long, help_heading = "Tokenizer", conflicts_with = "tokenizer_path")]
+    pub tokenizer_repo: Option<String>,
+    /// Tokenizer file in --tokenizer-repo
+    #[arg(long, help_heading = "Tokenizer", default_value = "tokenizer.json")]
+    pub tokenizer_file: String,
+    /// Revision of --tokenizer-repo
+    #[arg(long, help_heading = "Tokenizer", default_value = "main")]
+    pub tokenizer_revision: String,
+    /// Local tokenizer file
+    #[arg(long, help_heading = "Tokenizer")]
+    pub tokenizer_path: Option<String>,
+    /// Expected SHA-256 of the tokenizer file
+    #[arg(long, help_heading = "Tokenizer")]
+    pub tokenizer_sha256: Option<String>,
+
+    /// Maximum number of tokens of the prompt and history
+    #[arg(long, help_heading = "Engine")]
+    pub context_window: Option<u16>,
+    /// Runs the model on the GPU (Metal on Apple Silicon), failing if there is none
+    #[arg(long, help_heading = "Engine")]
+    pub gpu: bool,
+    /// Uses flash attention, on the GPU
+    #[arg(long, help_heading = "Engine")]
+    pub flash_attention: bool,
+    /// Memory maps GGUF models on the CPU instead of reading them into memory
+    #[arg(long, help_heading = "Engine")]
+    pub mmap: bool,
+    /// Type the weights of safetensors models are loaded as
+    #[arg(long, help_heading = "Engine", value_enum)]
+    pub dtype: Option<DType>,
+    /// Number of threads used on the CPU
+    #[arg(long, help_heading = "Engine")]
+    pub cpu_threads: Option<u16>,
+    /// Enables continuous batching of concurrent requests
+    #[arg(long, help_heading = "Engine")]
+    pub max_batch_size: Option<u16>,
+    /// LoRA adapter to load, selected with --lora-adapter
+    #[arg(long, help_heading = "Engine", value_name = "NAME=PATH", value_parser = parse_key_value)]
+    pub lora: Vec<(String, String)>,
+    /// Only uses files already in the cache
+    #[arg(long, help_heading = "Engine")]
+    pub offline: bool,
+    /// Hugging Face Hub mirror to download from
+    #[arg(long, help_heading = "Engine")]
+    pub hf_endpoint: Option<String>,
+    /// Hugging Face token, for gated or private repositories
+    #[arg(long, help_heading = "Engine")]
+    pub hf_token: Option<String>,
+    /// User agent of the downloads
+    #[arg(long, help_heading = "Engine")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DType {
+    F32,
+    F16,
+    Bf16,
+}
+
+/// The settings of the `InferenceOptionsBuilder`, left at the defaults of the builder when not set.
+#[derive(Debug, Args)]
+pub struct InferenceArgs {
+    /// Maximum number of generated tokens
+    #[arg(short = 'n', long, help_heading = "Inference", default_value_t = 512)]
+    pub max_tokens: u16,
+    /// Sampling temperature
+    #[arg(long, help_heading = "Inference")]
+    pub temperature: Option<f64>,
+    /// Samples from the most likely tokens up to this cumulative probability
+    #[arg(long, help_heading = "Inference")]
+    pub top_p: Option<f64>,
+    /// Samples from this number of most likely tokens
+    #[arg(long, help_heading = "Inference")]
+    pub top_k: Option<u64>,
+    /// Penalty of the tokens repeated within --repeat-last-n tokens, 1 for none
+    #[arg(long, help_heading = "Inference")]
+    pub repeat_penalty: Option<f32>,
+    /// Number of tokens the repeat penalty looks back
+    #[arg(long, help_heading = "Inference")]
+    pub repeat_last_n: Option<u16>,
+    /// Seed of the sampling
+    #[arg(long, help_heading = "Inference")]
+    pub seed: Option<u64>,
+    /// Prompt format of the model, chatml for Phi-4
+    #[arg(long, help_heading = "Inference", value_enum)]
+    pub chat_format: Option<ChatFormatArg>,
+    /// Name of the LoRA adapter (loaded with --lora) to apply
+    #[arg(long, help_heading = "Inference")]
+    pub lora_adapter: Option<String>,
+    /// Id of the request in the results
+    #[arg(long, help_heading = "Inference")]
+    pub request_id: Option<String>,
+    /// Computes the log probability of the generated tokens
+    #[arg(long, help_heading = "Inference")]
+    pub logprobs: bool,
+    /// Ends the generation at this text, can be repeated
+    #[arg(long, help_heading = "Inference")]
+    pub stop: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ChatFormatArg {
+    Llama2,
+    Chatml,
+}
+
+impl EngineArgs {
+    pub fn builder(&self, event_handler: Arc<dyn PhiEventHandler>) -> Result<PhiEngineBuilder, PhiError> {
+        let builder = PhiEngineBuilder::new();
+        let model_provider = self.model_provider()?;
+        // a model given without a tokenizer brings its own, otherwise the builder's defaults are kept
+        let tokenizer_provider = self
+            .tokenizer_provider()
+            .or_else(|| model_provider.as_ref().map(|_| TokenizerProvider::FromModel));
+        if let Some(model_provider) = model_provider {
+            builder.with_model_provider(model_provider)?;
+        }
+        if let Some(tokenizer_provider) = tokenizer_provider {
+            builder.with_tokenizer_provider(tokenizer_provider)?;
+        }
+        if let Some(context_window) = self.context_window {
+            builder.with_context_window(context_window)?;
+        }
+        if self.gpu && !builder.try_use_gpu()? {
+            return Err(PhiError::GpuNotSupported);
+        }
+        builder.with_flash_attention(self.flash_attention)?;
+        builder.with_mmap(self.mmap)?;
+        if let Some(dtype) = self.dtype {
+            builder.with_dtype(match dtype {
+                DType::F32 => ModelDType::F32,
+                DType::F16 => ModelDType::F16,
+                DType::Bf16 => ModelDType::BF16,
+            })?;
+        }
+        if let Some(cpu_threads) = self.cpu_threads {
+            builder.with_cpu_threads(cpu_threads)?;
+        }
+        if let Some(max_batch_size) = self.max_batch_size {
+            builder.with_continuous_batching(max_batch_size)?;
+        }
+        for (name, path) in &self.lora {
+            builder.with_lora_adapter(name.clone(), path.clone())?;
+        }
+        builder.with_offline(self.offline)?;
+        if let Some(hf_endpoint) = &self.hf_endpoint {
+            builder.with_hf_endpoint(hf_endpoint.clone())?;
+        }
+        if let Some(hf_token) = &self.hf_token {
+            builder.with_hf_token(hf_token.clone())?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder.with_user_agent(user_agent.clone())?;
+        }
+        builder.with_event_handler(event_handler)?;
+        Ok(builder)
+    }
+
+    // `None` keeps the default model of the builder
+    fn model_provider(&self) -> Result<Option<PhiModelProvider>, PhiError> {
+        let model_provider = match (&self.model_repo, &self.model_file, &self.model_path, &self.model_index) {
+            (Some(model_repo), Some(model_file), _, _) => PhiModelProvider::HuggingFaceGguf {
+                model_repo: model_repo.clone(),
+                model_file_name: model_file.clone(),
+                model_revision: self.model_revision.clone(),
+                model_sha256: self.gguf_sha256()?,
+            },
+            (Some(model_repo), None, _, _) => PhiModelProvider::HuggingFace {
+                model_repo: model_repo.clone(),
+                model_revision: self.model_revision.clone(),
+                model_sha256: self.safetensors_sha256()?,
+            },
+            (None, _, Some(model_path), _) => PhiModelProvider::FileSystemGguf {
+                model_path: model_path.clone(),
+                model_sha256: self.gguf_sha256()?,
+            },
+            (None, _, None, Some(index_path)) => PhiModelProvider::FileSystem {
+                index_path: index_path.clone(),
+                config_path: self.model_config.clone().unwrap_or_default(),
+                model_sha256: self.safetensors_sha256()?,
+            },
+            (None, _, None, None) if self.model_sha256.is_empty() => return Ok(None),
+            (None, _, None, None) => {
+                return Err(invalid_argument("--model-sha256 needs a model flag".to_string()))
+            }
+        };
+        Ok(Some(model_provider))
+    }
+
+    fn gguf_sha256(&self) -> Result<Option<String>, PhiError> {
+        match self.model_sha256.as_slice() {
+            [] => Ok(None),
+            [sha256] if !sha256.contains('=') => Ok(Some(sha256.clone())),
+            _ => Err(invalid_argument(
+                "--model-sha256 takes a single SHA-256 for GGUF models".to_string(),
+            )),
+        }
+    }
+
+    fn safetensors_sha256(&self) -> Result<HashMap<String, String>, PhiError> {
+        self.model_sha256
+            .iter()
+            .map(|value| parse_key_value(value).map_err(invalid_argument))
+            .collect()
+    }
+
+    fn tokenizer_provider(&self) -> Option<TokenizerProvider> {
+        let tokenizer_provider = match (&self.tokenizer_repo, &self.tokenizer_path) {
+            (Some(tokenizer_repo), _) => TokenizerProvider::HuggingFace {
+                tokenizer_repo: tokenizer_repo.clone(),
+                tokenizer_file_name: self.tokenizer_file.clone(),
+                tokenizer_revision: self.tokenizer_revision.clone(),
+                tokenizer_sha256: self.tokenizer_sha256.clone(),
+            },
+            (None, Some(tokenizer_path)) => TokenizerProvider::FileSystem {
+                tokenizer_path: tokenizer_path.clone(),
+                tokenizer_sha256: self.tokenizer_sha256.clone(),
+            },
+            (None, None) => return None,
+        };
+        Some(tokenizer_provider)
+    }
+}
+
+impl InferenceArgs {
+    pub fn inference_options(&self) -> Result<InferenceOptions, PhiError> {
+        let builder = InferenceOptionsBuilder::new();
+        builder.with_token_count(self.max_tokens)?;
+        if let Some(temperature) = self.temperature {
+            builder.with_temperature(temperature)?;
+        }
+        if let Some(top_p) = self.top_p {
+            builder.with_top_p(top_p)?;
+        }
+        if let Some(top_k) = self.top_k {
+            builder.with_top_k(top_k)?;
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            builder.with_repeat_penalty(repeat_penalty)?;
+        }
+        if let Some(repeat_last_n) = self.repeat_last_n {
+            builder.with_repeat_last_n(repeat_last_n)?;
+        }
+        if let Some(seed) = self.seed {
+            builder.with_seed(seed)?;
+        }
+        if let Some(chat_format) = self.chat_format {
+            builder.with_chat_format(match chat_format {
+                ChatFormatArg::Llama2 => ChatFormat::Llama2,
+                ChatFormatArg::Chatml => ChatFormat::ChatML,
+            })?;
+        }
+        if let Some(lora_adapter) = &self.lora_adapter {
+            builder.with_lora_adapter(lora_adapter.clone())?;
+        }
+        if let Some(request_id) = &self.request_id {
+            builder.with_request_id(request_id.clone())?;
+        }
+        builder.with_logprobs(self.logprobs)?;
+        builder.with_stop_sequences(self.stop.clone())?;
+        builder.build()
+    }
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !value.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", value)),
+    }
+}
+
+fn invalid_argument(error_text: String) -> PhiError {
+    PhiError::InitalizationError { error_text }
+}
@@ -0,0 +1,140 @@
+use serde_json::json;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use strathweb_phi_engine::engine::{InferenceOptions, Role, StatefulPhiEngine};
+use strathweb_phi_engine::PhiError;
+
+const HELP: &str = "/clear           forgets the conversation
+/history         prints the conversation
+/system [TEXT]   prints the system instruction, or replaces it (`/system -` removes it)
+/save PATH       writes the conversation to a JSON file
+/exit            quits (as does Ctrl-D)";
+
+/// Reads prompts from stdin until it ends, streaming the responses to stdout.
+pub fn run(engine: Arc<StatefulPhiEngine>, inference_options: InferenceOptions, stats: bool) -> io::Result<()> {
+    println!("Type a message, or /help for the commands.");
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        let Some(line) = lines.next() else {
+            println!();
+            return Ok(());
+        };
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let outcome = match line.strip_prefix('/') {
+            Some(command) => {
+                let (command, argument) = command.split_once(' ').unwrap_or((command, ""));
+                match command {
+                    "exit" | "quit" => return Ok(()),
+                    "help" => {
+                        println!("{}", HELP);
+                        Ok(())
+                    }
+                    "clear" => engine.clear_messsages().map_err(Into::into),
+                    "history" => print_history(&engine),
+                    "system" => system(&engine, argument.trim()),
+                    "save" => save(&engine, argument.trim()),
+                    _ => Err(format!("Unknown command /{}, see /help", command).into()),
+                }
+            }
+            None => respond(&engine, line, &inference_options, stats),
+        };
+        // errors of a single message, such as a prompt which is too long, do not end the chat
+        if let Err(e) = outcome {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+fn respond(
+    engine: &Arc<StatefulPhiEngine>,
+    prompt: &str,
+    inference_options: &InferenceOptions,
+    stats: bool,
+) -> Result<(), Box<dyn Error>> {
+    let stream = engine
+        .clone()
+        .stream_inference(prompt.to_string(), inference_options.clone());
+    let mut stdout = io::stdout();
+    while let Some(chunk) = stream.next()? {
+        print!("{}", chunk.text);
+        let _ = stdout.flush();
+    }
+    println!();
+    if let (true, Some(result)) = (stats, stream.get_result()) {
+        eprintln!(
+            "[{} tokens, {:.1} tokens/s]",
+            result.token_count, result.tokens_per_second
+        );
+    }
+    Ok(())
+}
+
+fn print_history(engine: &StatefulPhiEngine) -> Result<(), Box<dyn Error>> {
+    if let Some(system_instruction) = system_instruction(engine)? {
+        println!("[system] {}", system_instruction);
+    }
+    for message in engine.get_history()? {
+        println!("[{}] {}", role(&message.role), message.text);
+    }
+    Ok(())
+}
+
+fn system(engine: &StatefulPhiEngine, argument: &str) -> Result<(), Box<dyn Error>> {
+    if argument.is_empty() {
+        match system_instruction(engine)? {
+            Some(system_instruction) => println!("{}", system_instruction),
+            None => println!("There is no system instruction"),
+        }
+        return Ok(());
+    }
+    let mut conversation_context = engine.conversation_context.lock().map_err(|e| PhiError::LockingError {
+        error_text: e.to_string(),
+    })?;
+    conversation_context.system_instruction = match argument {
+        "-" => None,
+        _ => Some(argument.to_string()),
+    };
+    Ok(())
+}
+
+fn save(engine: &StatefulPhiEngine, path: &str) -> Result<(), Box<dyn Error>> {
+    if path.is_empty() {
+        println!("Usage: /save PATH");
+        return Ok(());
+    }
+    let messages = engine
+        .get_history()?
+        .into_iter()
+        .map(|message| json!({ "role": role(&message.role), "text": message.text }))
+        .collect::<Vec<_>>();
+    let conversation = json!({
+        "system_instruction": system_instruction(engine)?,
+        "messages": messages,
+    });
+    std::fs::write(path, serde_json::to_string_pretty(&conversation)?)
+        .map_err(|e| format!("Error writing {}: {}", path, e))?;
+    println!("Saved the conversation to {}", path);
+    Ok(())
+}
+
+fn system_instruction(engine: &StatefulPhiEngine) -> Result<Option<String>, PhiError> {
+    let conversation_context = engine.conversation_context.lock().map_err(|e| PhiError::LockingError {
+        error_text: e.to_string(),
+    })?;
+    Ok(conversation_context.system_instruction.clone())
+}
+
+fn role(role: &Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+    }
+}
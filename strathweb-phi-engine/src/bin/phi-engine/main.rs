@@ -0,0 +1,235 @@
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+use strathweb_phi_engine::engine::{ConversationContext, FinishReason, ModelInfo};
+
+mod args;
+mod chat;
+mod progress;
+
+use args::{EngineArgs, InferenceArgs};
+use progress::DownloadProgress;
+
+/// Runs Phi models locally. Setting the PHI_ENGINE_TRACING environment variable enables the
+/// tracing output of the engine.
+#[derive(Parser)]
+#[command(name = "phi-engine", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Chats with the model, which remembers the conversation
+    Chat {
+        #[command(flatten)]
+        engine: EngineArgs,
+        #[command(flatten)]
+        inference: InferenceArgs,
+        /// System instruction of the conversation
+        #[arg(long)]
+        system: Option<String>,
+        /// Prints the number of tokens and the speed of every response on stderr
+        #[arg(long)]
+        stats: bool,
+    },
+    /// Prints the response to a single prompt
+    Generate {
+        #[command(flatten)]
+        engine: EngineArgs,
+        #[command(flatten)]
+        inference: InferenceArgs,
+        /// System instruction of the prompt
+        #[arg(long)]
+        system: Option<String>,
+        /// Prints the number of tokens and the speed of the response on stderr
+        #[arg(long)]
+        stats: bool,
+        /// Prints the result, with the streamed chunks and their log probabilities, as JSON
+        #[arg(long)]
+        json: bool,
+        /// The prompt, read from stdin when omitted
+        prompt: Option<String>,
+    },
+    /// Prints the metadata of the model
+    Info {
+        #[command(flatten)]
+        engine: EngineArgs,
+        /// Prints the metadata as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if std::env::var_os("PHI_ENGINE_TRACING").is_some() {
+        strathweb_phi_engine::enable_tracing();
+    }
+    let outcome = match cli.command {
+        Command::Chat {
+            engine,
+            inference,
+            system,
+            stats,
+        } => chat(&engine, &inference, system, stats),
+        Command::Generate {
+            engine,
+            inference,
+            system,
+            stats,
+            json,
+            prompt,
+        } => generate(&engine, &inference, system, stats, json, prompt),
+        Command::Info { engine, json } => info(&engine, json),
+    };
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn chat(
+    engine_args: &EngineArgs,
+    inference_args: &InferenceArgs,
+    system: Option<String>,
+    stats: bool,
+) -> Result<(), Box<dyn Error>> {
+    let inference_options = inference_args.inference_options()?;
+    let builder = engine_args.builder(Arc::new(DownloadProgress::default()))?;
+    eprintln!("Loading the model...");
+    let engine = builder.build_stateful(engine_args.cache_dir.clone(), system)?;
+    chat::run(engine, inference_options, stats)?;
+    Ok(())
+}
+
+fn generate(
+    engine_args: &EngineArgs,
+    inference_args: &InferenceArgs,
+    system: Option<String>,
+    stats: bool,
+    json: bool,
+    prompt: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let inference_options = inference_args.inference_options()?;
+    let prompt = match prompt {
+        Some(prompt) => prompt,
+        None => {
+            let mut prompt = String::new();
+            std::io::stdin().read_to_string(&mut prompt)?;
+            prompt
+        }
+    };
+    let engine = engine_args
+        .builder(Arc::new(DownloadProgress::default()))?
+        .build(engine_args.cache_dir.clone())?;
+    let conversation_context = ConversationContext {
+        system_instruction: system,
+        messages: vec![],
+    };
+
+    let stream = engine.stream_inference(prompt, conversation_context, inference_options);
+    let mut chunks = Vec::new();
+    let mut stdout = std::io::stdout();
+    while let Some(chunk) = stream.next()? {
+        if !json {
+            print!("{}", chunk.text);
+            stdout.flush()?;
+        }
+        chunks.push(chunk);
+    }
+    let result = stream.get_result().ok_or("The inference ended without a result")?;
+    if json {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| {
+                json!({
+                    "text": chunk.text,
+                    "token_ids": chunk.token_ids,
+                    "logprob": chunk.logprob,
+                    "is_special": chunk.is_special,
+                })
+            })
+            .collect::<Vec<_>>();
+        let output = json!({
+            "request_id": result.request_id,
+            "text": result.result_text,
+            "token_count": result.token_count,
+            "prompt_token_count": result.prompt_token_count,
+            "finish_reason": match result.finish_reason {
+                FinishReason::Stop => "stop",
+                FinishReason::Length => "length",
+            },
+            "duration": result.duration,
+            "tokens_per_second": result.tokens_per_second,
+            "chunks": chunks,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!();
+    }
+    if stats {
+        eprintln!(
+            "[{} prompt tokens, {} tokens, {:.1} tokens/s]",
+            result.prompt_token_count, result.token_count, result.tokens_per_second
+        );
+    }
+    Ok(())
+}
+
+fn info(engine_args: &EngineArgs, json: bool) -> Result<(), Box<dyn Error>> {
+    let engine = engine_args
+        .builder(Arc::new(DownloadProgress::default()))?
+        .build(engine_args.cache_dir.clone())?;
+    let ModelInfo {
+        architecture,
+        parameter_count,
+        quantization,
+        max_context,
+        vocab_size,
+        special_tokens,
+        device,
+        dtype,
+        load_time,
+        cpu_threads,
+        simd_features,
+    } = engine.get_model_info();
+    if json {
+        let output = json!({
+            "architecture": architecture,
+            "parameter_count": parameter_count,
+            "quantization": quantization,
+            "max_context": max_context,
+            "context_window": engine.context_window,
+            "vocab_size": vocab_size,
+            "special_tokens": special_tokens,
+            "device": device,
+            "dtype": dtype,
+            "load_time": load_time,
+            "cpu_threads": cpu_threads,
+            "simd_features": simd_features,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    println!("architecture:    {}", architecture);
+    println!("parameters:      {}", parameter_count);
+    println!("quantization:    {}", quantization.as_deref().unwrap_or("none"));
+    println!("max context:     {}", max_context);
+    println!("context window:  {}", engine.context_window);
+    println!("vocabulary size: {}", vocab_size);
+    println!("special tokens:  {}", special_tokens.join(" "));
+    println!("device:          {}", device);
+    println!("dtype:           {}", dtype);
+    println!("load time:       {:.2}s", load_time);
+    println!("cpu threads:     {}", cpu_threads);
+    println!("simd features:   {}", simd_features.join(" "));
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use strathweb_phi_engine::engine::PhiEventHandler;
+use strathweb_phi_engine::PhiError;
+
+/// Reports the downloads of the model files on stderr, the generated text is printed from the
+/// streams instead.
+#[derive(Default)]
+pub struct DownloadProgress {
+    // the size of the files being downloaded
+    total_bytes: Mutex<HashMap<String, u64>>,
+}
+
+impl PhiEventHandler for DownloadProgress {
+    fn on_model_loaded(&self) -> Result<(), PhiError> {
+        Ok(())
+    }
+
+    fn on_inference_started(&self, _request_id: String) -> Result<(), PhiError> {
+        Ok(())
+    }
+
+    fn on_inference_ended(&self, _request_id: String) -> Result<(), PhiError> {
+        Ok(())
+    }
+
+    fn on_inference_token(&self, _request_id: String, _token: String) -> Result<(), PhiError> {
+        Ok(())
+    }
+
+    fn on_download_started(&self, file_name: String, total_bytes: u64) -> Result<(), PhiError> {
+        if let Ok(mut files) = self.total_bytes.lock() {
+            files.insert(file_name.clone(), total_bytes);
+        }
+        eprint!("Downloading {} ({:.1} MB)", file_name, total_bytes as f64 / 1e6);
+        Ok(())
+    }
+
+    fn on_download_progress(&self, file_name: String, downloaded_bytes: u64) -> Result<(), PhiError> {
+        let total_bytes = self
+            .total_bytes
+            .lock()
+            .ok()
+            .and_then(|files| files.get(&file_name).copied())
+            .unwrap_or_default();
+        if total_bytes > 0 {
+            eprint!(
+                "\rDownloading {} ({:.1} MB): {}%",
+                file_name,
+                total_bytes as f64 / 1e6,
+                downloaded_bytes * 100 / total_bytes
+            );
+            let _ = std::io::stderr().flush();
+        }
+        Ok(())
+    }
+
+    fn on_download_completed(&self, file_name: String) -> Result<(), PhiError> {
+        if let Ok(mut files) = self.total_bytes.lock() {
+            files.remove(&file_name);
+        }
+        eprintln!();
+        Ok(())
+    }
+}
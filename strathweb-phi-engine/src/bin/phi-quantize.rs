@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+use std::process::ExitCode;
+use strathweb_phi_engine::engine::PhiModelProvider;
+use strathweb_phi_engine::quantize::{quantize_model, QuantizationType};
+
+const USAGE: &str = "usage: phi-quantize <model.safetensors.index.json> <config.json> <output.gguf> <type>
+
+The index and config paths must be absolute, tokenizer.json is read from the folder of the index.
+Supported types: f32, f16, q4_0, q4_1, q5_0, q5_1, q8_0, q2k, q3k, q4k, q5k, q6k";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [index_path, config_path, output_path, quantization_type] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+
+    let result = quantization_type.parse::<QuantizationType>().and_then(|quantization_type| {
+        let model_provider = PhiModelProvider::FileSystem {
+            index_path: index_path.clone(),
+            config_path: config_path.clone(),
+            model_sha256: HashMap::new(),
+        };
+        quantize_model(model_provider, output_path.clone(), quantization_type)
+    });
+    match result {
+        Ok(()) => {
+            println!("Wrote {}", output_path);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
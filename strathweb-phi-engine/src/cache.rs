@@ -0,0 +1,331 @@
+use crate::PhiError;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+// hf-hub stores every model repository in `models--{org}--{name}`, containing `blobs` (the file
+// contents, named by their etag), `snapshots/{commit}` (links to the blobs, laid out like the repo)
+// and `refs/{revision}` (files holding the commit a branch or tag points to)
+const MODEL_FOLDER_PREFIX: &str = "models--";
+
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub file_name: String,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedRevision {
+    pub commit_hash: String,
+    /// The branches or tags (e.g. `main`) pointing to this revision. Empty for revisions which
+    /// are no longer used and would be removed by `prune_model_cache`.
+    pub refs: Vec<String>,
+    pub files: Vec<CachedFile>,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedModel {
+    pub repo_id: String,
+    pub revisions: Vec<CachedRevision>,
+    /// The disk space used by the model. Files shared between revisions are only counted once.
+    pub size_bytes: u64,
+}
+
+/// Lists the models in the cache directory passed to `PhiEngineBuilder::build`.
+pub fn list_cached_models(cache_dir: String) -> Result<Vec<CachedModel>, PhiError> {
+    let cache_dir = PathBuf::from(cache_dir);
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut models = Vec::new();
+    for entry in fs::read_dir(&cache_dir).map_err(|e| PhiError::io_error(&cache_dir, e))? {
+        let entry = entry.map_err(|e| PhiError::io_error(&cache_dir, e))?;
+        let folder_name = entry.file_name().to_string_lossy().to_string();
+        let repo_id = match folder_name.strip_prefix(MODEL_FOLDER_PREFIX) {
+            Some(repo_id) if entry.path().is_dir() => repo_id.replace("--", "/"),
+            _ => continue,
+        };
+        models.push(read_cached_model(repo_id, &entry.path())?);
+    }
+    models.sort_by(|a, b| a.repo_id.cmp(&b.repo_id));
+    Ok(models)
+}
+
+/// Deletes a model, with all its revisions, from the cache directory. Returns the number of bytes freed.
+pub fn delete_cached_model(cache_dir: String, repo_id: String) -> Result<u64, PhiError> {
+    let model_dir = model_dir(&cache_dir, &repo_id);
+    if !model_dir.is_dir() {
+        return Ok(0);
+    }
+    let size_bytes = disk_usage(&model_dir)?;
+    fs::remove_dir_all(&model_dir).map_err(|e| PhiError::io_error(&model_dir, e))?;
+    debug!(" --> Deleted {} from the cache, freed {} bytes", repo_id, size_bytes);
+    Ok(size_bytes)
+}
+
+/// Removes the revisions no branch or tag points to anymore (e.g. older versions of `main`) and the
+/// files only they used. Returns the number of bytes freed.
+///
+/// Should not run while models are being downloaded into the same cache directory.
+pub fn prune_model_cache(cache_dir: String) -> Result<u64, PhiError> {
+    let mut freed_bytes = 0;
+    for model in list_cached_models(cache_dir.clone())? {
+        let model_dir = model_dir(&cache_dir, &model.repo_id);
+        let size_before = model.size_bytes;
+
+        for revision in model.revisions.iter().filter(|revision| revision.refs.is_empty()) {
+            let snapshot_dir = model_dir.join("snapshots").join(&revision.commit_hash);
+            fs::remove_dir_all(&snapshot_dir).map_err(|e| PhiError::io_error(&snapshot_dir, e))?;
+            debug!(" --> Pruned revision {} of {}", revision.commit_hash, model.repo_id);
+        }
+
+        // blobs which none of the remaining snapshots link to
+        let mut used_blobs = HashSet::new();
+        for (_, path) in snapshot_files(&model_dir.join("snapshots"))? {
+            if let Ok(blob) = fs::canonicalize(&path) {
+                used_blobs.insert(blob);
+            }
+        }
+        let blobs_dir = model_dir.join("blobs");
+        if blobs_dir.is_dir() {
+            for entry in fs::read_dir(&blobs_dir).map_err(|e| PhiError::io_error(&blobs_dir, e))? {
+                let path = entry.map_err(|e| PhiError::io_error(&blobs_dir, e))?.path();
+                // partial downloads and their locks are kept, so that downloads can still be resumed
+                let is_download = path.extension().is_some_and(|ext| ext == "part" || ext == "lock");
+                let is_used = fs::canonicalize(&path).is_ok_and(|blob| used_blobs.contains(&blob));
+                if path.is_file() && !is_download && !is_used {
+                    fs::remove_file(&path).map_err(|e| PhiError::io_error(&path, e))?;
+                }
+            }
+        }
+
+        freed_bytes += size_before.saturating_sub(disk_usage(&model_dir)?);
+    }
+    Ok(freed_bytes)
+}
+
+fn read_cached_model(repo_id: String, model_dir: &Path) -> Result<CachedModel, PhiError> {
+    let mut refs_by_commit: HashMap<String, Vec<String>> = HashMap::new();
+    let refs_dir = model_dir.join("refs");
+    if refs_dir.is_dir() {
+        // revisions such as `refs/pr/1` contain slashes and are stored in subfolders
+        for (ref_name, path) in walk_files(&refs_dir)? {
+            let commit_hash = fs::read_to_string(&path).map_err(|e| PhiError::io_error(&path, e))?;
+            refs_by_commit
+                .entry(commit_hash.trim().to_string())
+                .or_default()
+                .push(ref_name);
+        }
+    }
+
+    let mut revisions = Vec::new();
+    let snapshots_dir = model_dir.join("snapshots");
+    if snapshots_dir.is_dir() {
+        for entry in fs::read_dir(&snapshots_dir).map_err(|e| PhiError::io_error(&snapshots_dir, e))? {
+            let entry = entry.map_err(|e| PhiError::io_error(&snapshots_dir, e))?;
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let commit_hash = entry.file_name().to_string_lossy().to_string();
+            let mut files = Vec::new();
+            for (file_name, path) in walk_files(&entry.path())? {
+                // snapshot entries are links to blobs, the metadata of the blob has the size
+                let size_bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                files.push(CachedFile {
+                    file_name,
+                    size_bytes,
+                });
+            }
+            files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            let mut refs = refs_by_commit.remove(&commit_hash).unwrap_or_default();
+            refs.sort();
+            revisions.push(CachedRevision {
+                size_bytes: files.iter().map(|file| file.size_bytes).sum(),
+                commit_hash,
+                refs,
+                files,
+            });
+        }
+    }
+    revisions.sort_by(|a, b| b.refs.len().cmp(&a.refs.len()).then(a.commit_hash.cmp(&b.commit_hash)));
+
+    Ok(CachedModel {
+        repo_id,
+        revisions,
+        size_bytes: disk_usage(model_dir)?,
+    })
+}
+
+fn model_dir(cache_dir: &str, repo_id: &str) -> PathBuf {
+    Path::new(cache_dir).join(format!("{}{}", MODEL_FOLDER_PREFIX, repo_id.replace('/', "--")))
+}
+
+fn snapshot_files(snapshots_dir: &Path) -> Result<Vec<(String, PathBuf)>, PhiError> {
+    if !snapshots_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    walk_files(snapshots_dir)
+}
+
+// all files (or links to files) below `dir`, with their path relative to `dir` using `/` as separator
+fn walk_files(dir: &Path) -> Result<Vec<(String, PathBuf)>, PhiError> {
+    let mut files = Vec::new();
+    let mut pending = vec![(String::new(), dir.to_path_buf())];
+    while let Some((prefix, dir)) = pending.pop() {
+        for entry in fs::read_dir(&dir).map_err(|e| PhiError::io_error(&dir, e))? {
+            let entry = entry.map_err(|e| PhiError::io_error(&dir, e))?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().map_err(|e| PhiError::io_error(&entry.path(), e))?;
+            if file_type.is_dir() {
+                pending.push((format!("{}/", name), entry.path()));
+            } else {
+                files.push((name, entry.path()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+// actual disk usage: regular files only, links to blobs are not counted again
+fn disk_usage(dir: &Path) -> Result<u64, PhiError> {
+    let mut size_bytes = 0;
+    for (_, path) in walk_files(dir)? {
+        let metadata = fs::symlink_metadata(&path).map_err(|e| PhiError::io_error(&path, e))?;
+        if metadata.is_file() {
+            size_bytes += metadata.len();
+        }
+    }
+    Ok(size_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn link(target: &str, link: &Path) {
+        std::os::unix::fs::symlink(target, link).unwrap();
+    }
+
+    #[cfg(windows)]
+    fn link(target: &str, link: &Path) {
+        std::os::windows::fs::symlink_file(link.parent().unwrap().join(target), link).unwrap();
+    }
+
+    // a repository laid out like hf-hub does: `files` are (revision, file name, blob), and every
+    // blob is filled with as many bytes as given
+    fn write_repo(cache_dir: &Path, repo_id: &str, blobs: &[(&str, usize)], files: &[(&str, &str, &str)], refs: &[(&str, &str)]) {
+        let model_dir = model_dir(cache_dir.to_str().unwrap(), repo_id);
+        fs::create_dir_all(model_dir.join("blobs")).unwrap();
+        for (blob, size) in blobs {
+            fs::write(model_dir.join("blobs").join(blob), vec![0u8; *size]).unwrap();
+        }
+        for (revision, file_name, blob) in files {
+            let snapshot_dir = model_dir.join("snapshots").join(revision);
+            fs::create_dir_all(&snapshot_dir).unwrap();
+            link(&format!("../../blobs/{}", blob), &snapshot_dir.join(file_name));
+        }
+        for (ref_name, revision) in refs {
+            let path = model_dir.join("refs").join(ref_name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, revision).unwrap();
+        }
+    }
+
+    fn write_cache(name: &str) -> PathBuf {
+        let cache_dir = crate::test_util::temp_dir(name);
+        // `old` was main before `new`, both use the same config; `pr` is only referenced by a pull request
+        write_repo(
+            &cache_dir,
+            "org/model",
+            &[("shared", 10), ("old-weights", 20), ("new-weights", 40), ("pr-weights", 80), ("resumed.part", 5)],
+            &[
+                ("old", "config.json", "shared"),
+                ("old", "model.safetensors", "old-weights"),
+                ("new", "config.json", "shared"),
+                ("new", "model.safetensors", "new-weights"),
+                ("pr", "model.safetensors", "pr-weights"),
+            ],
+            &[("main", "new"), ("pr/1", "pr")],
+        );
+        write_repo(&cache_dir, "org/other", &[("weights", 7)], &[("abc", "model.gguf", "weights")], &[("main", "abc")]);
+        cache_dir
+    }
+
+    // the refs hold the commit hashes, `new` and `pr` in org/model and `abc` in org/other
+    const MODEL_REFS_BYTES: u64 = 5;
+    const OTHER_REFS_BYTES: u64 = 3;
+
+    fn blobs(cache_dir: &Path, repo_id: &str) -> Vec<String> {
+        let blobs_dir = model_dir(cache_dir.to_str().unwrap(), repo_id).join("blobs");
+        let mut blobs = walk_files(&blobs_dir).unwrap().into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        blobs.sort();
+        blobs
+    }
+
+    #[test]
+    fn cached_models_count_shared_blobs_once() {
+        let cache_dir = write_cache("cache-list");
+        let models = list_cached_models(cache_dir.display().to_string()).unwrap();
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(models.iter().map(|model| model.repo_id.as_str()).collect::<Vec<_>>(), ["org/model", "org/other"]);
+        let model = &models[0];
+        assert_eq!(model.size_bytes, 10 + 20 + 40 + 80 + 5 + MODEL_REFS_BYTES);
+        let revisions = model
+            .revisions
+            .iter()
+            .map(|revision| (revision.commit_hash.as_str(), revision.refs.clone(), revision.size_bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            revisions,
+            [
+                ("new", vec!["main".to_string()], 50),
+                ("pr", vec!["pr/1".to_string()], 80),
+                ("old", vec![], 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn prune_removes_only_unreferenced_revisions_and_blobs() {
+        let cache_dir = write_cache("cache-prune");
+        let freed_bytes = prune_model_cache(cache_dir.display().to_string()).unwrap();
+        let model_dir = model_dir(cache_dir.to_str().unwrap(), "org/model");
+        let snapshots = fs::read_dir(model_dir.join("snapshots"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<HashSet<_>>();
+        let models = list_cached_models(cache_dir.display().to_string()).unwrap();
+        let (model_blobs, other_blobs) = (blobs(&cache_dir, "org/model"), blobs(&cache_dir, "org/other"));
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        // only the weights of `old`, its config is still used by `new`
+        assert_eq!(freed_bytes, 20);
+        assert_eq!(snapshots, HashSet::from(["new".to_string(), "pr".to_string()]));
+        assert_eq!(model_blobs, ["new-weights", "pr-weights", "resumed.part", "shared"]);
+        assert_eq!(other_blobs, ["weights"]);
+        assert_eq!(models[0].size_bytes, 10 + 40 + 80 + 5 + MODEL_REFS_BYTES);
+        assert_eq!(models[1].size_bytes, 7 + OTHER_REFS_BYTES);
+        assert!(models.iter().all(|model| model.revisions.iter().all(|revision| !revision.refs.is_empty())));
+    }
+
+    #[test]
+    fn delete_removes_only_the_named_model() {
+        let cache_dir = write_cache("cache-delete");
+        let cache = cache_dir.display().to_string();
+        let freed_bytes = delete_cached_model(cache.clone(), "org/model".to_string()).unwrap();
+        let missing_bytes = delete_cached_model(cache.clone(), "org/missing".to_string()).unwrap();
+        let models = list_cached_models(cache).unwrap();
+        let other_blobs = blobs(&cache_dir, "org/other");
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(freed_bytes, 10 + 20 + 40 + 80 + 5 + MODEL_REFS_BYTES);
+        assert_eq!(missing_bytes, 0);
+        assert_eq!(models.iter().map(|model| model.repo_id.as_str()).collect::<Vec<_>>(), ["org/other"]);
+        assert_eq!(other_blobs, ["weights"]);
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_transformers::models::phi3::{Config as Phi3Config, Model as Phi3};
+use candle_transformers::models::quantized_phi3::ModelWeights as QuantizedPhi3;
+use std::sync::Arc;
+
+/// A causal language model backend that the text generator can drive.
+///
+/// Implementations own their KV cache. `forward` is called once with the whole prompt
+/// at position 0 and then once per sampled token, with increasing positions.
+pub trait CausalLm: Send + Sync {
+    /// Runs the model over `input` starting at `position` and returns the logits
+    /// of the last input token as a 1D `f32` tensor of size `vocab_size()`.
+    fn forward(&mut self, input: &[u32], position: usize) -> Result<Tensor>;
+
+    fn clear_kv_cache(&mut self);
+
+    fn vocab_size(&self) -> usize;
+
+    /// The maximum number of tokens (prompt and generated) the model can attend to.
+    fn max_context(&self) -> usize;
+
+    fn device(&self) -> &Device;
+
+    /// Selects the LoRA adapter used by the following forward passes, `None` runs the base model.
+    fn set_lora_adapter(&mut self, adapter: Option<&str>) -> Result<()> {
+        match adapter {
+            Some(adapter) => anyhow::bail!("the model does not support LoRA adapters, cannot apply {adapter}"),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates a copy of the model sharing the weights but with its own KV cache.
+    fn box_clone(&self) -> Box<dyn CausalLm>;
+}
+
+/// The KV cache of a single sequence, kept outside of a `BatchedCausalLm`.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    // (key, value) per layer, each (1, num_kv_heads, len, head_dim)
+    layers: Vec<Option<(Tensor, Tensor)>>,
+}
+
+impl KvCache {
+    /// The number of cached tokens, which is also the position of the next input token.
+    pub fn len(&self) -> usize {
+        match self.layers.first() {
+            Some(Some((key, _))) => key.dim(2).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn layer(&mut self, layer_idx: usize) -> &mut Option<(Tensor, Tensor)> {
+        if self.layers.len() <= layer_idx {
+            self.layers.resize(layer_idx + 1, None);
+        }
+        &mut self.layers[layer_idx]
+    }
+
+    // drops everything after the first `len` tokens
+    pub(crate) fn truncate(&mut self, len: usize) -> Result<()> {
+        for (key, value) in self.layers.iter_mut().flatten() {
+            *key = key.narrow(2, 0, len)?;
+            *value = value.narrow(2, 0, len)?;
+        }
+        Ok(())
+    }
+}
+
+/// A causal language model which runs several sequences, each at its own position, in one
+/// forward pass. Used by the continuous batching scheduler.
+pub trait BatchedCausalLm: Send + Sync {
+    /// Runs the model over `input`, one row of tokens per sequence, with every sequence continuing
+    /// from the end of its cache. Shorter rows are padded to the longest one, and the padding is
+    /// kept out of the caches. Returns the logits of the last token of every sequence as a 2D `f32`
+    /// tensor of shape (sequences, `vocab_size()`).
+    fn forward_batch(&self, input: &[&[u32]], caches: &mut [&mut KvCache], adapter: Option<&str>) -> Result<Tensor>;
+
+    fn max_context(&self) -> usize;
+}
+
+#[derive(Clone)]
+pub struct Phi3Model {
+    model: Phi3,
+    vocab_size: usize,
+    max_context: usize,
+    device: Device,
+}
+
+impl Phi3Model {
+    pub fn new(model: Phi3, config: &Phi3Config, device: &Device) -> Self {
+        Self {
+            model,
+            vocab_size: config.vocab_size,
+            max_context: config.max_position_embeddings,
+            device: device.clone(),
+        }
+    }
+}
+
+impl CausalLm for Phi3Model {
+    fn forward(&mut self, input: &[u32], position: usize) -> Result<Tensor> {
+        let input = Tensor::new(input, &self.device)?.unsqueeze(0)?;
+        let logits = self
+            .model
+            .forward(&input, position)?
+            .i((.., 0, ..))?
+            .squeeze(0)?
+            .to_dtype(DType::F32)?;
+        Ok(logits)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.model.clear_kv_cache();
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_context(&self) -> usize {
+        self.max_context
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn box_clone(&self) -> Box<dyn CausalLm> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct QuantizedPhi3Model {
+    model: QuantizedPhi3,
+    vocab_size: usize,
+    max_context: usize,
+    device: Device,
+}
+
+impl QuantizedPhi3Model {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        use_flash_attention: bool,
+        content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let max_context = match content.metadata.get("phi3.context_length") {
+            Some(value) => value.to_u32()? as usize,
+            None => anyhow::bail!("cannot find phi3.context_length in metadata"),
+        };
+        // embeddings are stored as (vocab_size, embedding_length)
+        let vocab_size = match content.tensor_infos.get("token_embd.weight") {
+            Some(info) => info.shape.dims()[0],
+            None => anyhow::bail!("cannot find token_embd.weight in tensors"),
+        };
+        let model = QuantizedPhi3::from_gguf(use_flash_attention, content, reader, device)?;
+        Ok(Self {
+            model,
+            vocab_size,
+            max_context,
+            device: device.clone(),
+        })
+    }
+}
+
+impl CausalLm for QuantizedPhi3Model {
+    fn forward(&mut self, input: &[u32], position: usize) -> Result<Tensor> {
+        let input = Tensor::new(input, &self.device)?.unsqueeze(0)?;
+        let logits = self
+            .model
+            .forward(&input, position)?
+            .squeeze(0)?
+            .to_dtype(DType::F32)?;
+        Ok(logits)
+    }
+
+    // the quantized model resets its KV cache whenever a forward pass starts at position 0
+    fn clear_kv_cache(&mut self) {}
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_context(&self) -> usize {
+        self.max_context
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn box_clone(&self) -> Box<dyn CausalLm> {
+        Box::new(self.clone())
+    }
+}
+
+/// Runs the forward passes of another model on a dedicated thread pool. candle's CPU kernels
+/// parallelize on the current rayon pool, so this bounds the number of cores the model uses.
+pub struct ThreadPoolModel {
+    model: Box<dyn CausalLm>,
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl ThreadPoolModel {
+    pub fn new(model: Box<dyn CausalLm>, pool: Arc<rayon::ThreadPool>) -> Self {
+        Self { model, pool }
+    }
+}
+
+impl CausalLm for ThreadPoolModel {
+    fn forward(&mut self, input: &[u32], position: usize) -> Result<Tensor> {
+        let model = &mut self.model;
+        self.pool.install(|| model.forward(input, position))
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.model.clear_kv_cache();
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.model.vocab_size()
+    }
+
+    fn max_context(&self) -> usize {
+        self.model.max_context()
+    }
+
+    fn device(&self) -> &Device {
+        self.model.device()
+    }
+
+    fn set_lora_adapter(&mut self, adapter: Option<&str>) -> Result<()> {
+        self.model.set_lora_adapter(adapter)
+    }
+
+    fn box_clone(&self) -> Box<dyn CausalLm> {
+        Box::new(Self {
+            model: self.model.box_clone(),
+            pool: self.pool.clone(),
+        })
+    }
+}
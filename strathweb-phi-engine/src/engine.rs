@@ -1,18 +1,28 @@
-use anyhow::{Error as E, Result};
+use anyhow::Result;
 use candle_core::quantized::gguf_file;
-use candle_core::{DType, Device};
+use candle_core::{DType, Device, DeviceLocation, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::models::phi3::{Config as Phi3Config, Model as Phi3};
-use candle_transformers::models::quantized_phi3::ModelWeights as QuantizedPhi3;
+use candle_transformers::models::phi3::Config as Phi3Config;
 use hf_hub::api::sync::ApiBuilder;
 use hf_hub::Repo;
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use tokenizers::Tokenizer;
 use tracing::debug;
 
+use crate::causal_lm::{BatchedCausalLm, CausalLm, Phi3Model, QuantizedPhi3Model, ThreadPoolModel};
+use crate::gguf_tokenizer::tokenizer_from_gguf;
+use crate::lora::{LoraAdapter, LoraPhi3Model};
+use crate::integrity::{read_safetensors_header, validate_gguf, validate_safetensors, verify_sha256};
+use crate::memory::{format_memory_usage, mmaped_gguf_tensors, peak_memory_usage};
 use crate::text_generator::TextGenerator;
+use crate::scheduler::Scheduler;
+use crate::stream::{InferenceStream, TokenChunkSink};
+use crate::worker::spawn_worker;
 use crate::{PhiError, GPU_SUPPORTED};
 
 #[derive(Debug, Clone)]
@@ -33,6 +43,14 @@ pub enum ChatFormat {
     Llama2,  // Phi-3 style with <|system|>, <|end|>, etc.
 }
 
+/// The type the weights of safetensors models are loaded as. GGUF models always compute in `f32`.
+#[derive(Clone, Debug)]
+pub enum ModelDType {
+    F32,
+    F16,
+    BF16,
+}
+
 #[derive(Debug, Clone)]
 pub struct InferenceOptions {
     pub token_count: u16,
@@ -43,12 +61,26 @@ pub struct InferenceOptions {
     pub repeat_last_n: u16,
     pub seed: u64,
     pub chat_format: ChatFormat,
+    /// The name of the LoRA adapter (added with `PhiEngineBuilder::with_lora_adapter`) to apply, `None` for the base model.
+    pub lora_adapter: Option<String>,
+    /// Identifies the request in the `PhiEventHandler` callbacks and the result, generated when `None`.
+    pub request_id: Option<String>,
+    /// Computes the log probability of every generated token, reported in the streamed `TokenChunk`s.
+    pub logprobs: bool,
+    /// Ends the generation once the text contains one of these, which is cut off from the result.
+    pub stop_sequences: Vec<String>,
 }
 
 pub struct InferenceOptionsBuilder {
     inner: Mutex<InferenceOptions>,
 }
 
+impl Default for InferenceOptionsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InferenceOptionsBuilder {
     pub fn new() -> Self {
         Self {
@@ -61,6 +93,10 @@ impl InferenceOptionsBuilder {
                 repeat_last_n: 64,
                 seed: 146628346,
                 chat_format: ChatFormat::Llama2,
+                lora_adapter: None,
+                request_id: None,
+                logprobs: false,
+                stop_sequences: Vec::new(),
             }),
         }
     }
@@ -129,6 +165,38 @@ impl InferenceOptionsBuilder {
         Ok(())
     }
 
+    pub fn with_lora_adapter(&self, lora_adapter: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.lora_adapter = Some(lora_adapter);
+        Ok(())
+    }
+
+    pub fn with_request_id(&self, request_id: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.request_id = Some(request_id);
+        Ok(())
+    }
+
+    pub fn with_logprobs(&self, logprobs: bool) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.logprobs = logprobs;
+        Ok(())
+    }
+
+    pub fn with_stop_sequences(&self, stop_sequences: Vec<String>) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.stop_sequences = stop_sequences;
+        Ok(())
+    }
+
     pub fn build(&self) -> Result<InferenceOptions, PhiError> {
         let inner = self.inner.lock().map_err(|e| PhiError::LockingError {
             error_text: e.to_string(),
@@ -139,34 +207,147 @@ impl InferenceOptionsBuilder {
 
 #[derive(Debug, Clone)]
 pub struct InferenceResult {
+    pub request_id: String,
     pub token_count: u16,
+    /// The number of tokens of the prompt, including the conversation history and chat template.
+    pub prompt_token_count: u32,
+    pub finish_reason: FinishReason,
     pub result_text: String,
     pub duration: f64,
     pub tokens_per_second: f64,
 }
 
+/// Why the generation of an `InferenceResult` ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model generated an end token, or the text reached one of the stop sequences.
+    Stop,
+    /// The response reached the token count, or filled the rest of the context.
+    Length,
+}
+
+/// A prompt of `PhiEngine::run_batch`, with its own conversation context.
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub prompt: String,
+    pub conversation_context: Option<ConversationContext>,
+}
+
+/// The outcome of one item of `PhiEngine::run_batch`.
+#[derive(Debug, Clone)]
+pub enum BatchItemResult {
+    Success { result: InferenceResult },
+    Failure { error: PhiError },
+}
+
+impl From<Result<InferenceResult, PhiError>> for BatchItemResult {
+    fn from(result: Result<InferenceResult, PhiError>) -> Self {
+        match result {
+            Ok(result) => BatchItemResult::Success { result },
+            Err(error) => BatchItemResult::Failure { error },
+        }
+    }
+}
+
+/// Describes the model an engine has loaded.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    /// The architecture from the GGUF metadata, or of the model implementation used for safetensors.
+    pub architecture: String,
+    pub parameter_count: u64,
+    /// The GGUF type holding most of the weights (e.g. `Q4_0`), `None` for safetensors models.
+    pub quantization: Option<String>,
+    pub max_context: u64,
+    pub vocab_size: u64,
+    pub special_tokens: Vec<String>,
+    /// `cpu` or `metal`
+    pub device: String,
+    /// The type the model computes in (quantized models dequantize to `f32`).
+    pub dtype: String,
+    /// Seconds it took to load the model, including downloads.
+    pub load_time: f64,
+    /// The number of threads the forward passes run on (when computing on the CPU).
+    pub cpu_threads: u64,
+    /// The SIMD instruction sets and BLAS libraries candle was compiled with, e.g. `avx2` or `neon`.
+    pub simd_features: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct EngineOptions {
     pub cache_dir: String,
     pub model_provider: PhiModelProvider,
     pub tokenizer_provider: TokenizerProvider,
     pub use_flash_attention: bool,
+    pub use_mmap: bool,
+    pub offline: bool,
     pub context_window: Option<u16>,
     pub use_gpu: bool,
-    pub dtype: Option<String>,
+    pub dtype: Option<ModelDType>,
+    pub cpu_threads: Option<u16>,
+    pub file_provider: Option<Arc<dyn PhiFileProvider>>,
+    pub hub_options: HubOptions,
+    /// LoRA adapters as (name, path of the PEFT adapter folder).
+    pub lora_adapters: Vec<(String, String)>,
+    /// Enables continuous batching with up to this many concurrent requests.
+    pub max_batch_size: Option<u16>,
+}
+
+/// Settings applied to every Hugging Face Hub client created by the engine.
+#[derive(Debug, Clone, Default)]
+pub struct HubOptions {
+    pub endpoint: Option<String>,
+    pub token: Option<String>,
+    pub user_agent: Option<String>,
 }
 
 pub trait PhiEventHandler: Send + Sync {
     fn on_model_loaded(&self) -> Result<(), PhiError>;
-    fn on_inference_started(&self) -> Result<(), PhiError>;
-    fn on_inference_ended(&self) -> Result<(), PhiError>;
-    fn on_inference_token(&self, token: String) -> Result<(), PhiError>;
+    fn on_inference_started(&self, request_id: String) -> Result<(), PhiError>;
+    fn on_inference_ended(&self, request_id: String) -> Result<(), PhiError>;
+    fn on_inference_token(&self, request_id: String, token: String) -> Result<(), PhiError>;
+    fn on_download_started(&self, file_name: String, total_bytes: u64) -> Result<(), PhiError>;
+    fn on_download_progress(&self, file_name: String, downloaded_bytes: u64) -> Result<(), PhiError>;
+    fn on_download_completed(&self, file_name: String) -> Result<(), PhiError>;
+}
+
+/// Supplies model and tokenizer files from the host application, e.g. from an asset bundle
+/// or an encrypted archive, instead of a plain path on disk.
+///
+/// Small files (configs, safetensors indexes, tokenizers) are requested with `read_file`,
+/// while model weights are read in chunks through `open_file`.
+pub trait PhiFileProvider: Send + Sync {
+    fn read_file(&self, file_name: String) -> Result<Vec<u8>, PhiError>;
+    fn open_file(&self, file_name: String) -> Result<Arc<dyn PhiFileStream>, PhiError>;
+}
+
+/// Random access to a file owned by the host application.
+pub trait PhiFileStream: Send + Sync {
+    fn get_length(&self) -> Result<u64, PhiError>;
+    fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>, PhiError>;
+}
+
+impl std::fmt::Debug for dyn PhiFileProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PhiFileProvider")
+    }
+}
+
+impl std::fmt::Debug for dyn PhiFileStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PhiFileStream")
+    }
 }
 
 pub struct PhiEngineBuilder {
     inner: Mutex<PhiEngineBuilderInner>,
 }
 
+impl Default for PhiEngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PhiEngineBuilder {
     pub fn new() -> Self {
         Self {
@@ -190,6 +371,79 @@ impl PhiEngineBuilder {
         Ok(())
     }
 
+    pub fn with_cpu_threads(&self, cpu_threads: u16) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.cpu_threads = Some(cpu_threads);
+        Ok(())
+    }
+
+    pub fn with_continuous_batching(&self, max_batch_size: u16) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.max_batch_size = Some(max_batch_size);
+        Ok(())
+    }
+
+    pub fn with_dtype(&self, dtype: ModelDType) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.dtype = Some(dtype);
+        Ok(())
+    }
+
+    pub fn with_lora_adapter(&self, name: String, adapter_path: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.lora_adapters.retain(|(existing, _)| *existing != name);
+        inner.lora_adapters.push((name, adapter_path));
+        Ok(())
+    }
+
+    pub fn with_mmap(&self, use_mmap: bool) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.use_mmap = use_mmap;
+        Ok(())
+    }
+
+    pub fn with_offline(&self, offline: bool) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.offline = offline;
+        Ok(())
+    }
+
+    pub fn with_hf_endpoint(&self, endpoint: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.hub_options.endpoint = Some(endpoint);
+        Ok(())
+    }
+
+    pub fn with_hf_token(&self, token: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.hub_options.token = Some(token);
+        Ok(())
+    }
+
+    pub fn with_user_agent(&self, user_agent: String) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.hub_options.user_agent = Some(user_agent);
+        Ok(())
+    }
+
     pub fn with_event_handler(
         &self,
         event_handler: Arc<dyn PhiEventHandler>,
@@ -201,6 +455,17 @@ impl PhiEngineBuilder {
         Ok(())
     }
 
+    pub fn with_file_provider(
+        &self,
+        file_provider: Arc<dyn PhiFileProvider>,
+    ) -> Result<(), PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
+        inner.file_provider = Some(file_provider);
+        Ok(())
+    }
+
     pub fn with_model_provider(&self, model_provider: PhiModelProvider) -> Result<(), PhiError> {
         let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
             error_text: e.to_string(),
@@ -228,20 +493,42 @@ impl PhiEngineBuilder {
         Ok(())
     }
 
-    pub fn build(&self, cache_dir: String) -> Result<Arc<PhiEngine>, PhiError> {
+    /// Downloads the files the configured providers need into `cache_dir`, without loading the model.
+    /// Only Hugging Face providers download anything, for all others this is a no-op.
+    pub fn download(&self, cache_dir: String) -> Result<(), PhiError> {
         let inner = self.inner.lock().map_err(|e| PhiError::LockingError {
             error_text: e.to_string(),
         })?;
+        download_hub_files(
+            &cache_dir,
+            &inner.model_provider,
+            &inner.tokenizer_provider,
+            &inner.hub_options,
+            inner.event_handler.clone(),
+        )
+    }
+
+    pub fn build(&self, cache_dir: String) -> Result<Arc<PhiEngine>, PhiError> {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })?;
         let engine_options = EngineOptions {
-            cache_dir: cache_dir,
-            model_provider: inner.model_provider.clone(),
+            cache_dir,
+            model_provider: inner.take_model_provider(),
             tokenizer_provider: inner.tokenizer_provider.clone(),
             use_flash_attention: inner.use_flash_attention,
-            context_window: inner.context_window.clone(),
+            use_mmap: inner.use_mmap,
+            offline: inner.offline || hf_hub_offline_from_env(),
+            context_window: inner.context_window,
             use_gpu: inner.use_gpu,
-            dtype: Some("bf16".to_string()),
+            dtype: inner.dtype.clone(),
+            cpu_threads: inner.cpu_threads,
+            file_provider: inner.file_provider.clone(),
+            hub_options: inner.hub_options.clone(),
+            lora_adapters: inner.lora_adapters.clone(),
+            max_batch_size: inner.max_batch_size,
         };
-        PhiEngine::new(engine_options, inner.event_handler.clone()).map(|engine| Arc::new(engine))
+        PhiEngine::new(engine_options, inner.event_handler.clone()).map(Arc::new)
     }
 
     pub fn build_stateful(
@@ -249,27 +536,34 @@ impl PhiEngineBuilder {
         cache_dir: String,
         system_instruction: Option<String>,
     ) -> Result<Arc<StatefulPhiEngine>, PhiError> {
-        let inner = self.inner.lock().map_err(|e| PhiError::LockingError {
+        let mut inner = self.inner.lock().map_err(|e| PhiError::LockingError {
             error_text: e.to_string(),
         })?;
         let engine_options = EngineOptions {
-            cache_dir: cache_dir,
-            model_provider: inner.model_provider.clone(),
+            cache_dir,
+            model_provider: inner.take_model_provider(),
             tokenizer_provider: inner.tokenizer_provider.clone(),
             use_flash_attention: inner.use_flash_attention,
-            context_window: inner.context_window.clone(),
+            use_mmap: inner.use_mmap,
+            offline: inner.offline || hf_hub_offline_from_env(),
+            context_window: inner.context_window,
             use_gpu: inner.use_gpu,
-            dtype: Some("bf16".to_string()),
+            dtype: inner.dtype.clone(),
+            cpu_threads: inner.cpu_threads,
+            file_provider: inner.file_provider.clone(),
+            hub_options: inner.hub_options.clone(),
+            lora_adapters: inner.lora_adapters.clone(),
+            max_batch_size: inner.max_batch_size,
         };
 
         let conversation_context = ConversationContext {
-            system_instruction: system_instruction,
+            system_instruction,
             messages: Vec::new(),
         };
 
         let engine = PhiEngine::new(engine_options, inner.event_handler.clone())?;
         Ok(Arc::new(StatefulPhiEngine {
-            engine: engine,
+            engine,
             conversation_context: Mutex::new(conversation_context),
         }))
     }
@@ -280,7 +574,15 @@ struct PhiEngineBuilderInner {
     tokenizer_provider: TokenizerProvider,
     model_provider: PhiModelProvider,
     use_flash_attention: bool,
+    use_mmap: bool,
+    offline: bool,
     event_handler: Option<Arc<dyn PhiEventHandler>>,
+    file_provider: Option<Arc<dyn PhiFileProvider>>,
+    hub_options: HubOptions,
+    dtype: Option<ModelDType>,
+    cpu_threads: Option<u16>,
+    lora_adapters: Vec<(String, String)>,
+    max_batch_size: Option<u16>,
     use_gpu: bool,
 }
 
@@ -291,36 +593,93 @@ impl PhiEngineBuilderInner {
             tokenizer_provider: TokenizerProvider::HuggingFace {
                 tokenizer_repo: "microsoft/Phi-3-mini-4k-instruct".to_string(),
                 tokenizer_file_name: "tokenizer.json".to_string(),
+                tokenizer_revision: "main".to_string(),
+                tokenizer_sha256: None,
             },
             model_provider: PhiModelProvider::HuggingFaceGguf {
                 model_repo: "microsoft/Phi-3-mini-4k-instruct-gguf".to_string(),
                 model_file_name: "Phi-3-mini-4k-instruct-q4.gguf".to_string(),
                 model_revision: "main".to_string(),
+                model_sha256: None,
             },
             use_gpu: false,
             event_handler: None,
+            file_provider: None,
+            hub_options: HubOptions::default(),
+            dtype: None,
+            cpu_threads: None,
+            lora_adapters: Vec::new(),
+            max_batch_size: None,
             use_flash_attention: false,
+            use_mmap: false,
+            offline: false,
+        }
+    }
+
+    // in-memory models can be gigabytes in size, so they are handed over to the engine instead of being copied
+    fn take_model_provider(&mut self) -> PhiModelProvider {
+        match &mut self.model_provider {
+            PhiModelProvider::Bytes {
+                model_bytes,
+                model_sha256,
+            } => PhiModelProvider::Bytes {
+                model_bytes: std::mem::take(model_bytes),
+                model_sha256: model_sha256.clone(),
+            },
+            model_provider => model_provider.clone(),
         }
     }
 }
 
+// same convention as the Python huggingface_hub library
+fn hf_hub_offline_from_env() -> bool {
+    std::env::var("HF_HUB_OFFLINE")
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Where the model weights are loaded from.
+///
+/// Every variant accepts the expected SHA-256 hashes (hex encoded) of the model weights, which are
+/// verified before the model is loaded. Safetensors models may be split into several files, so
+/// their hashes are keyed by the file name as it appears in the safetensors index.
 #[derive(Debug, Clone)]
 pub enum PhiModelProvider {
     HuggingFace {
         model_repo: String,
         model_revision: String,
+        model_sha256: HashMap<String, String>,
     },
     HuggingFaceGguf {
         model_repo: String,
         model_file_name: String,
         model_revision: String,
+        model_sha256: Option<String>,
     },
     FileSystem {
         index_path: String,
         config_path: String,
+        model_sha256: HashMap<String, String>,
     },
     FileSystemGguf {
         model_path: String,
+        model_sha256: Option<String>,
+    },
+    // read through the builder's file provider: either a `.gguf` file, or a safetensors index
+    // which is accompanied by `config.json`; hashes are keyed by file name in both cases
+    Custom {
+        model_file_name: String,
+        model_sha256: HashMap<String, String>,
+    },
+    /// A GGUF model held in memory, e.g. after decrypting it, so that it never touches disk.
+    ///
+    /// The buffer is copied once when it crosses the FFI boundary and is then moved (not copied)
+    /// into the engine by `build`, so it can only be used for a single build. While loading, peak
+    /// memory is roughly the size of the buffer plus the size of the loaded weights; the buffer is
+    /// released as soon as the weights have been read.
+    Bytes {
+        model_bytes: Vec<u8>,
+        model_sha256: Option<String>,
     },
 }
 
@@ -329,9 +688,22 @@ pub enum TokenizerProvider {
     HuggingFace {
         tokenizer_repo: String,
         tokenizer_file_name: String,
+        tokenizer_revision: String,
+        tokenizer_sha256: Option<String>,
     },
     FileSystem {
         tokenizer_path: String,
+        tokenizer_sha256: Option<String>,
+    },
+    FromModel,
+    Custom {
+        tokenizer_file_name: String,
+        tokenizer_sha256: Option<String>,
+    },
+    /// The contents of a `tokenizer.json` file held in memory.
+    Bytes {
+        tokenizer_bytes: Vec<u8>,
+        tokenizer_sha256: Option<String>,
     },
 }
 
@@ -351,6 +723,46 @@ impl StatefulPhiEngine {
         &self,
         prompt_text: &str,
         inference_options: &InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+    ) -> Result<InferenceResult, PhiError> {
+        let event_handler = event_handler.or_else(|| self.engine.event_handler.clone());
+        self.generate(prompt_text, inference_options, event_handler, None, None)
+    }
+
+    /// Like `PhiEngine::run_inference_async`. The conversation stays locked until the inference
+    /// completes or is cancelled, a cancelled inference does not add to the history.
+    pub async fn run_inference_async(
+        self: Arc<Self>,
+        prompt_text: String,
+        inference_options: InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+    ) -> Result<InferenceResult, PhiError> {
+        spawn_worker("phi-engine-inference", move |cancelled| {
+            let event_handler = event_handler.or_else(|| self.engine.event_handler.clone());
+            self.generate(&prompt_text, &inference_options, event_handler, None, Some(cancelled))
+        })
+        .await
+    }
+
+    /// Like `PhiEngine::stream_inference`. The streamed response is added to the history once the
+    /// stream has ended.
+    pub fn stream_inference(
+        self: Arc<Self>,
+        prompt_text: String,
+        inference_options: InferenceOptions,
+    ) -> Arc<InferenceStream> {
+        InferenceStream::spawn(move |chunk_sink, cancelled| {
+            self.generate(&prompt_text, &inference_options, None, Some(chunk_sink), Some(cancelled))
+        })
+    }
+
+    fn generate(
+        &self,
+        prompt_text: &str,
+        inference_options: &InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+        chunk_sink: Option<Arc<dyn TokenChunkSink>>,
+        cancelled: Option<Arc<AtomicBool>>,
     ) -> Result<InferenceResult, PhiError> {
         let mut conversation_context =
             self.conversation_context
@@ -360,10 +772,14 @@ impl StatefulPhiEngine {
                 })?;
         let result = self
             .engine
-            .run_inference(prompt_text, &conversation_context, inference_options)
-            .map_err(|e| PhiError::InferenceError {
-                error_text: e.to_string(),
-            })?;
+            .generate(
+                prompt_text,
+                &conversation_context,
+                inference_options,
+                event_handler,
+                chunk_sink,
+                cancelled,
+            )?;
 
         conversation_context.messages.push(ConversationMessage {
             role: Role::Assistant,
@@ -384,6 +800,10 @@ impl StatefulPhiEngine {
         Ok(())
     }
 
+    pub fn get_model_info(&self) -> ModelInfo {
+        self.engine.get_model_info()
+    }
+
     pub fn get_history(&self) -> Result<Vec<ConversationMessage>, PhiError> {
         let conversation_context =
             self.conversation_context
@@ -395,18 +815,14 @@ impl StatefulPhiEngine {
     }
 }
 
-#[derive(Clone)]
-pub enum Model {
-    Standard(Phi3),
-    Quantized(QuantizedPhi3),
-}
-
 pub struct PhiEngine {
-    pub model: Model,
+    pub model: Box<dyn CausalLm>,
     pub tokenizer: Tokenizer,
-    pub device: Device,
     pub event_handler: Option<Arc<dyn PhiEventHandler>>,
     pub context_window: u16,
+    pub model_info: ModelInfo,
+    // runs all requests on one model instance when continuous batching is enabled
+    scheduler: Option<Scheduler>,
 }
 
 impl PhiEngine {
@@ -422,167 +838,331 @@ impl PhiEngine {
             Device::Cpu
         };
 
-        let (files, is_gguf, config) = match engine_options.model_provider {
+        // with TokenizerProvider::FromModel, safetensors models ship a tokenizer.json next to the weights
+        // while GGUF models carry the tokenizer in their metadata
+        let tokenizer_from_model = matches!(
+            engine_options.tokenizer_provider,
+            TokenizerProvider::FromModel
+        );
+
+        if engine_options.offline {
+            let missing_files = find_missing_cached_files(
+                &engine_options.cache_dir,
+                &engine_options.model_provider,
+                &engine_options.tokenizer_provider,
+            );
+            if !missing_files.is_empty() {
+                return Err(PhiError::ModelNotCached { missing_files });
+            }
+        }
+
+        // each model file is paired with its name, used to look up its expected hash and in errors
+        let (files, is_gguf, config, model_tokenizer_file, model_sha256) = match engine_options.model_provider {
             PhiModelProvider::HuggingFace {
                 model_repo,
                 model_revision,
+                model_sha256,
             } => {
-                let api_builder = ApiBuilder::new()
-                    .with_cache_dir(PathBuf::from(engine_options.cache_dir.clone()));
-                let api = api_builder
-                    .build()
-                    .map_err(|e| PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    })?;
-                let repo = Repo::with_revision(
-                    model_repo.to_string(),
-                    hf_hub::RepoType::Model,
-                    model_revision,
-                );
-                let api = api.repo(repo);
-                let api_provider = ApiFileProvider { repo: api };
-                let files = load_safetensors(&api_provider, "model.safetensors.index.json")?;
+                let hub_provider = hub_file_provider(
+                    &engine_options.cache_dir,
+                    engine_options.offline,
+                    &engine_options.hub_options,
+                    &model_repo,
+                    &model_revision,
+                    event_handler.clone(),
+                )?;
+                let files = load_safetensors(hub_provider.as_ref(), SAFETENSORS_INDEX_FILE)?;
 
                 debug!("Loaded model files: {:?}", files);
 
-                let config = load_config(&api_provider, "config.json")?;
+                let config = load_config(hub_provider.as_ref(), "config.json")?;
                 debug!("Loaded model config: {:?}", config);
-                (files, false, Some(config))
+
+                let tokenizer_path = if tokenizer_from_model {
+                    Some(hub_provider.get("tokenizer.json")?)
+                } else {
+                    None
+                };
+                (files, false, Some(config), tokenizer_path, model_sha256)
             }
             PhiModelProvider::HuggingFaceGguf {
                 model_repo,
                 model_file_name,
                 model_revision,
+                model_sha256,
             } => {
-                let api_builder = ApiBuilder::new()
-                    .with_cache_dir(PathBuf::from(engine_options.cache_dir.clone()));
-                let api = api_builder
-                    .build()
-                    .map_err(|e| PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    })?;
-                let repo = Repo::with_revision(
-                    model_repo.to_string(),
-                    hf_hub::RepoType::Model,
-                    model_revision,
-                );
-                let api = api.repo(repo);
-                let model_path = api.get(model_file_name.as_str()).map_err(|e| {
-                    PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    }
-                })?;
+                let hub_provider = hub_file_provider(
+                    &engine_options.cache_dir,
+                    engine_options.offline,
+                    &engine_options.hub_options,
+                    &model_repo,
+                    &model_revision,
+                    event_handler.clone(),
+                )?;
+                let model_path = hub_provider.get(&model_file_name)?;
                 debug!(" --> Downloaded model to {:?}...", model_path);
-                (vec![model_path], true, None)
+                let model_sha256 = single_file_sha256(&model_file_name, model_sha256);
+                (vec![(model_file_name, model_path)], true, None, None, model_sha256)
             }
-            PhiModelProvider::FileSystemGguf { model_path } => (vec![model_path.into()], true, None),
-            PhiModelProvider::FileSystem { index_path, config_path } => {
-                let index_path = std::path::PathBuf::from(&index_path);
-                if !index_path.is_absolute() {
-                    return Err(PhiError::InitalizationError {
-                        error_text: format!("The index path must be absolute. Provided path: {:?}", index_path),
-                    });
-                }
-
-                let base_dir = match index_path.parent() {
-                    Some(parent) => parent.to_path_buf(),
-                    None => {
-                        return Err(PhiError::InitalizationError {
-                            error_text: format!("The index path {:?} does not have a valid parent directory", index_path),
-                        });
-                    }
-                };
-
-                let index_path_str = match index_path.to_str() {
-                    Some(path_str) => path_str,
-                    None => {
-                        return Err(PhiError::InitalizationError {
-                            error_text: format!("The index path {:?} could not be converted to a valid string", index_path),
-                        });
-                    }
-                };
-
-                let fs_provider = FilesystemFileProvider::new(base_dir);
-                let files = load_safetensors(&fs_provider, &index_path_str)?;
+            PhiModelProvider::FileSystemGguf { model_path, model_sha256 } => {
+                let model_sha256 = single_file_sha256(&model_path, model_sha256);
+                let model_file = ModelFile::Path(model_path.clone().into());
+                (vec![(model_path, model_file)], true, None, None, model_sha256)
+            }
+            PhiModelProvider::FileSystem { index_path, config_path, model_sha256 } => {
+                let fs_provider = filesystem_file_provider(&index_path)?;
+                let files = load_safetensors(&fs_provider, &index_path)?;
                 debug!("Loaded model files: {:?}", files);
 
                 let config = load_config(&fs_provider, &config_path)?;
                 debug!("Loaded model config: {:?}", config);
-                (files, false, Some(config))
+
+                let tokenizer_path = if tokenizer_from_model {
+                    Some(fs_provider.get("tokenizer.json")?)
+                } else {
+                    None
+                };
+                (files, false, Some(config), tokenizer_path, model_sha256)
             },
+            PhiModelProvider::Bytes { model_bytes, model_sha256 } => {
+                if model_bytes.is_empty() {
+                    return Err(PhiError::InitalizationError {
+                        error_text: "The model bytes are empty. In-memory models are moved into the engine and can only be used for a single build".to_string(),
+                    });
+                }
+                debug!(" --> Loading model from {} bytes in memory...", model_bytes.len());
+                let model_sha256 = single_file_sha256(IN_MEMORY_MODEL_NAME, model_sha256);
+                (vec![(IN_MEMORY_MODEL_NAME.to_string(), ModelFile::Bytes(model_bytes))], true, None, None, model_sha256)
+            }
+            PhiModelProvider::Custom { model_file_name, model_sha256 } => {
+                let custom_provider = ForeignFileProvider::new(engine_options.file_provider.clone())?;
+                if model_file_name.ends_with(".gguf") {
+                    let model_file = custom_provider.open(&model_file_name)?;
+                    debug!(" --> Loaded model file {:?}...", model_file);
+                    (vec![(model_file_name, model_file)], true, None, None, model_sha256)
+                } else {
+                    let files = load_safetensors(&custom_provider, &model_file_name)?;
+                    debug!("Loaded model files: {:?}", files);
+
+                    let config = load_config(&custom_provider, "config.json")?;
+                    debug!("Loaded model config: {:?}", config);
+
+                    let tokenizer_file = if tokenizer_from_model {
+                        Some(custom_provider.get("tokenizer.json")?)
+                    } else {
+                        None
+                    };
+                    (files, false, Some(config), tokenizer_file, model_sha256)
+                }
+            }
         };
 
-        let tokenizer_path = match engine_options.tokenizer_provider {
+        let tokenizer_file = match engine_options.tokenizer_provider {
             TokenizerProvider::HuggingFace {
                 tokenizer_repo,
                 tokenizer_file_name,
+                tokenizer_revision,
+                tokenizer_sha256,
             } => {
-                let api_builder = ApiBuilder::new()
-                    .with_cache_dir(PathBuf::from(engine_options.cache_dir.clone()));
-                let api = api_builder
-                    .build()
-                    .map_err(|e| PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    })?;
-                let repo = Repo::with_revision(
-                    tokenizer_repo.to_string(),
-                    hf_hub::RepoType::Model,
-                    "main".to_string(),
-                );
-                let api = api.repo(repo);
-                let tokenizer_path = api.get(tokenizer_file_name.as_str()).map_err(|e| {
-                    PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    }
-                })?;
+                let hub_provider = hub_file_provider(
+                    &engine_options.cache_dir,
+                    engine_options.offline,
+                    &engine_options.hub_options,
+                    &tokenizer_repo,
+                    &tokenizer_revision,
+                    event_handler.clone(),
+                )?;
+                let tokenizer_path = hub_provider.get(&tokenizer_file_name)?;
                 debug!(" --> Downloaded tokenizer to {:?}...", tokenizer_path);
-                tokenizer_path
+                Some((tokenizer_file_name, tokenizer_path, tokenizer_sha256))
+            }
+            TokenizerProvider::FileSystem { tokenizer_path, tokenizer_sha256 } => {
+                let tokenizer_file = ModelFile::Path(tokenizer_path.clone().into());
+                Some((tokenizer_path, tokenizer_file, tokenizer_sha256))
+            }
+            TokenizerProvider::FromModel => model_tokenizer_file.map(|file| ("tokenizer.json".to_string(), file, None)),
+            TokenizerProvider::Custom {
+                tokenizer_file_name,
+                tokenizer_sha256,
+            } => {
+                let custom_provider = ForeignFileProvider::new(engine_options.file_provider.clone())?;
+                let tokenizer_file = custom_provider.get(&tokenizer_file_name)?;
+                Some((tokenizer_file_name, tokenizer_file, tokenizer_sha256))
             }
-            TokenizerProvider::FileSystem { tokenizer_path } => tokenizer_path.into(),
+            TokenizerProvider::Bytes { tokenizer_bytes, tokenizer_sha256 } => {
+                Some(("tokenizer bytes".to_string(), ModelFile::Bytes(tokenizer_bytes), tokenizer_sha256))
+            }
+        };
+
+        // corrupted or partial files are rejected with a clear error before anything tries to parse them
+        if let Some((file_name, file, Some(expected))) = &tokenizer_file {
+            verify_sha256(file_name, &mut file.reader()?, expected)?;
+        }
+        verify_model_files(&files, &model_sha256, is_gguf)?;
+
+        let mut tokenizer = match tokenizer_file {
+            Some((_, tokenizer_file, _)) => Some(load_tokenizer(tokenizer_file)?),
+            None => None,
         };
 
         // defaults
         let context_window = engine_options.context_window.unwrap_or(3800);
 
-        let model = if is_gguf {
+        if engine_options.max_batch_size == Some(0) {
+            return Err(PhiError::InitalizationError {
+                error_text: "The maximum batch size must be greater than 0".to_string(),
+            });
+        }
+
+        let peak_memory_before_load = peak_memory_usage();
+        // the batched model shares its weights with the model used for single requests
+        let (model, batched_model, weights): (Box<dyn CausalLm>, Option<Arc<dyn BatchedCausalLm>>, WeightsSummary) = if is_gguf {
+            if !engine_options.lora_adapters.is_empty() {
+                return Err(PhiError::InitalizationError {
+                    error_text: "LoRA adapters can only be applied to safetensors models".to_string(),
+                });
+            }
+            if engine_options.max_batch_size.is_some() && engine_options.use_flash_attention {
+                return Err(PhiError::InitalizationError {
+                    error_text: "Flash attention is not supported with continuous batching".to_string(),
+                });
+            }
             // Load quantized model using gguf
-            let mut file = File::open(&files[0]).map_err(|e| PhiError::InitalizationError {
-                error_text: e.to_string(),
-            })?;
-            let model_content =
-                gguf_file::Content::read(&mut file).map_err(|e| PhiError::InitalizationError {
-                    error_text: e.to_string(),
+            let model_file = files
+                .into_iter()
+                .next()
+                .map(|(_, file)| file)
+                .ok_or_else(|| PhiError::InitalizationError {
+                    error_text: "Model file not found".to_string(),
                 })?;
-            let quantized_model =
-                candle_transformers::models::quantized_phi3::ModelWeights::from_gguf(
+            let model_path = match &model_file {
+                ModelFile::Path(path) => Some(path.clone()),
+                _ => None,
+            };
+            let mut file = model_file.into_reader()?;
+            let model_content = gguf_file::Content::read(&mut file)
+                .map_err(|e| PhiError::initialization_error(e.to_string()))?;
+            let weights = gguf_weights_summary(&model_content);
+            if weights.architecture != "phi3" {
+                return Err(PhiError::UnsupportedArchitecture {
+                    architecture: weights.architecture,
+                });
+            }
+            if tokenizer.is_none() {
+                tokenizer = Some(tokenizer_from_gguf(&model_content).map_err(|e| {
+                    PhiError::InitalizationError {
+                        error_text: format!("Error loading tokenizer from GGUF metadata: {}", e),
+                    }
+                })?);
+            }
+            if let Some(dtype) = &engine_options.dtype {
+                debug!(" --> Ignoring dtype {:?}, GGUF models compute in f32", dtype);
+            }
+            // the quantized tensors of a mapped file are used in place, which only works on the CPU
+            let mmaped_tensors = match model_path {
+                Some(path) if engine_options.use_mmap && device.is_cpu() => {
+                    if engine_options.use_flash_attention {
+                        return Err(PhiError::InitalizationError {
+                            error_text: "Flash attention is not supported with memory mapped GGUF models".to_string(),
+                        });
+                    }
+                    Some(mmaped_gguf_tensors(&path, &model_content).map_err(|e| {
+                        PhiError::initialization_error(format!("Error memory mapping {}: {}", path.display(), e))
+                    })?)
+                }
+                _ => {
+                    if engine_options.use_mmap {
+                        debug!(" --> Not memory mapping the model, which needs a model file on disk and the CPU");
+                    }
+                    None
+                }
+            };
+            if engine_options.max_batch_size.is_some() || mmaped_tensors.is_some() {
+                // quantized_phi3 keeps the KV cache in its layers, so batches need the model which
+                // holds it outside of them. It is used for single requests too, to share the weights.
+                // It also takes the tensors of a mapped file, where quantized_phi3 reads them itself
+                let lora_model = match mmaped_tensors {
+                    Some(tensors) => LoraPhi3Model::from_gguf_tensors(&model_content, tensors, &[], &device),
+                    None => LoraPhi3Model::from_gguf(&model_content, &mut file, &[], &device),
+                }
+                .map_err(|e| PhiError::initialization_error(e.to_string()))?;
+                let batched_model = engine_options
+                    .max_batch_size
+                    .map(|_| Arc::new(lora_model.clone()) as Arc<dyn BatchedCausalLm>);
+                (Box::new(lora_model), batched_model, weights)
+            } else {
+                let quantized_model = QuantizedPhi3Model::from_gguf(
                     engine_options.use_flash_attention,
                     model_content,
                     &mut file,
                     &device,
                 )
-                .map_err(|e| PhiError::InitalizationError {
-                    error_text: e.to_string(),
-                })?;
-            Model::Quantized(quantized_model)
+                .map_err(|e| PhiError::initialization_error(e.to_string()))?;
+                (Box::new(quantized_model), None, weights)
+            }
         } else {
             if let Some(config) = config {
-                let dtype = match engine_options.dtype.as_deref() {
-                    Some("f32") => DType::F32,
-                    Some("bf16") => device.bf16_default_to_f32(),
-                    _ => DType::F32,
+                let dtype = resolve_dtype(engine_options.dtype.as_ref(), &device)?;
+                debug!(" --> Loading the model as {}", dtype.as_str());
+                let weights = WeightsSummary {
+                    architecture: "phi3".to_string(),
+                    parameter_count: safetensors_parameter_count(&files)?,
+                    quantization: None,
+                    dtype,
                 };
-                let vb = unsafe {
-                    VarBuilder::from_mmaped_safetensors(&files, dtype, &device).map_err(|e| {
-                        PhiError::InitalizationError {
-                            error_text: format!("Error loading model: {:?}", e),
+                let paths = files
+                    .iter()
+                    .map(|(_, file)| match file {
+                        ModelFile::Path(path) => Some(path.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>();
+                let vb = match paths {
+                    Some(paths) => unsafe {
+                        VarBuilder::from_mmaped_safetensors(&paths, dtype, &device).map_err(|e| {
+                            PhiError::initialization_error(format!("Error loading model: {:?}", e))
+                        })?
+                    },
+                    // files supplied by the host can't be memory mapped, so load them tensor by tensor
+                    None => {
+                        let mut tensors = HashMap::new();
+                        for (file_name, file) in files {
+                            tensors.extend(load_safetensors_tensors(&file_name, file, &device)?);
                         }
-                    })?
+                        VarBuilder::from_tensors(tensors, dtype, &device)
+                    }
                 };
-                let standard_model = candle_transformers::models::phi3::Model::new(&config, vb)
-                    .map_err(|e| PhiError::InitalizationError {
-                        error_text: e.to_string(),
-                    })?;
-                Model::Standard(standard_model)
+                if engine_options.lora_adapters.is_empty() && engine_options.max_batch_size.is_none() {
+                    let standard_model = candle_transformers::models::phi3::Model::new(&config, vb)
+                        .map_err(|e| PhiError::initialization_error(e.to_string()))?;
+                    (Box::new(Phi3Model::new(standard_model, &config, &device)), None, weights)
+                } else {
+                    // adapters are applied at runtime, so the base weights are shared by all of them.
+                    // The same model implementation batches sequences at different positions
+                    if engine_options.use_flash_attention {
+                        return Err(PhiError::InitalizationError {
+                            error_text: "Flash attention is not supported with LoRA adapters or continuous batching"
+                                .to_string(),
+                        });
+                    }
+                    let adapters = engine_options
+                        .lora_adapters
+                        .iter()
+                        .map(|(name, adapter_path)| {
+                            LoraAdapter::load(name, Path::new(adapter_path), dtype, &device).map_err(|e| {
+                                PhiError::InitalizationError {
+                                    error_text: format!("Error loading LoRA adapter {}: {}", name, e),
+                                }
+                            })
+                        })
+                        .collect::<Result<Vec<_>, PhiError>>()?;
+                    let lora_model = LoraPhi3Model::new(&config, vb, &adapters)
+                        .map_err(|e| PhiError::initialization_error(e.to_string()))?;
+                    let batched_model = engine_options
+                        .max_batch_size
+                        .map(|_| Arc::new(lora_model.clone()) as Arc<dyn BatchedCausalLm>);
+                    (Box::new(lora_model), batched_model, weights)
+                }
             } else {
                 return Err(PhiError::InitalizationError {
                     error_text: "Model config not found".to_string(),
@@ -590,37 +1170,297 @@ impl PhiEngine {
             }
         };
 
-        let tokenizer =
-            Tokenizer::from_file(tokenizer_path).map_err(|e| PhiError::InitalizationError {
-                error_text: e.to_string(),
-            })?;
+        let tokenizer = tokenizer.ok_or_else(|| PhiError::InitalizationError {
+            error_text: "Tokenizer could not be loaded from the model".to_string(),
+        })?;
+
+        // without a dedicated pool, forward passes run on the global rayon pool which uses all cores
+        let pool = engine_options
+            .cpu_threads
+            .map(cpu_thread_pool)
+            .transpose()?
+            .map(Arc::new);
+        let (model, cpu_threads) = match &pool {
+            Some(pool) => {
+                let model: Box<dyn CausalLm> = Box::new(ThreadPoolModel::new(model, pool.clone()));
+                (model, pool.current_num_threads())
+            }
+            None => (model, rayon::current_num_threads()),
+        };
+        let scheduler = match (batched_model, engine_options.max_batch_size) {
+            (Some(batched_model), Some(max_batch_size)) => {
+                Some(Scheduler::start(batched_model, max_batch_size as usize, pool)?)
+            }
+            _ => None,
+        };
 
         let event_handler_clone = event_handler.clone();
 
-        debug!(" --> Loaded the model in {:?}", start.elapsed());
+        let mut special_tokens = tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .collect::<Vec<_>>();
+        special_tokens.sort_by_key(|(id, _)| *id);
+        let model_info = ModelInfo {
+            architecture: weights.architecture,
+            parameter_count: weights.parameter_count,
+            quantization: weights.quantization,
+            max_context: model.max_context() as u64,
+            vocab_size: model.vocab_size() as u64,
+            special_tokens: special_tokens.into_iter().map(|(_, token)| token.content).collect(),
+            device: match device.location() {
+                DeviceLocation::Cpu => "cpu".to_string(),
+                DeviceLocation::Metal { .. } => "metal".to_string(),
+                DeviceLocation::Cuda { .. } => "cuda".to_string(),
+            },
+            dtype: weights.dtype.as_str().to_string(),
+            load_time: start.elapsed().as_secs_f64(),
+            cpu_threads: cpu_threads as u64,
+            simd_features: simd_features(),
+        };
+
+        debug!(" --> Loaded the model: {:?}", model_info);
+        debug!(
+            " --> Peak memory usage before loading: {}, after loading: {} (memory mapped: {})",
+            format_memory_usage(peak_memory_before_load),
+            format_memory_usage(peak_memory_usage()),
+            engine_options.use_mmap
+        );
         if let Some(event_handler) = event_handler {
             event_handler
                 .on_model_loaded()
-                .map_err(|e| PhiError::InitalizationError {
-                    error_text: e.to_string(),
-                })?;
+                .map_err(|e| PhiError::event_handler_error("on_model_loaded", e))?;
         }
 
         Ok(Self {
-            model: model,
-            tokenizer: tokenizer,
-            device: device,
+            model,
+            tokenizer,
             event_handler: event_handler_clone,
-            context_window: context_window,
+            context_window,
+            model_info,
+            scheduler,
         })
     }
 
+    /// An engine around an already loaded `model`, for testing the inference APIs without model files.
+    #[cfg(test)]
+    pub(crate) fn from_model(model: Box<dyn CausalLm>, tokenizer: Tokenizer) -> Self {
+        let model_info = ModelInfo {
+            architecture: "phi3".to_string(),
+            parameter_count: 0,
+            quantization: None,
+            max_context: model.max_context() as u64,
+            vocab_size: model.vocab_size() as u64,
+            special_tokens: vec![],
+            device: "cpu".to_string(),
+            dtype: "f32".to_string(),
+            load_time: 0.,
+            cpu_threads: rayon::current_num_threads() as u64,
+            simd_features: vec![],
+        };
+        Self {
+            model,
+            tokenizer,
+            event_handler: None,
+            context_window: model_info.max_context as u16,
+            model_info,
+            scheduler: None,
+        }
+    }
+
+    pub fn get_model_info(&self) -> ModelInfo {
+        self.model_info.clone()
+    }
+
+    /// Runs the inference, reporting its events to `event_handler` or, when `None`, to the handler
+    /// set on the builder.
     pub fn run_inference(
         &self,
         prompt_text: &str,
         conversation_context: &ConversationContext,
         inference_options: &InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
     ) -> Result<InferenceResult, PhiError> {
+        self.generate(
+            prompt_text,
+            conversation_context,
+            inference_options,
+            event_handler.or_else(|| self.event_handler.clone()),
+            None,
+            None,
+        )
+    }
+
+    /// Runs the inference on a dedicated worker thread. Dropping the returned future (e.g. cancelling
+    /// the Swift task or Kotlin coroutine) stops the generation before the next token.
+    pub async fn run_inference_async(
+        self: Arc<Self>,
+        prompt_text: String,
+        conversation_context: ConversationContext,
+        inference_options: InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+    ) -> Result<InferenceResult, PhiError> {
+        spawn_worker("phi-engine-inference", move |cancelled| {
+            self.generate(
+                &prompt_text,
+                &conversation_context,
+                &inference_options,
+                event_handler.or_else(|| self.event_handler.clone()),
+                None,
+                Some(cancelled),
+            )
+        })
+        .await
+    }
+
+    /// Starts the inference on a dedicated worker thread and returns a stream of its chunks, which
+    /// are delivered to the stream instead of the engine's `PhiEventHandler`. Every stream belongs
+    /// to a single request, so several requests can be streamed concurrently.
+    pub fn stream_inference(
+        self: Arc<Self>,
+        prompt_text: String,
+        conversation_context: ConversationContext,
+        inference_options: InferenceOptions,
+    ) -> Arc<InferenceStream> {
+        InferenceStream::spawn(move |chunk_sink, cancelled| {
+            self.generate(
+                &prompt_text,
+                &conversation_context,
+                &inference_options,
+                None,
+                Some(chunk_sink),
+                Some(cancelled),
+            )
+        })
+    }
+
+    /// Runs the inference for every item with the same options, and returns the results in the
+    /// order of the items. Items which fail are reported in their result without stopping the
+    /// others. With continuous batching, the items run together in batches of up to
+    /// `max_batch_size`, otherwise one after another.
+    ///
+    /// Events go to the handler set on the builder. Each item gets its own request id: the one
+    /// from the options followed by `-` and the index of the item, or a generated one.
+    pub fn run_batch(
+        &self,
+        items: Vec<BatchItem>,
+        inference_options: &InferenceOptions,
+    ) -> Result<Vec<BatchItemResult>, PhiError> {
+        // an unknown adapter fails the whole batch rather than every item
+        self.model
+            .box_clone()
+            .set_lora_adapter(inference_options.lora_adapter.as_deref())
+            .map_err(|e| PhiError::InferenceError {
+                error_text: e.to_string(),
+            })?;
+        let no_context = ConversationContext {
+            system_instruction: None,
+            messages: vec![],
+        };
+        let items = items.into_iter().enumerate().map(|(idx, item)| {
+            let mut options = inference_options.clone();
+            options.request_id = Some(match &inference_options.request_id {
+                Some(request_id) => format!("{}-{}", request_id, idx),
+                None => next_request_id(),
+            });
+            (item, options)
+        });
+
+        let results = match &self.scheduler {
+            Some(scheduler) => {
+                let requests = items
+                    .map(|(item, options)| {
+                        let conversation_context = item.conversation_context.as_ref().unwrap_or(&no_context);
+                        let prompt = self.format_prompt(&item.prompt, conversation_context, &options);
+                        (self.text_generator(&options, self.event_handler.clone()), prompt)
+                    })
+                    .collect();
+                scheduler.run_batch(
+                    requests,
+                    inference_options.token_count,
+                    inference_options.lora_adapter.clone(),
+                )
+            }
+            None => items
+                .map(|(item, options)| {
+                    self.generate(
+                        &item.prompt,
+                        item.conversation_context.as_ref().unwrap_or(&no_context),
+                        &options,
+                        self.event_handler.clone(),
+                        None,
+                        None,
+                    )
+                })
+                .collect(),
+        };
+        Ok(results.into_iter().map(BatchItemResult::from).collect())
+    }
+
+    fn generate(
+        &self,
+        prompt_text: &str,
+        conversation_context: &ConversationContext,
+        inference_options: &InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+        chunk_sink: Option<Arc<dyn TokenChunkSink>>,
+        cancelled: Option<Arc<AtomicBool>>,
+    ) -> Result<InferenceResult, PhiError> {
+        let prompt_with_history = self.format_prompt(prompt_text, conversation_context, inference_options);
+
+        // with continuous batching, the copy only validates the adapter
+        let mut model = self.model.box_clone();
+        model
+            .set_lora_adapter(inference_options.lora_adapter.as_deref())
+            .map_err(|e| PhiError::InferenceError {
+                error_text: e.to_string(),
+            })?;
+        let mut pipeline = self.text_generator(inference_options, event_handler);
+        if let Some(chunk_sink) = chunk_sink {
+            pipeline = pipeline.with_chunk_sink(chunk_sink);
+        }
+        if let Some(cancelled) = cancelled {
+            pipeline = pipeline.with_cancellation(cancelled);
+        }
+    
+        if let Some(scheduler) = &self.scheduler {
+            return scheduler.run(
+                pipeline,
+                prompt_with_history,
+                inference_options.token_count,
+                inference_options.lora_adapter.clone(),
+            );
+        }
+        let response = pipeline
+            .run(model.as_mut(), &prompt_with_history, inference_options.token_count)
+            .map_err(PhiError::inference_error)?;
+        Ok(response)
+    }
+
+    fn text_generator(
+        &self,
+        inference_options: &InferenceOptions,
+        event_handler: Option<Arc<dyn PhiEventHandler>>,
+    ) -> TextGenerator {
+        let request_id = inference_options
+            .request_id
+            .clone()
+            .unwrap_or_else(next_request_id);
+        TextGenerator::new(
+            self.tokenizer.clone(),
+            inference_options,
+            request_id,
+            event_handler,
+        )
+    }
+
+    fn format_prompt(
+        &self,
+        prompt_text: &str,
+        conversation_context: &ConversationContext,
+        inference_options: &InferenceOptions,
+    ) -> String {
         let mut history = conversation_context.messages.clone();
         self.trim_history_to_token_limit(&mut history, self.context_window);
         history.push(ConversationMessage {
@@ -651,7 +1491,7 @@ impl PhiEngine {
                 .collect::<String>(),
         };
     
-        let prompt_with_history = match inference_options.chat_format {
+        match inference_options.chat_format {
             ChatFormat::Llama2 => {
                 if let Some(system_instruction) = conversation_context.system_instruction.clone() {
                     format!("<|system|>{}<|end|>{}\n<|assistant|>\n", 
@@ -670,22 +1510,7 @@ impl PhiEngine {
                     format!("{}\n<|im_start|>assistant<|im_sep|>\n", history_prompt)
                 }
             }
-        };
-    
-        let mut pipeline = TextGenerator::new(
-            &self.model,
-            self.tokenizer.clone(),
-            inference_options,
-            &self.device,
-            self.event_handler.clone(),
-        );
-    
-        let response = pipeline
-            .run(&prompt_with_history, inference_options.token_count)
-            .map_err(|e: E| PhiError::InferenceError {
-                error_text: e.to_string(),
-            })?;
-        Ok(response)
+        }
     }
 
     fn count_tokens(&self, text: &str) -> usize {
@@ -709,17 +1534,29 @@ impl PhiEngine {
     }
 }
 
+// ids for requests which did not set one, unique within the process
+fn next_request_id() -> String {
+    static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+    format!("request-{}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+}
+
 // see https://github.com/huggingface/candle/blob/main/candle-examples/src/lib.rs#L125C5-L149C2
 fn load_safetensors(
     provider: &dyn FileProvider,
     json_file: &str,
-) -> Result<Vec<std::path::PathBuf>, PhiError> {
-    let json_file = provider.get(json_file)?;
-    let json_file = std::fs::File::open(json_file).map_err(|e| PhiError::InitalizationError {
-        error_text: e.to_string(),
-    })?;
+) -> Result<Vec<(String, ModelFile)>, PhiError> {
+    let json_file = provider.get(json_file)?.read_bytes()?;
+    let safetensors_files = safetensors_file_names(&json_file)?
+        .into_iter()
+        .map(|v| provider.open(&v).map(|file| (v, file)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(safetensors_files)
+}
+
+fn safetensors_file_names(json_file: &[u8]) -> Result<Vec<String>, PhiError> {
     let json: serde_json::Value =
-        serde_json::from_reader(&json_file).map_err(|e| PhiError::InitalizationError {
+        serde_json::from_slice(json_file).map_err(|e| PhiError::InitalizationError {
             error_text: e.to_string(),
         })?;
     let weight_map = match json.get("weight_map") {
@@ -737,45 +1574,703 @@ fn load_safetensors(
             safetensors_files.insert(file.to_string());
         }
     }
-    let safetensors_files = safetensors_files
-        .iter()
-        .map(|v| provider.get(v))
-        .collect::<Result<Vec<_>, _>>()?;
+    Ok(safetensors_files.into_iter().collect())
+}
 
-    Ok(safetensors_files)
+const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+
+const DOWNLOAD_RETRIES: usize = 3;
+
+fn hub_file_provider(
+    cache_dir: &str,
+    offline: bool,
+    hub_options: &HubOptions,
+    repo_id: &str,
+    revision: &str,
+    event_handler: Option<Arc<dyn PhiEventHandler>>,
+) -> Result<Box<dyn FileProvider>, PhiError> {
+    let repo = Repo::with_revision(
+        repo_id.to_string(),
+        hf_hub::RepoType::Model,
+        revision.to_string(),
+    );
+    if offline {
+        let cache = hf_hub::Cache::new(PathBuf::from(cache_dir));
+        return Ok(Box::new(CacheFileProvider {
+            repo: cache.repo(repo),
+            repo_id: repo_id.to_string(),
+            revision: revision.to_string(),
+        }));
+    }
+
+    // progress is reported through the event handler instead of a progress bar on stdout.
+    // An interrupted download leaves a partial file in the cache, which the next attempt
+    // (a retry, or the next engine build) continues from.
+    let mut api_builder = ApiBuilder::new()
+        .with_cache_dir(PathBuf::from(cache_dir))
+        .with_progress(false)
+        .with_retries(DOWNLOAD_RETRIES);
+    if let Some(endpoint) = &hub_options.endpoint {
+        api_builder = api_builder.with_endpoint(endpoint.trim_end_matches('/').to_string());
+    }
+    // without an explicit token, the one stored by `huggingface-cli login` is used
+    if let Some(token) = &hub_options.token {
+        api_builder = api_builder.with_token(Some(token.clone()));
+    }
+    if let Some(user_agent) = &hub_options.user_agent {
+        // the hub client sends "name/version" pairs
+        let (name, version) = user_agent.split_once('/').unwrap_or((user_agent, "unknown"));
+        api_builder = api_builder.with_user_agent(name, version);
+    }
+    let api = api_builder
+        .build()
+        .map_err(|e| PhiError::InitalizationError {
+            error_text: e.to_string(),
+        })?;
+    let cache = hf_hub::Cache::new(PathBuf::from(cache_dir));
+    Ok(Box::new(ApiFileProvider {
+        cache_repo: cache.repo(repo.clone()),
+        repo: api.repo(repo),
+        event_handler,
+    }))
+}
+
+fn download_hub_files(
+    cache_dir: &str,
+    model_provider: &PhiModelProvider,
+    tokenizer_provider: &TokenizerProvider,
+    hub_options: &HubOptions,
+    event_handler: Option<Arc<dyn PhiEventHandler>>,
+) -> Result<(), PhiError> {
+    let hub_provider = |repo_id: &str, revision: &str| {
+        hub_file_provider(cache_dir, false, hub_options, repo_id, revision, event_handler.clone())
+    };
+
+    match model_provider {
+        PhiModelProvider::HuggingFace {
+            model_repo,
+            model_revision,
+            ..
+        } => {
+            let hub_provider = hub_provider(model_repo, model_revision)?;
+            hub_provider.get("config.json")?;
+            if matches!(tokenizer_provider, TokenizerProvider::FromModel) {
+                hub_provider.get("tokenizer.json")?;
+            }
+            let files = load_safetensors(hub_provider.as_ref(), SAFETENSORS_INDEX_FILE)?;
+            debug!(" --> Downloaded model files: {:?}", files);
+        }
+        PhiModelProvider::HuggingFaceGguf {
+            model_repo,
+            model_file_name,
+            model_revision,
+            ..
+        } => {
+            let model_path = hub_provider(model_repo, model_revision)?.get(model_file_name)?;
+            debug!(" --> Downloaded model to {:?}...", model_path);
+        }
+        _ => {}
+    }
+
+    if let TokenizerProvider::HuggingFace {
+        tokenizer_repo,
+        tokenizer_file_name,
+        tokenizer_revision,
+        ..
+    } = tokenizer_provider
+    {
+        let tokenizer_path = hub_provider(tokenizer_repo, tokenizer_revision)?.get(tokenizer_file_name)?;
+        debug!(" --> Downloaded tokenizer to {:?}...", tokenizer_path);
+    }
+
+    Ok(())
+}
+
+// checks everything up front, so that a single error can list all the files that still need downloading
+fn find_missing_cached_files(
+    cache_dir: &str,
+    model_provider: &PhiModelProvider,
+    tokenizer_provider: &TokenizerProvider,
+) -> Vec<String> {
+    let cache = hf_hub::Cache::new(PathBuf::from(cache_dir));
+    let mut missing_files = Vec::new();
+    let mut check = |repo_id: &str, revision: &str, file_name: &str| {
+        let repo = cache.repo(Repo::with_revision(
+            repo_id.to_string(),
+            hf_hub::RepoType::Model,
+            revision.to_string(),
+        ));
+        let path = repo.get(file_name);
+        if path.is_none() {
+            missing_files.push(missing_file_name(repo_id, revision, file_name));
+        }
+        path
+    };
+
+    match model_provider {
+        PhiModelProvider::HuggingFace {
+            model_repo,
+            model_revision,
+            ..
+        } => {
+            check(model_repo, model_revision, "config.json");
+            if matches!(tokenizer_provider, TokenizerProvider::FromModel) {
+                check(model_repo, model_revision, "tokenizer.json");
+            }
+            // the shards are only known once the index is available
+            let shards = check(model_repo, model_revision, SAFETENSORS_INDEX_FILE)
+                .and_then(|index| std::fs::read(index).ok())
+                .and_then(|index| safetensors_file_names(&index).ok())
+                .unwrap_or_default();
+            for shard in shards {
+                check(model_repo, model_revision, &shard);
+            }
+        }
+        PhiModelProvider::HuggingFaceGguf {
+            model_repo,
+            model_file_name,
+            model_revision,
+            ..
+        } => {
+            check(model_repo, model_revision, model_file_name);
+        }
+        _ => {}
+    }
+
+    if let TokenizerProvider::HuggingFace {
+        tokenizer_repo,
+        tokenizer_file_name,
+        tokenizer_revision,
+        ..
+    } = tokenizer_provider
+    {
+        check(tokenizer_repo, tokenizer_revision, tokenizer_file_name);
+    }
+
+    missing_files
+}
+
+fn missing_file_name(repo_id: &str, revision: &str, file_name: &str) -> String {
+    format!("{}/{} (revision {})", repo_id, file_name, revision)
 }
 
 fn load_config(provider: &dyn FileProvider, config_file: &str) -> Result<Phi3Config, PhiError> {
-    let config_filename = provider.get(config_file)?;
-    let config_content = std::fs::read_to_string(config_filename).map_err(|e| {
+    let config_content = provider.get(config_file)?.read_bytes()?;
+    let config: serde_json::Value = serde_json::from_slice(&config_content).map_err(|e| {
         PhiError::InitalizationError {
             error_text: e.to_string(),
         }
     })?;
-    let config: Phi3Config = serde_json::from_str(&config_content).map_err(|e| {
-        PhiError::InitalizationError {
-            error_text: e.to_string(),
+    // configs without a model type are assumed to be phi3, the parsing below catches anything else
+    if let Some(model_type) = config.get("model_type").and_then(|model_type| model_type.as_str()) {
+        if model_type != "phi3" {
+            return Err(PhiError::UnsupportedArchitecture {
+                architecture: model_type.to_string(),
+            });
         }
+    }
+    let config: Phi3Config = serde_json::from_value(config).map_err(|e| PhiError::InitalizationError {
+        error_text: e.to_string(),
     })?;
     Ok(config)
 }
 
+fn resolve_dtype(dtype: Option<&ModelDType>, device: &Device) -> Result<DType, PhiError> {
+    let dtype = match dtype {
+        // bf16 where the device handles it well, f32 otherwise
+        None => return Ok(device.bf16_default_to_f32()),
+        Some(ModelDType::F32) => DType::F32,
+        Some(ModelDType::F16) => DType::F16,
+        Some(ModelDType::BF16) => DType::BF16,
+    };
+    // a small matmul fails fast if the device has no kernels for the dtype, before gigabytes of weights are loaded
+    Tensor::ones((2, 2), dtype, device)
+        .and_then(|t| t.matmul(&t))
+        .map_err(|e| PhiError::InitalizationError {
+            error_text: format!(
+                "dtype {} is not supported on {:?}: {}",
+                dtype.as_str(),
+                device.location(),
+                e
+            ),
+        })?;
+    Ok(dtype)
+}
+
+fn cpu_thread_pool(cpu_threads: u16) -> Result<rayon::ThreadPool, PhiError> {
+    if cpu_threads == 0 {
+        return Err(PhiError::InitalizationError {
+            error_text: "The number of CPU threads must be greater than 0".to_string(),
+        });
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cpu_threads as usize)
+        .thread_name(|index| format!("phi-engine-{}", index))
+        .build()
+        .map_err(|e| PhiError::InitalizationError {
+            error_text: format!("Error creating the CPU thread pool: {}", e),
+        })
+}
+
+fn simd_features() -> Vec<String> {
+    use candle_core::utils;
+
+    [
+        ("avx2", utils::with_avx()),
+        ("neon", utils::with_neon()),
+        ("simd128", utils::with_simd128()),
+        ("f16c", utils::with_f16c()),
+        ("accelerate", utils::has_accelerate()),
+        ("mkl", utils::has_mkl()),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature.to_string())
+    .collect()
+}
+
+struct WeightsSummary {
+    architecture: String,
+    parameter_count: u64,
+    quantization: Option<String>,
+    dtype: DType,
+}
+
+fn gguf_weights_summary(content: &gguf_file::Content) -> WeightsSummary {
+    let architecture = content
+        .metadata
+        .get("general.architecture")
+        .and_then(|value| value.to_string().ok().cloned())
+        .unwrap_or_else(|| "phi3".to_string());
+    let mut parameters_by_type: HashMap<String, u64> = HashMap::new();
+    for info in content.tensor_infos.values() {
+        *parameters_by_type
+            .entry(format!("{:?}", info.ggml_dtype))
+            .or_default() += info.shape.elem_count() as u64;
+    }
+    let quantization = parameters_by_type
+        .iter()
+        .max_by_key(|(_, count)| **count)
+        .map(|(ggml_dtype, _)| ggml_dtype.clone());
+    WeightsSummary {
+        architecture,
+        parameter_count: parameters_by_type.values().sum(),
+        quantization,
+        dtype: DType::F32,
+    }
+}
+
+fn safetensors_parameter_count(files: &[(String, ModelFile)]) -> Result<u64, PhiError> {
+    let mut parameter_count = 0;
+    for (file_name, file) in files {
+        let (_, header) = read_safetensors_header(file_name, &mut file.reader()?)?;
+        for (_, info) in header.iter().filter(|(name, _)| *name != "__metadata__") {
+            let shape = info.get("shape").and_then(|shape| shape.as_array());
+            parameter_count += shape
+                .map(|shape| shape.iter().filter_map(|dim| dim.as_u64()).product::<u64>())
+                .unwrap_or(0);
+        }
+    }
+    Ok(parameter_count)
+}
+
+const IN_MEMORY_MODEL_NAME: &str = "in-memory model";
+
+fn single_file_sha256(file_name: &str, sha256: Option<String>) -> HashMap<String, String> {
+    sha256
+        .map(|sha256| HashMap::from([(file_name.to_string(), sha256)]))
+        .unwrap_or_default()
+}
+
+fn verify_model_files(
+    files: &[(String, ModelFile)],
+    model_sha256: &HashMap<String, String>,
+    is_gguf: bool,
+) -> Result<(), PhiError> {
+    // a hash for a file that is not part of the model is most likely a typo, which would silently skip the check
+    if let Some(file_name) = model_sha256
+        .keys()
+        .find(|file_name| !files.iter().any(|(name, _)| name == *file_name))
+    {
+        return Err(PhiError::InitalizationError {
+            error_text: format!("A SHA-256 hash was provided for {}, which is not one of the model files", file_name),
+        });
+    }
+
+    for (file_name, file) in files {
+        let mut reader = file.reader()?;
+        if let Some(expected) = model_sha256.get(file_name) {
+            verify_sha256(file_name, &mut reader, expected)?;
+            debug!(" --> Verified SHA-256 of {}", file_name);
+        }
+        if is_gguf {
+            validate_gguf(file_name, &mut reader)?;
+        } else {
+            validate_safetensors(file_name, &mut reader)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_tokenizer(file: ModelFile) -> Result<Tokenizer, PhiError> {
+    let tokenizer = match file {
+        ModelFile::Path(path) => Tokenizer::from_file(path),
+        file => Tokenizer::from_bytes(file.read_bytes()?),
+    };
+    tokenizer.map_err(|e| PhiError::InitalizationError {
+        error_text: e.to_string(),
+    })
+}
+
+#[derive(Debug)]
+enum ModelFile {
+    Path(std::path::PathBuf),
+    Bytes(Vec<u8>),
+    Stream(Arc<dyn PhiFileStream>),
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+impl ModelFile {
+    fn read_bytes(self) -> Result<Vec<u8>, PhiError> {
+        match self {
+            ModelFile::Path(path) => std::fs::read(&path).map_err(|e| PhiError::io_error(&path, e)),
+            ModelFile::Bytes(data) => Ok(data),
+            ModelFile::Stream(stream) => {
+                let length = stream.get_length()?;
+                stream.read_at(0, length)
+            }
+        }
+    }
+
+    fn into_reader(self) -> Result<Box<dyn ReadSeek>, PhiError> {
+        match self {
+            ModelFile::Path(path) => {
+                let file = File::open(&path).map_err(|e| PhiError::io_error(&path, e))?;
+                Ok(Box::new(file))
+            }
+            ModelFile::Bytes(data) => Ok(Box::new(Cursor::new(data))),
+            ModelFile::Stream(stream) => Ok(Box::new(foreign_reader(stream)?)),
+        }
+    }
+
+    // reads the file without consuming it, e.g. to verify it before it is loaded
+    fn reader(&self) -> Result<Box<dyn ReadSeek + '_>, PhiError> {
+        match self {
+            ModelFile::Path(path) => {
+                let file = File::open(path).map_err(|e| PhiError::io_error(path, e))?;
+                Ok(Box::new(std::io::BufReader::with_capacity(1 << 20, file)))
+            }
+            ModelFile::Bytes(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            ModelFile::Stream(stream) => Ok(Box::new(foreign_reader(stream.clone())?)),
+        }
+    }
+}
+
+// reads the tensors one at a time from the offsets in the header, so that besides the tensors
+// already loaded only a single one is held in memory, never the whole file
+fn load_safetensors_tensors(file_name: &str, file: ModelFile, device: &Device) -> Result<HashMap<String, Tensor>, PhiError> {
+    let mut reader = file.into_reader()?;
+    let (header_size, header) = read_safetensors_header(file_name, &mut reader)?;
+    let load_error = |e: String| PhiError::initialization_error(format!("Error loading model from {}: {}", file_name, e));
+
+    let mut tensors = HashMap::new();
+    for (name, info) in header.iter().filter(|(name, _)| *name != "__metadata__") {
+        let invalid = || load_error(format!("invalid header entry for tensor {}", name));
+        let dtype = match info["dtype"].as_str() {
+            Some("U8") => DType::U8,
+            Some("U32") => DType::U32,
+            Some("I64") => DType::I64,
+            Some("BF16") => DType::BF16,
+            Some("F16") => DType::F16,
+            Some("F32") => DType::F32,
+            Some("F64") => DType::F64,
+            _ => return Err(invalid()),
+        };
+        let shape = info["shape"]
+            .as_array()
+            .and_then(|dims| dims.iter().map(|dim| dim.as_u64().map(|dim| dim as usize)).collect::<Option<Vec<_>>>())
+            .ok_or_else(invalid)?;
+        let (start, end) = info["data_offsets"]
+            .as_array()
+            .and_then(|offsets| match offsets.as_slice() {
+                [start, end] => Some((start.as_u64()?, end.as_u64()?)),
+                _ => None,
+            })
+            .filter(|(start, end)| start <= end)
+            .ok_or_else(invalid)?;
+
+        let mut data = vec![0u8; (end - start) as usize];
+        reader
+            .seek(SeekFrom::Start(8 + header_size + start))
+            .and_then(|_| reader.read_exact(&mut data))
+            .map_err(|e| load_error(e.to_string()))?;
+        let tensor =
+            Tensor::from_raw_buffer(&data, dtype, &shape, device).map_err(|e| load_error(e.to_string()))?;
+        tensors.insert(name.clone(), tensor);
+    }
+    Ok(tensors)
+}
+
+fn foreign_reader(
+    stream: Arc<dyn PhiFileStream>,
+) -> Result<std::io::BufReader<ForeignFileReader>, PhiError> {
+    let length = stream.get_length()?;
+    let reader = ForeignFileReader {
+        stream,
+        position: 0,
+        length,
+    };
+    // GGUF headers are parsed with many tiny reads, which should not each cross the FFI boundary
+    Ok(std::io::BufReader::with_capacity(1 << 20, reader))
+}
+
+struct ForeignFileReader {
+    stream: Arc<dyn PhiFileStream>,
+    position: u64,
+    length: u64,
+}
+
+impl Read for ForeignFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let to_read = (buf.len() as u64).min(self.length - self.position);
+        let data = self
+            .stream
+            .read_at(self.position, to_read)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let read = data.len().min(buf.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ForeignFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
 trait FileProvider {
-    fn get(&self, file_path: &str) -> Result<std::path::PathBuf, PhiError>;
+    fn get(&self, file_path: &str) -> Result<ModelFile, PhiError>;
+
+    // used for model weights, which may be too large to be handed over in one piece
+    fn open(&self, file_path: &str) -> Result<ModelFile, PhiError> {
+        self.get(file_path)
+    }
 }
 
 struct ApiFileProvider {
     repo: hf_hub::api::sync::ApiRepo,
+    cache_repo: hf_hub::CacheRepo,
+    event_handler: Option<Arc<dyn PhiEventHandler>>,
 }
 
 impl FileProvider for ApiFileProvider {
-    fn get(&self, file_path: &str) -> Result<std::path::PathBuf, PhiError> {
-        self.repo.get(file_path).map_err(|e| PhiError::InitalizationError {
+    fn get(&self, file_path: &str) -> Result<ModelFile, PhiError> {
+        if let Some(path) = self.cache_repo.get(file_path) {
+            return Ok(ModelFile::Path(path));
+        }
+
+        let event_handler = match &self.event_handler {
+            Some(event_handler) => event_handler.clone(),
+            None => {
+                return self
+                    .repo
+                    .download(file_path)
+                    .map(ModelFile::Path)
+                    .map_err(hub_error)
+            }
+        };
+
+        let progress = DownloadProgress::new(event_handler.clone());
+        let path = self
+            .repo
+            .download_with_progress(file_path, progress)
+            .map_err(hub_error)?;
+        if let Err(e) = event_handler.on_download_completed(file_path.to_string()) {
+            debug!("Error in on_download_completed: {:?}", e);
+        }
+        Ok(ModelFile::Path(path))
+    }
+}
+
+fn hub_error(e: hf_hub::api::sync::ApiError) -> PhiError {
+    use hf_hub::api::sync::ApiError;
+    match e {
+        ApiError::TooManyRetries(e) => hub_error(*e),
+        ApiError::RequestError(e) => match *e {
+            ureq::Error::Status(status_code, response) => PhiError::NetworkError {
+                status_code: Some(status_code),
+                error_text: format!("{} responded with {}", response.get_url(), response.status_text()),
+            },
+            e => PhiError::NetworkError {
+                status_code: None,
+                error_text: e.to_string(),
+            },
+        },
+        ApiError::IoError(e) if e.kind() == std::io::ErrorKind::OutOfMemory => PhiError::OutOfMemory {
             error_text: e.to_string(),
-        })
+        },
+        e => PhiError::InitalizationError {
+            error_text: e.to_string(),
+        },
     }
 }
 
+// report at most every 1% of the file (and at least every 1MB of it), rather than on every chunk
+const DOWNLOAD_PROGRESS_MIN_STEP: u64 = 1024 * 1024;
+
+struct DownloadProgress {
+    event_handler: Arc<dyn PhiEventHandler>,
+    file_name: String,
+    total_bytes: u64,
+    downloaded_bytes: u64,
+    reported_bytes: u64,
+    started: bool,
+}
+
+impl DownloadProgress {
+    fn new(event_handler: Arc<dyn PhiEventHandler>) -> Self {
+        Self {
+            event_handler,
+            file_name: String::new(),
+            total_bytes: 0,
+            downloaded_bytes: 0,
+            reported_bytes: 0,
+            started: false,
+        }
+    }
+}
+
+impl hf_hub::api::Progress for DownloadProgress {
+    // called once more for every retry, followed by an update with the bytes already on disk
+    fn init(&mut self, size: usize, filename: &str) {
+        self.downloaded_bytes = 0;
+        self.reported_bytes = 0;
+        if self.started {
+            return;
+        }
+        self.started = true;
+        self.file_name = filename.to_string();
+        self.total_bytes = size as u64;
+        if let Err(e) = self
+            .event_handler
+            .on_download_started(self.file_name.clone(), self.total_bytes)
+        {
+            debug!("Error in on_download_started: {:?}", e);
+        }
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded_bytes += size as u64;
+        let step = (self.total_bytes / 100).max(DOWNLOAD_PROGRESS_MIN_STEP);
+        if self.downloaded_bytes - self.reported_bytes < step && self.downloaded_bytes < self.total_bytes {
+            return;
+        }
+        self.reported_bytes = self.downloaded_bytes;
+        if let Err(e) = self
+            .event_handler
+            .on_download_progress(self.file_name.clone(), self.downloaded_bytes)
+        {
+            debug!("Error in on_download_progress: {:?}", e);
+        }
+    }
+
+    fn finish(&mut self) {}
+}
+
+struct CacheFileProvider {
+    repo: hf_hub::CacheRepo,
+    repo_id: String,
+    revision: String,
+}
+
+impl FileProvider for CacheFileProvider {
+    fn get(&self, file_path: &str) -> Result<ModelFile, PhiError> {
+        match self.repo.get(file_path) {
+            Some(path) => Ok(ModelFile::Path(path)),
+            None => Err(PhiError::ModelNotCached {
+                missing_files: vec![missing_file_name(&self.repo_id, &self.revision, file_path)],
+            }),
+        }
+    }
+}
+
+// the files of a `PhiModelProvider::FileSystem` model are resolved relative to the directory of its index
+fn filesystem_file_provider(index_path: &str) -> Result<FilesystemFileProvider, PhiError> {
+    let index_path = std::path::PathBuf::from(index_path);
+    if !index_path.is_absolute() {
+        return Err(PhiError::InitalizationError {
+            error_text: format!("The index path must be absolute. Provided path: {:?}", index_path),
+        });
+    }
+
+    match index_path.parent() {
+        Some(parent) => Ok(FilesystemFileProvider::new(parent.to_path_buf())),
+        None => Err(PhiError::InitalizationError {
+            error_text: format!("The index path {:?} does not have a valid parent directory", index_path),
+        }),
+    }
+}
+
+/// A safetensors model on disk, resolved and verified but not loaded.
+pub(crate) struct SafetensorsModel {
+    pub name: String,
+    pub files: Vec<PathBuf>,
+    pub config: Phi3Config,
+    pub tokenizer_json: Vec<u8>,
+}
+
+pub(crate) fn resolve_safetensors_model(
+    index_path: &str,
+    config_path: &str,
+    model_sha256: &HashMap<String, String>,
+) -> Result<SafetensorsModel, PhiError> {
+    let fs_provider = filesystem_file_provider(index_path)?;
+    let files = load_safetensors(&fs_provider, index_path)?;
+    verify_model_files(&files, model_sha256, false)?;
+    let config = load_config(&fs_provider, config_path)?;
+    let tokenizer_json = fs_provider.get("tokenizer.json")?.read_bytes()?;
+    let name = fs_provider
+        .base_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let files = files
+        .into_iter()
+        .filter_map(|(_, file)| match file {
+            ModelFile::Path(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    Ok(SafetensorsModel {
+        name,
+        files,
+        config,
+        tokenizer_json,
+    })
+}
+
 struct FilesystemFileProvider {
     base_dir: std::path::PathBuf,
 }
@@ -786,7 +2281,7 @@ impl FilesystemFileProvider {
     }
 }
 impl FileProvider for FilesystemFileProvider {
-    fn get(&self, file_path: &str) -> Result<std::path::PathBuf, PhiError> {
+    fn get(&self, file_path: &str) -> Result<ModelFile, PhiError> {
         let path = std::path::PathBuf::from(file_path);
         let full_path = if path.is_absolute() {
             path
@@ -795,11 +2290,353 @@ impl FileProvider for FilesystemFileProvider {
         };
         
         if full_path.exists() {
-            Ok(full_path)
+            Ok(ModelFile::Path(full_path))
         } else {
-            Err(PhiError::InitalizationError {
-                error_text: format!("File not found: {}", full_path.display()),
+            Err(PhiError::FileNotFound {
+                path: full_path.display().to_string(),
+            })
+        }
+    }
+}
+
+struct ForeignFileProvider {
+    provider: Arc<dyn PhiFileProvider>,
+}
+
+impl ForeignFileProvider {
+    fn new(provider: Option<Arc<dyn PhiFileProvider>>) -> Result<Self, PhiError> {
+        match provider {
+            Some(provider) => Ok(ForeignFileProvider { provider }),
+            None => Err(PhiError::InitalizationError {
+                error_text: "A file provider must be set with with_file_provider to load custom files".to_string(),
+            }),
+        }
+    }
+}
+
+impl FileProvider for ForeignFileProvider {
+    fn get(&self, file_path: &str) -> Result<ModelFile, PhiError> {
+        self.provider
+            .read_file(file_path.to_string())
+            .map(ModelFile::Bytes)
+    }
+
+    fn open(&self, file_path: &str) -> Result<ModelFile, PhiError> {
+        self.provider
+            .open_file(file_path.to_string())
+            .map(ModelFile::Stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file supplied by the host, remembering the largest read
+    struct RecordingStream {
+        data: Vec<u8>,
+        largest_read: AtomicU64,
+    }
+
+    impl PhiFileStream for RecordingStream {
+        fn get_length(&self) -> Result<u64, PhiError> {
+            Ok(self.data.len() as u64)
+        }
+
+        fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>, PhiError> {
+            self.largest_read.fetch_max(length, Ordering::SeqCst);
+            let start = (offset as usize).min(self.data.len());
+            let end = (start + length as usize).min(self.data.len());
+            Ok(self.data[start..end].to_vec())
+        }
+    }
+
+    #[test]
+    fn streamed_safetensors_are_read_tensor_by_tensor() {
+        let device = Device::Cpu;
+        let tensors = HashMap::from([
+            ("small".to_string(), Tensor::arange(0f32, 6., &device).unwrap().reshape((2, 3)).unwrap()),
+            ("first".to_string(), Tensor::ones((1024, 1024), DType::F32, &device).unwrap()),
+            ("second".to_string(), Tensor::zeros((1024, 1024), DType::F16, &device).unwrap()),
+        ]);
+        let path = std::env::temp_dir().join(format!("phi-engine-test-{}.safetensors", std::process::id()));
+        candle_core::safetensors::save(&tensors, &path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let file_length = data.len() as u64;
+        let stream = Arc::new(RecordingStream {
+            data,
+            largest_read: AtomicU64::new(0),
+        });
+
+        let loaded = load_safetensors_tensors("model.safetensors", ModelFile::Stream(stream.clone()), &device).unwrap();
+        assert_eq!(loaded.len(), tensors.len());
+        for (name, tensor) in &tensors {
+            let loaded = &loaded[name];
+            assert_eq!(loaded.dtype(), tensor.dtype());
+            assert_eq!(loaded.dims(), tensor.dims());
+            let difference = (loaded.to_dtype(DType::F32).unwrap() - tensor.to_dtype(DType::F32).unwrap())
+                .unwrap()
+                .abs()
+                .unwrap()
+                .sum_all()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert_eq!(difference, 0., "tensor {}", name);
+        }
+        // the largest tensor, never the whole file
+        let largest_read = stream.largest_read.load(Ordering::SeqCst);
+        assert!(largest_read <= 1024 * 1024 * 4, "read {} of {} bytes at once", largest_read, file_length);
+    }
+
+    // a safetensors model written to `dir`, sized for the vocabulary of the test tokenizer
+    fn build_test_engine(dir: &Path, weights: &HashMap<String, Tensor>, adapters: &[(&str, PathBuf)]) -> Arc<PhiEngine> {
+        let mut config = crate::test_util::phi3_config_json();
+        config["vocab_size"] = weights["model.embed_tokens.weight"].dim(0).unwrap().into();
+        crate::test_util::write_phi3_model(dir, &config, weights);
+        let builder = PhiEngineBuilder::new();
+        builder
+            .with_model_provider(PhiModelProvider::FileSystem {
+                index_path: dir.join("model.safetensors.index.json").display().to_string(),
+                config_path: dir.join("config.json").display().to_string(),
+                model_sha256: HashMap::new(),
+            })
+            .unwrap();
+        builder
+            .with_tokenizer_provider(TokenizerProvider::FileSystem {
+                tokenizer_path: dir.join("tokenizer.json").display().to_string(),
+                tokenizer_sha256: None,
             })
+            .unwrap();
+        for (name, adapter_dir) in adapters {
+            builder.with_lora_adapter(name.to_string(), adapter_dir.display().to_string()).unwrap();
         }
+        builder.build(dir.join("cache").display().to_string()).unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn requests_switch_between_lora_adapters() {
+        let device = Device::Cpu;
+        let mut config = crate::test_util::phi3_config();
+        config.vocab_size = crate::test_util::tokenizer().get_vocab_size(true);
+        let mut weights = crate::test_util::phi3_weights(&config, &device);
+        // all but the letters (ids 259 to 274) get logits of 0, which keeps greedy sampling off the
+        // special tokens and the byte fallback tokens, so that every request generates whole characters
+        // up to the token count
+        let letters = 259..275;
+        let lm_head = Tensor::cat(
+            &[
+                Tensor::zeros((letters.start, config.hidden_size), DType::F32, &device).unwrap(),
+                weights["lm_head.weight"].narrow(0, letters.start, letters.len()).unwrap(),
+                Tensor::zeros((config.vocab_size - letters.end, config.hidden_size), DType::F32, &device).unwrap(),
+            ],
+            0,
+        )
+        .unwrap();
+        weights.insert("lm_head.weight".to_string(), lm_head);
+
+        let dir = crate::test_util::temp_dir("lora-switching");
+        let adapter_config = serde_json::json!({ "r": 4, "lora_alpha": 8 });
+        let (first_dir, second_dir) = (dir.join("first"), dir.join("second"));
+        std::fs::create_dir_all(&first_dir).unwrap();
+        std::fs::create_dir_all(&second_dir).unwrap();
+        let first_modules = [("model.layers.0.self_attn.qkv_proj", 4), ("model.layers.1.mlp.down_proj", 4)];
+        let first = crate::test_util::write_lora_adapter(&first_dir, adapter_config.clone(), &weights, &first_modules);
+        let second_modules = [("model.layers.0.mlp.gate_up_proj", 4), ("model.layers.1.self_attn.o_proj", 4)];
+        let second = crate::test_util::write_lora_adapter(&second_dir, adapter_config, &weights, &second_modules);
+        let scales = |modules: &[(&'static str, usize)]| modules.iter().map(|(module, _)| (*module, 2.)).collect::<Vec<_>>();
+
+        let engine_dir = |name: &str| {
+            let engine_dir = dir.join(name);
+            std::fs::create_dir_all(&engine_dir).unwrap();
+            engine_dir
+        };
+        let engine = build_test_engine(
+            &engine_dir("engine"),
+            &weights,
+            &[("first", first_dir.clone()), ("second", second_dir.clone())],
+        );
+        // the same model with the updates merged into its weights, and without any
+        let first_merged = crate::test_util::merge_lora(&weights, &first, &scales(&first_modules));
+        let second_merged = crate::test_util::merge_lora(&weights, &second, &scales(&second_modules));
+        let references = [
+            (Some("first"), build_test_engine(&engine_dir("first-merged"), &first_merged, &[])),
+            (Some("second"), build_test_engine(&engine_dir("second-merged"), &second_merged, &[])),
+            (None, build_test_engine(&engine_dir("base"), &weights, &[])),
+        ];
+        let context = ConversationContext {
+            system_instruction: None,
+            messages: Vec::new(),
+        };
+        let prompt = "a a";
+        let expected = references
+            .iter()
+            .map(|(adapter, reference)| {
+                let options = crate::test_util::greedy_options(8);
+                let result = reference.run_inference(prompt, &context, &options, None).unwrap();
+                (*adapter, result.result_text)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_ne!(expected[&Some("first")], expected[&None]);
+        assert_ne!(expected[&Some("second")], expected[&None]);
+
+        for adapter in [Some("first"), None, Some("second"), Some("first"), Some("second"), None] {
+            let mut options = crate::test_util::greedy_options(8);
+            options.lora_adapter = adapter.map(|adapter| adapter.to_string());
+            let result = engine.run_inference(prompt, &context, &options, None).unwrap();
+            assert_eq!(result.result_text, expected[&adapter], "adapter {:?}", adapter);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // stands in for the Hugging Face Hub, serving `content` for every file of every repository.
+    // Until `healthy` is set, every download is cut off after `cut_after` bytes
+    struct HubStandIn {
+        endpoint: String,
+        // the first byte requested by every download
+        ranges: Arc<Mutex<Vec<u64>>>,
+        healthy: Arc<AtomicBool>,
+    }
+
+    const HUB_STAND_IN_COMMIT: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    impl HubStandIn {
+        fn start(content: Vec<u8>, cut_after: usize) -> Self {
+            use std::io::{BufRead, BufReader, Write};
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let ranges = Arc::new(Mutex::new(Vec::new()));
+            let healthy = Arc::new(AtomicBool::new(false));
+            let (server_ranges, server_healthy) = (ranges.clone(), healthy.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut range = None;
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("range") {
+                                range = Some(value.trim().to_string());
+                            }
+                        }
+                    }
+                    // hf-hub asks for the first byte to learn the size, etag and commit of the file
+                    let start = match range.as_deref() {
+                        Some("bytes=0-0") => None,
+                        Some(range) => Some(range["bytes=".len()..range.len() - 1].parse::<usize>().unwrap()),
+                        None => Some(0),
+                    };
+                    let (body, sent) = match start {
+                        None => (&content[..1], 1),
+                        Some(start) => {
+                            server_ranges.lock().unwrap().push(start as u64);
+                            let body = &content[start..];
+                            let sent = if server_healthy.load(Ordering::SeqCst) {
+                                body.len()
+                            } else {
+                                body.len().min(cut_after)
+                            };
+                            (body, sent)
+                        }
+                    };
+                    let first = content.len() - body.len();
+                    let head = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n\
+                         ETag: \"blob\"\r\nX-Repo-Commit: {}\r\nConnection: close\r\n\r\n",
+                        body.len(),
+                        first,
+                        first + body.len() - 1,
+                        content.len(),
+                        HUB_STAND_IN_COMMIT
+                    );
+                    // the client may give up on a cut off download before everything is written
+                    let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&body[..sent]));
+                }
+            });
+            Self {
+                endpoint,
+                ranges,
+                healthy,
+            }
+        }
+
+        fn ranges(&self) -> Vec<u64> {
+            self.ranges.lock().unwrap().clone()
+        }
+    }
+
+    // the reported download progress, which must never go backwards
+    fn download_progress(events: &[crate::test_util::Event]) -> Vec<u64> {
+        let progress = events
+            .iter()
+            .filter_map(|event| match event {
+                crate::test_util::Event::DownloadProgress(_, downloaded_bytes) => Some(*downloaded_bytes),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(progress.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", progress);
+        progress
+    }
+
+    #[test]
+    fn interrupted_downloads_resume_from_the_partial_file() {
+        use crate::test_util::{Event, RecordingEventHandler};
+        let content = (0..3 * 1024 * 1024 + 123).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let total_bytes = content.len() as u64;
+        let cut_after = 512 * 1024;
+        let hub = HubStandIn::start(content.clone(), cut_after);
+        let cache_dir = crate::test_util::temp_dir("download-resume");
+        let hub_options = HubOptions {
+            endpoint: Some(hub.endpoint.clone()),
+            token: None,
+            user_agent: None,
+        };
+        let provider = |event_handler: Arc<RecordingEventHandler>| {
+            let cache_dir = cache_dir.display().to_string();
+            hub_file_provider(&cache_dir, false, &hub_options, "org/model", "main", Some(event_handler)).unwrap()
+        };
+
+        // the download and all its retries are cut off, each continuing where the last one stopped
+        let interrupted = Arc::new(RecordingEventHandler::default());
+        assert!(provider(interrupted.clone()).get("model.gguf").is_err());
+        let part_path = cache_dir.join("models--org--model").join("blobs").join("blob.part");
+        let attempts = hub.ranges().len() as u64;
+        assert!(attempts > DOWNLOAD_RETRIES as u64);
+        let partial_bytes = std::fs::metadata(&part_path).unwrap().len();
+        assert_eq!(partial_bytes, attempts * cut_after as u64);
+        assert_eq!(hub.ranges(), (0..attempts).map(|attempt| attempt * cut_after as u64).collect::<Vec<_>>());
+        let events = interrupted.events();
+        assert_eq!(events[0], Event::DownloadStarted("model.gguf".to_string(), total_bytes));
+        assert!(download_progress(&events).iter().all(|bytes| *bytes <= partial_bytes));
+        assert!(!events.contains(&Event::DownloadCompleted("model.gguf".to_string())));
+
+        // the next download asks only for the rest of the file
+        hub.healthy.store(true, Ordering::SeqCst);
+        let resumed = Arc::new(RecordingEventHandler::default());
+        let path = match provider(resumed.clone()).get("model.gguf").unwrap() {
+            ModelFile::Path(path) => path,
+            _ => panic!("downloads are files in the cache"),
+        };
+        let downloaded = std::fs::read(&path).unwrap();
+        let part_exists = part_path.exists();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+
+        assert_eq!(hub.ranges().last(), Some(&partial_bytes));
+        assert!(downloaded == content, "the resumed file differs from the original");
+        assert!(!part_exists);
+        assert!(path.ends_with(format!("snapshots/{}/model.gguf", HUB_STAND_IN_COMMIT)));
+        let events = resumed.events();
+        assert_eq!(events.first(), Some(&Event::DownloadStarted("model.gguf".to_string(), total_bytes)));
+        assert_eq!(events.last(), Some(&Event::DownloadCompleted("model.gguf".to_string())));
+        let progress = download_progress(&events);
+        assert!(progress.len() > 1, "{:?}", progress);
+        assert!(progress[0] >= partial_bytes, "{:?}", progress);
+        assert_eq!(progress.last(), Some(&total_bytes));
+    }
+}
@@ -0,0 +1,437 @@
+use anyhow::{Error as E, Result};
+use candle_core::quantized::gguf_file;
+use std::collections::HashMap;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::{Vocab, BPE};
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, DecoderWrapper, NormalizerWrapper, Tokenizer};
+use tracing::debug;
+
+// token types as defined by llama.cpp
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_UNUSED: i32 = 5;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// Builds a tokenizer from the `tokenizer.ggml.*` metadata embedded in a GGUF file.
+///
+/// Supports SentencePiece style vocabularies (`llama`, used by Phi-3) and byte-level BPE
+/// vocabularies (`gpt2`, used by Phi-4).
+pub fn tokenizer_from_gguf(content: &gguf_file::Content) -> Result<Tokenizer> {
+    let md_get = |key: &str| match content.metadata.get(key) {
+        None => anyhow::bail!("cannot find {key} in metadata"),
+        Some(v) => Ok(v),
+    };
+
+    let model = md_get("tokenizer.ggml.model")?.to_string()?.clone();
+    let tokens = md_get("tokenizer.ggml.tokens")?
+        .to_vec()?
+        .iter()
+        .map(|v| v.to_string().cloned())
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    let token_types = match content.metadata.get("tokenizer.ggml.token_type") {
+        Some(v) => v
+            .to_vec()?
+            .iter()
+            .map(|v| v.to_i32())
+            .collect::<candle_core::Result<Vec<_>>>()?,
+        None => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+    };
+    let scores = match content.metadata.get("tokenizer.ggml.scores") {
+        Some(v) => Some(
+            v.to_vec()?
+                .iter()
+                .map(|v| v.to_f32())
+                .collect::<candle_core::Result<Vec<_>>>()?,
+        ),
+        None => None,
+    };
+    let merges = match content.metadata.get("tokenizer.ggml.merges") {
+        Some(v) => Some(
+            v.to_vec()?
+                .iter()
+                .map(|v| {
+                    let merge = v.to_string()?;
+                    merge
+                        .split_once(' ')
+                        .map(|(left, right)| (left.to_string(), right.to_string()))
+                        .ok_or_else(|| E::msg(format!("invalid merge: {merge}")))
+                })
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        None => None,
+    };
+    let token_id = |key: &str| -> Result<Option<u32>> {
+        match content.metadata.get(key) {
+            Some(v) => Ok(Some(v.to_u32()?)),
+            None => Ok(None),
+        }
+    };
+    let bos_token_id = token_id("tokenizer.ggml.bos_token_id")?;
+    let unk_token_id = token_id("tokenizer.ggml.unknown_token_id")?;
+    let add_bos_token = match content.metadata.get("tokenizer.ggml.add_bos_token") {
+        Some(v) => v.to_bool()?,
+        None => model == "llama",
+    };
+
+    if token_types.len() != tokens.len() {
+        anyhow::bail!(
+            "tokenizer.ggml.token_type has {} entries but there are {} tokens",
+            token_types.len(),
+            tokens.len()
+        );
+    }
+
+    let vocab: Vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect();
+    let token_name = |id: Option<u32>| id.and_then(|id| tokens.get(id as usize)).cloned();
+
+    let mut tokenizer = match model.as_str() {
+        "llama" => {
+            let merges = match merges {
+                Some(merges) => merges,
+                None => generate_merges(&tokens, &token_types, scores.as_deref()),
+            };
+            let mut bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .byte_fallback(true)
+                .fuse_unk(true);
+            if let Some(unk_token) = token_name(unk_token_id) {
+                bpe = bpe.unk_token(unk_token);
+            }
+            let mut tokenizer = Tokenizer::new(bpe.build().map_err(E::msg)?);
+            tokenizer.with_normalizer(Some(NormalizerSequence::new(vec![
+                NormalizerWrapper::Prepend(Prepend::new("▁".to_string())),
+                NormalizerWrapper::Replace(Replace::new(" ", "▁").map_err(E::msg)?),
+            ])));
+            tokenizer.with_decoder(Some(DecoderSequence::new(vec![
+                DecoderWrapper::Replace(Replace::new("▁", " ").map_err(E::msg)?),
+                DecoderWrapper::ByteFallback(ByteFallback::new()),
+                DecoderWrapper::Fuse(Fuse::new()),
+                DecoderWrapper::Strip(Strip::new(' ', 1, 0)),
+            ])));
+            tokenizer
+        }
+        "gpt2" => {
+            let merges = merges.ok_or_else(|| E::msg("cannot find tokenizer.ggml.merges in metadata"))?;
+            let bpe = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .build()
+                .map_err(E::msg)?;
+            let mut tokenizer = Tokenizer::new(bpe);
+            tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, true, true)));
+            tokenizer.with_decoder(Some(ByteLevel::default()));
+            tokenizer
+        }
+        other => anyhow::bail!("unsupported tokenizer model in GGUF metadata: {other}"),
+    };
+
+    // GGUF does not store which special tokens strip the whitespace after them. Like llama.cpp, the
+    // tokens of the Phi-3 chat template do, as they do in its tokenizer.json
+    let architecture = content
+        .metadata
+        .get("general.architecture")
+        .and_then(|v| v.to_string().ok());
+    let rstrip = model == "llama" && architecture.is_some_and(|architecture| architecture == "phi3");
+    let keeps_whitespace = |token: &String| {
+        token == "<|endoftext|>"
+            || Some(token) == token_name(bos_token_id).as_ref()
+            || Some(token) == token_name(unk_token_id).as_ref()
+    };
+    let special_tokens = tokens
+        .iter()
+        .zip(token_types.iter())
+        .filter(|(_, token_type)| {
+            **token_type == TOKEN_TYPE_CONTROL || **token_type == TOKEN_TYPE_USER_DEFINED
+        })
+        .map(|(token, _)| AddedToken::from(token.clone(), true).rstrip(rstrip && !keeps_whitespace(token)))
+        .collect::<Vec<_>>();
+    tokenizer.add_special_tokens(&special_tokens);
+
+    if add_bos_token {
+        if let (Some(bos_token), Some(bos_token_id)) = (token_name(bos_token_id), bos_token_id) {
+            let post_processor = TemplateProcessing::builder()
+                .try_single(format!("{bos_token}:0 $A:0"))
+                .map_err(E::msg)?
+                .try_pair(format!("{bos_token}:0 $A:0 {bos_token}:1 $B:1"))
+                .map_err(E::msg)?
+                .special_tokens(vec![(bos_token, bos_token_id)])
+                .build()
+                .map_err(E::msg)?;
+            tokenizer.with_post_processor(Some(post_processor));
+        }
+    }
+
+    debug!(
+        " --> Built {} tokenizer from GGUF metadata with {} tokens and {} special tokens",
+        model,
+        tokens.len(),
+        special_tokens.len()
+    );
+    Ok(tokenizer)
+}
+
+// SentencePiece vocabularies carry scores instead of merges. Every normal token that can be split
+// into two other tokens yields a merge, ranked by the score of the merged token (higher first).
+fn generate_merges(
+    tokens: &[String],
+    token_types: &[i32],
+    scores: Option<&[f32]>,
+) -> Vec<(String, String)> {
+    let vocab: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.as_str(), id))
+        .collect();
+
+    let mut merges = Vec::new();
+    for (id, token) in tokens.iter().enumerate() {
+        if token_types[id] != TOKEN_TYPE_NORMAL {
+            continue;
+        }
+        let score = scores
+            .and_then(|scores| scores.get(id).copied())
+            .unwrap_or(-(id as f32));
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((score, id, *left_id, *right_id, left, right));
+            }
+        }
+    }
+    merges.sort_by(|a, b| {
+        b.0.total_cmp(&a.0)
+            .then(a.1.cmp(&b.1))
+            .then(a.2.cmp(&b.2))
+            .then(a.3.cmp(&b.3))
+    });
+    merges
+        .into_iter()
+        .map(|(_, _, _, _, left, right)| (left.to_string(), right.to_string()))
+        .collect()
+}
+
+/// Converts a `tokenizer.json` BPE tokenizer into `tokenizer.ggml.*` metadata, the inverse of
+/// `tokenizer_from_gguf`, so that GGUF files written by the engine carry their own tokenizer.
+pub fn tokenizer_to_gguf_metadata(
+    tokenizer_json: &[u8],
+    bos_token_id: Option<u32>,
+    eos_token_id: Option<u32>,
+) -> Result<Vec<(String, gguf_file::Value)>> {
+    use gguf_file::Value;
+
+    let json: serde_json::Value = serde_json::from_slice(tokenizer_json)?;
+    let model = &json["model"];
+    if model["type"].as_str() != Some("BPE") {
+        anyhow::bail!("only BPE tokenizers can be embedded into GGUF files");
+    }
+    let vocab = model["vocab"]
+        .as_object()
+        .ok_or_else(|| E::msg("tokenizer.json has no vocab"))?;
+
+    // byte-level tokenizers (Phi-4) use the gpt2 pre-tokenizer, SentencePiece ones (Phi-3) fall back to bytes
+    let byte_level = json["pre_tokenizer"].to_string().contains("\"ByteLevel\"");
+    let ggml_model = match (byte_level, model["byte_fallback"].as_bool().unwrap_or(false)) {
+        (true, _) => "gpt2",
+        (false, true) => "llama",
+        (false, false) => anyhow::bail!("unsupported tokenizer, expected a byte-level or byte fallback BPE tokenizer"),
+    };
+
+    let mut tokens: Vec<Option<(String, i32)>> = Vec::new();
+    let mut set_token = |id: usize, token: &str, token_type: i32| {
+        if tokens.len() <= id {
+            tokens.resize(id + 1, None);
+        }
+        tokens[id] = Some((token.to_string(), token_type));
+    };
+    for (token, id) in vocab {
+        let id = id.as_u64().ok_or_else(|| E::msg(format!("invalid id for token {token}")))? as usize;
+        let is_byte = ggml_model == "llama" && token.len() == 6 && token.starts_with("<0x") && token.ends_with('>');
+        set_token(id, token, if is_byte { TOKEN_TYPE_BYTE } else { TOKEN_TYPE_NORMAL });
+    }
+    if let Some(added_tokens) = json["added_tokens"].as_array() {
+        for added_token in added_tokens {
+            let (Some(id), Some(content)) = (added_token["id"].as_u64(), added_token["content"].as_str()) else {
+                anyhow::bail!("invalid added token {added_token}");
+            };
+            let token_type = if added_token["special"].as_bool().unwrap_or(false) {
+                TOKEN_TYPE_CONTROL
+            } else {
+                TOKEN_TYPE_USER_DEFINED
+            };
+            set_token(id as usize, content, token_type);
+        }
+    }
+    // ids without a token still need an entry, as GGUF tokens are stored by position
+    let (tokens, token_types): (Vec<_>, Vec<_>) = tokens
+        .into_iter()
+        .enumerate()
+        .map(|(id, token)| token.unwrap_or_else(|| (format!("[PAD{id}]"), TOKEN_TYPE_UNUSED)))
+        .unzip();
+
+    let merges = model["merges"]
+        .as_array()
+        .ok_or_else(|| E::msg("tokenizer.json has no merges"))?
+        .iter()
+        .map(|merge| match merge {
+            serde_json::Value::String(merge) => Ok(merge.clone()),
+            // newer versions of tokenizers store merges as pairs
+            serde_json::Value::Array(pair) => match pair.as_slice() {
+                [serde_json::Value::String(left), serde_json::Value::String(right)] => Ok(format!("{left} {right}")),
+                _ => Err(E::msg(format!("invalid merge: {merge}"))),
+            },
+            _ => Err(E::msg(format!("invalid merge: {merge}"))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let token_id = |token: &str| tokens.iter().position(|t| t == token).map(|id| id as u32);
+    let unk_token_id = model["unk_token"].as_str().and_then(token_id);
+    // the post processor adds the bos token when its template starts with a special token
+    let add_bos_token = json["post_processor"]["type"].as_str() == Some("TemplateProcessing")
+        && json["post_processor"]["single"][0].get("SpecialToken").is_some();
+
+    let mut metadata = vec![
+        ("tokenizer.ggml.model".to_string(), Value::String(ggml_model.to_string())),
+        (
+            "tokenizer.ggml.tokens".to_string(),
+            Value::Array(tokens.into_iter().map(Value::String).collect()),
+        ),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(token_types.into_iter().map(Value::I32).collect()),
+        ),
+        (
+            "tokenizer.ggml.merges".to_string(),
+            Value::Array(merges.into_iter().map(Value::String).collect()),
+        ),
+        ("tokenizer.ggml.add_bos_token".to_string(), Value::Bool(add_bos_token)),
+    ];
+    for (key, id) in [
+        ("tokenizer.ggml.bos_token_id", bos_token_id),
+        ("tokenizer.ggml.eos_token_id", eos_token_id),
+        ("tokenizer.ggml.unknown_token_id", unk_token_id),
+    ] {
+        if let Some(id) = id {
+            metadata.push((key.to_string(), Value::U32(id)));
+        }
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::io::Cursor;
+
+    // writes the metadata into a GGUF file and reads it back, as loading a quantized model does
+    fn round_trip(tokenizer_json: &[u8]) -> Tokenizer {
+        let metadata = tokenizer_to_gguf_metadata(tokenizer_json, Some(1), Some(2)).unwrap();
+        read_back(metadata)
+    }
+
+    fn read_back(mut metadata: Vec<(String, gguf_file::Value)>) -> Tokenizer {
+        metadata.push(("general.architecture".to_string(), gguf_file::Value::String("phi3".to_string())));
+        let metadata = metadata.iter().map(|(key, value)| (key.as_str(), value)).collect::<Vec<_>>();
+        let mut file = Cursor::new(Vec::new());
+        gguf_file::write(&mut file, &metadata, &[]).unwrap();
+        file.set_position(0);
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        tokenizer_from_gguf(&content).unwrap()
+    }
+
+    fn assert_matches_the_original(rebuilt: &Tokenizer) {
+        let original = test_util::tokenizer();
+        assert_eq!(rebuilt.get_vocab_size(true), original.get_vocab_size(true));
+
+        let texts = [
+            "hello",
+            "hello ab",
+            "abc hello hello",
+            "<|user|>hello<|end|><|assistant|>",
+            "<|system|> a b<|end|>",
+            "<|user|>\nhello<|end|>\n<|assistant|>\n",
+            // not in the vocabulary, so encoded as bytes
+            "héllo ☃ 42",
+        ];
+        for text in texts {
+            let expected = original.encode(text, true).unwrap();
+            let actual = rebuilt.encode(text, true).unwrap();
+            assert_eq!(actual.get_ids(), expected.get_ids(), "encoding {:?}", text);
+            for skip_special_tokens in [false, true] {
+                assert_eq!(
+                    rebuilt.decode(actual.get_ids(), skip_special_tokens).unwrap(),
+                    original.decode(expected.get_ids(), skip_special_tokens).unwrap(),
+                    "decoding {:?}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gguf_tokenizer_matches_the_original() {
+        assert_matches_the_original(&round_trip(&test_util::tokenizer_json()));
+    }
+
+    // like the GGUF files of Phi-3, which have the scores of the SentencePiece model but no merges
+    #[test]
+    fn gguf_tokenizer_without_merges_matches_the_original() {
+        let tokenizer_json = test_util::tokenizer_json();
+        let mut metadata = tokenizer_to_gguf_metadata(&tokenizer_json, Some(1), Some(2)).unwrap();
+        metadata.retain(|(key, _)| key != "tokenizer.ggml.merges");
+
+        // the merged tokens score by the rank of their merge, the letters lower than all of them
+        let json: serde_json::Value = serde_json::from_slice(&tokenizer_json).unwrap();
+        let merged = json["model"]["merges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|merge| format!("{}{}", merge[0].as_str().unwrap(), merge[1].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        let Some((_, gguf_file::Value::Array(tokens))) = metadata.iter().find(|(key, _)| key == "tokenizer.ggml.tokens")
+        else {
+            unreachable!()
+        };
+        let scores = tokens
+            .iter()
+            .map(|token| match merged.iter().position(|merged| token.to_string().unwrap() == merged) {
+                Some(rank) => gguf_file::Value::F32(-(rank as f32)),
+                None => gguf_file::Value::F32(-1000.),
+            })
+            .collect();
+        metadata.push(("tokenizer.ggml.scores".to_string(), gguf_file::Value::Array(scores)));
+
+        assert_matches_the_original(&read_back(metadata));
+    }
+
+    #[test]
+    fn special_tokens_are_single_tokens() {
+        let rebuilt = round_trip(&test_util::tokenizer_json());
+        for token in test_util::SPECIAL_TOKENS {
+            let id = rebuilt.token_to_id(token).unwrap();
+            assert_eq!(rebuilt.encode(token, false).unwrap().get_ids(), &[id]);
+            assert_eq!(rebuilt.decode(&[id], false).unwrap(), token);
+            assert_eq!(rebuilt.decode(&[id], true).unwrap(), "");
+        }
+    }
+
+    #[test]
+    fn byte_fallback_decodes_to_the_original_text() {
+        let rebuilt = round_trip(&test_util::tokenizer_json());
+        let text = "héllo ☃";
+        let ids = rebuilt.encode(text, false).unwrap().get_ids().to_vec();
+        let byte_token = rebuilt.token_to_id("<0xC3>").unwrap();
+        assert!(ids.contains(&byte_token));
+        assert_eq!(rebuilt.decode(&ids, true).unwrap(), text);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::PhiError;
+use candle_core::quantized::gguf_file;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+// same limit as the safetensors crate, anything larger is not a real header
+const SAFETENSORS_MAX_HEADER_SIZE: u64 = 100_000_000;
+
+fn corrupt(file_name: &str, error_text: String) -> PhiError {
+    PhiError::CorruptModel {
+        file_name: file_name.to_string(),
+        error_text,
+    }
+}
+
+fn io_error(file_name: &str, e: std::io::Error) -> PhiError {
+    corrupt(file_name, format!("error reading file: {}", e))
+}
+
+/// Checks the SHA-256 hash of a file against the expected (hex encoded) hash.
+pub fn verify_sha256<R: Read>(file_name: &str, reader: &mut R, expected: &str) -> Result<(), PhiError> {
+    let mut hasher = Sha256::new();
+    std::io::copy(reader, &mut hasher).map_err(|e| io_error(file_name, e))?;
+    let actual = format!("{:x}", hasher.finalize());
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(corrupt(
+            file_name,
+            format!("SHA-256 mismatch, expected {} but the file has {}", expected.trim(), actual),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the GGUF magic and version, then that the data of every tensor listed in the
+/// header fits into the file, which catches truncated downloads.
+pub fn validate_gguf<R: Read + Seek>(file_name: &str, reader: &mut R) -> Result<(), PhiError> {
+    let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| io_error(file_name, e))?;
+    reader.seek(SeekFrom::Start(0)).map_err(|e| io_error(file_name, e))?;
+
+    let mut preamble = [0u8; 8];
+    reader
+        .read_exact(&mut preamble)
+        .map_err(|_| corrupt(file_name, format!("file is too small to be a GGUF model ({} bytes)", file_length)))?;
+    if &preamble[..4] != GGUF_MAGIC {
+        return Err(corrupt(file_name, "missing GGUF magic, this is not a GGUF file".to_string()));
+    }
+    let version = u32::from_le_bytes([preamble[4], preamble[5], preamble[6], preamble[7]]);
+    if !(1..=3).contains(&version) {
+        return Err(corrupt(file_name, format!("unsupported GGUF version {}", version)));
+    }
+
+    reader.seek(SeekFrom::Start(0)).map_err(|e| io_error(file_name, e))?;
+    let content = gguf_file::Content::read(reader)
+        .map_err(|e| corrupt(file_name, format!("invalid GGUF header: {}", e)))?;
+    let data_end = content
+        .tensor_infos
+        .values()
+        .map(|info| {
+            let size = info.shape.elem_count() / info.ggml_dtype.block_size() * info.ggml_dtype.type_size();
+            content.tensor_data_offset + info.offset + size as u64
+        })
+        .max()
+        .unwrap_or(content.tensor_data_offset);
+    if data_end > file_length {
+        return Err(corrupt(
+            file_name,
+            format!("file is truncated, expected at least {} bytes but it has {}", data_end, file_length),
+        ));
+    }
+
+    reader.seek(SeekFrom::Start(0)).map_err(|e| io_error(file_name, e))?;
+    Ok(())
+}
+
+/// Checks that the safetensors header is well formed and that the tensor data it describes
+/// matches the size of the file.
+pub fn validate_safetensors<R: Read + Seek>(file_name: &str, reader: &mut R) -> Result<(), PhiError> {
+    let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| io_error(file_name, e))?;
+    let (header_size, header) = read_safetensors_header(file_name, reader)?;
+
+    let mut data_end = 0;
+    for (tensor_name, info) in header.iter().filter(|(name, _)| *name != "__metadata__") {
+        let offsets = info
+            .get("data_offsets")
+            .and_then(|offsets| offsets.as_array())
+            .and_then(|offsets| match offsets.as_slice() {
+                [start, end] => Some((start.as_u64()?, end.as_u64()?)),
+                _ => None,
+            });
+        match offsets {
+            Some((start, end)) if start <= end => data_end = data_end.max(end),
+            _ => {
+                return Err(corrupt(
+                    file_name,
+                    format!("invalid data offsets for tensor {}", tensor_name),
+                ))
+            }
+        }
+    }
+    let data_length = file_length - 8 - header_size;
+    if data_end != data_length {
+        return Err(corrupt(
+            file_name,
+            format!(
+                "tensor data should be {} bytes but the file contains {}, it is likely truncated",
+                data_end, data_length
+            ),
+        ));
+    }
+
+    reader.seek(SeekFrom::Start(0)).map_err(|e| io_error(file_name, e))?;
+    Ok(())
+}
+
+/// Reads the JSON header of a safetensors file, returning its size in bytes and its entries
+/// (one per tensor, plus an optional `__metadata__` entry).
+pub fn read_safetensors_header<R: Read + Seek>(
+    file_name: &str,
+    reader: &mut R,
+) -> Result<(u64, serde_json::Map<String, serde_json::Value>), PhiError> {
+    let file_length = reader.seek(SeekFrom::End(0)).map_err(|e| io_error(file_name, e))?;
+    reader.seek(SeekFrom::Start(0)).map_err(|e| io_error(file_name, e))?;
+
+    let mut header_size = [0u8; 8];
+    reader
+        .read_exact(&mut header_size)
+        .map_err(|_| corrupt(file_name, format!("file is too small to be a safetensors file ({} bytes)", file_length)))?;
+    let header_size = u64::from_le_bytes(header_size);
+    if header_size > SAFETENSORS_MAX_HEADER_SIZE || header_size > file_length - 8 {
+        return Err(corrupt(
+            file_name,
+            format!("invalid safetensors header size {} for a file of {} bytes", header_size, file_length),
+        ));
+    }
+
+    let mut header = vec![0u8; header_size as usize];
+    reader.read_exact(&mut header).map_err(|e| io_error(file_name, e))?;
+    let header = serde_json::from_slice(&header)
+        .map_err(|e| corrupt(file_name, format!("invalid safetensors header: {}", e)))?;
+    Ok((header_size, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use candle_core::quantized::GgmlDType;
+    use candle_core::Device;
+    use std::io::Cursor;
+
+    fn assert_corrupt(result: Result<(), PhiError>, expected_file_name: &str, expected_text: &str) {
+        match result {
+            Err(PhiError::CorruptModel { file_name, error_text }) => {
+                assert_eq!(file_name, expected_file_name);
+                assert!(error_text.contains(expected_text), "{}", error_text);
+            }
+            other => panic!("expected CorruptModel, got {:?}", other),
+        }
+    }
+
+    fn gguf() -> Vec<u8> {
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &Device::Cpu);
+        test_util::phi3_gguf(&config, &weights, GgmlDType::Q8_0).into_inner()
+    }
+
+    // 32 f32 and 16 f16 values
+    const SAFETENSORS_DATA_LENGTH: u64 = 4 * 32 + 2 * 16;
+
+    // a safetensors file with two tensors, whose offsets are given
+    fn safetensors_with_offsets(first: [u64; 2], second: [u64; 2]) -> Vec<u8> {
+        let header = serde_json::json!({
+            "__metadata__": { "format": "pt" },
+            "first": { "dtype": "F32", "shape": [4, 8], "data_offsets": first },
+            "second": { "dtype": "F16", "shape": [16], "data_offsets": second },
+        });
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend(header);
+        data.extend(vec![0u8; SAFETENSORS_DATA_LENGTH as usize]);
+        data
+    }
+
+    fn safetensors() -> Vec<u8> {
+        safetensors_with_offsets([0, 128], [128, SAFETENSORS_DATA_LENGTH])
+    }
+
+    #[test]
+    fn sha256_matches_the_file() {
+        // the hash of "hello", in any case and with surrounding whitespace
+        let expected = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824\n";
+        assert!(verify_sha256("model.gguf", &mut Cursor::new(b"hello"), expected).is_ok());
+    }
+
+    #[test]
+    fn sha256_mismatch_names_the_file() {
+        let expected = "0000000000000000000000000000000000000000000000000000000000000000";
+        assert_corrupt(
+            verify_sha256("model.gguf", &mut Cursor::new(b"hello"), expected),
+            "model.gguf",
+            "SHA-256 mismatch, expected 0000000000000000000000000000000000000000000000000000000000000000 but the file has 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        );
+    }
+
+    #[test]
+    fn valid_gguf_is_accepted() {
+        let mut reader = Cursor::new(gguf());
+        validate_gguf("model.gguf", &mut reader).unwrap();
+        // the reader is rewound for loading
+        assert_eq!(reader.position(), 0);
+    }
+
+    #[test]
+    fn gguf_with_a_truncated_header_is_rejected() {
+        assert_corrupt(validate_gguf("model.gguf", &mut Cursor::new(b"GGU")), "model.gguf", "too small to be a GGUF model (3 bytes)");
+        let data = gguf();
+        assert_corrupt(validate_gguf("model.gguf", &mut Cursor::new(&data[..64])), "model.gguf", "invalid GGUF header");
+    }
+
+    #[test]
+    fn gguf_with_a_bad_magic_is_rejected() {
+        let mut data = gguf();
+        data[..4].copy_from_slice(b"GGML");
+        assert_corrupt(validate_gguf("model.gguf", &mut Cursor::new(data)), "model.gguf", "missing GGUF magic");
+        let mut data = gguf();
+        data[4..8].copy_from_slice(&7u32.to_le_bytes());
+        assert_corrupt(validate_gguf("model.gguf", &mut Cursor::new(data)), "model.gguf", "unsupported GGUF version 7");
+    }
+
+    #[test]
+    fn gguf_with_truncated_tensor_data_is_rejected() {
+        let data = gguf();
+        let length = data.len() - 1;
+        assert_corrupt(
+            validate_gguf("model.gguf", &mut Cursor::new(&data[..length])),
+            "model.gguf",
+            &format!("file is truncated, expected at least {} bytes but it has {}", length + 1, length),
+        );
+    }
+
+    #[test]
+    fn valid_safetensors_are_accepted() {
+        let mut reader = Cursor::new(safetensors());
+        validate_safetensors("model.safetensors", &mut reader).unwrap();
+        assert_eq!(reader.position(), 0);
+    }
+
+    #[test]
+    fn safetensors_header_length_past_the_end_is_rejected() {
+        let mut data = safetensors();
+        let file_length = data.len();
+        data[..8].copy_from_slice(&(file_length as u64).to_le_bytes());
+        assert_corrupt(
+            validate_safetensors("model.safetensors", &mut Cursor::new(data)),
+            "model.safetensors",
+            &format!("invalid safetensors header size {} for a file of {} bytes", file_length, file_length),
+        );
+        assert_corrupt(
+            validate_safetensors("model.safetensors", &mut Cursor::new(b"\x10\0\0")),
+            "model.safetensors",
+            "too small to be a safetensors file (3 bytes)",
+        );
+    }
+
+    #[test]
+    fn safetensors_tensor_offsets_outside_the_file_are_rejected() {
+        let past_the_end = safetensors_with_offsets([0, 128], [128, SAFETENSORS_DATA_LENGTH + 32]);
+        assert_corrupt(
+            validate_safetensors("model.safetensors", &mut Cursor::new(past_the_end)),
+            "model.safetensors",
+            &format!(
+                "tensor data should be {} bytes but the file contains {}",
+                SAFETENSORS_DATA_LENGTH + 32,
+                SAFETENSORS_DATA_LENGTH
+            ),
+        );
+        let reversed = safetensors_with_offsets([128, 0], [128, SAFETENSORS_DATA_LENGTH]);
+        assert_corrupt(
+            validate_safetensors("model.safetensors", &mut Cursor::new(reversed)),
+            "model.safetensors",
+            "invalid data offsets for tensor first",
+        );
+        let data = safetensors();
+        assert_corrupt(
+            validate_safetensors("model.safetensors", &mut Cursor::new(&data[..data.len() - 1])),
+            "model.safetensors",
+            "it is likely truncated",
+        );
+    }
+}
@@ -1,30 +1,66 @@
-uniffi::include_scaffolding!("strathweb-phi-engine");
+// the scaffolding generated by uniffi has doc comments followed by empty lines
+#[allow(clippy::empty_line_after_doc_comments)]
+mod scaffolding {
+    use super::*;
+    uniffi::include_scaffolding!("strathweb-phi-engine");
+}
+// the generated code expects its tag type in the crate root
+use scaffolding::UniFfiTag;
 
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
+use crate::cache::delete_cached_model;
+use crate::cache::list_cached_models;
+use crate::cache::prune_model_cache;
+use crate::cache::CachedFile;
+use crate::cache::CachedModel;
+use crate::cache::CachedRevision;
+use crate::engine::BatchItem;
+use crate::engine::BatchItemResult;
 use crate::engine::ConversationContext;
 use crate::engine::ConversationMessage;
+use crate::engine::FinishReason;
 use crate::engine::InferenceOptions;
 use crate::engine::InferenceOptionsBuilder;
 use crate::engine::InferenceResult;
+use crate::engine::ModelDType;
+use crate::engine::ModelInfo;
 use crate::engine::PhiEngine;
 use crate::engine::PhiEngineBuilder;
 use crate::engine::PhiEventHandler;
+use crate::engine::PhiFileProvider;
+use crate::engine::PhiFileStream;
 use crate::engine::PhiModelProvider;
 use crate::engine::Role;
 use crate::engine::StatefulPhiEngine;
 use crate::engine::TokenizerProvider;
 use crate::engine::ChatFormat;
+use crate::quantize::quantize_model;
+use crate::quantize::QuantizationType;
+use crate::stream::InferenceStream;
+use crate::stream::TokenChunk;
 
 use once_cell::sync::Lazy;
 use thiserror::Error;
 use tracing::Level;
 use tracing_subscriber::{filter::FilterFn, prelude::*};
 
+pub mod cache;
+pub mod causal_lm;
 pub mod engine;
+pub mod gguf_tokenizer;
+pub mod integrity;
+pub mod lora;
+pub mod memory;
+pub mod quantize;
+mod scheduler;
+pub mod stream;
 pub mod text_generator;
 pub mod token_stream;
+#[cfg(test)]
+mod test_util;
+mod worker;
 
 static TRACING_INITIALIZED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
 
@@ -49,7 +85,7 @@ pub fn enable_tracing() {
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum PhiError {
     #[error("LockingError with message: `{error_text}`")]
     LockingError { error_text: String },
@@ -62,6 +98,90 @@ pub enum PhiError {
 
     #[error("GPU is not supported on this architecture")]
     GpuNotSupported,
+
+    #[error("ModelNotCached, missing files: {}", .missing_files.join(", "))]
+    ModelNotCached { missing_files: Vec<String> },
+
+    #[error("CorruptModel, file `{file_name}`: {error_text}")]
+    CorruptModel { file_name: String, error_text: String },
+
+    #[error("FileNotFound: `{path}`")]
+    FileNotFound { path: String },
+
+    /// `status_code` is set when the server responded with an HTTP error, `None` when it could not be reached.
+    #[error("NetworkError{}: {error_text}", .status_code.map(|code| format!(" (HTTP {})", code)).unwrap_or_default())]
+    NetworkError { status_code: Option<u16>, error_text: String },
+
+    #[error("OutOfMemory with message: `{error_text}`")]
+    OutOfMemory { error_text: String },
+
+    #[error("UnsupportedArchitecture `{architecture}`, only phi3 models are supported")]
+    UnsupportedArchitecture { architecture: String },
+
+    #[error("ContextOverflow, the prompt is {prompt_tokens} tokens long, which exceeds the maximum context of {max_context} tokens")]
+    ContextOverflow { prompt_tokens: u64, max_context: u64 },
+
+    #[error("Inference was cancelled")]
+    Cancelled,
+
+    /// Raised by a foreign `PhiEventHandler` callback, rather than by the engine itself.
+    #[error("EventHandlerError in `{callback}`: {error_text}")]
+    EventHandlerError { callback: String, error_text: String },
+}
+
+impl PhiError {
+    /// An error while loading the model, classified as `OutOfMemory` where the message tells so.
+    pub(crate) fn initialization_error(error_text: String) -> Self {
+        if is_out_of_memory(&error_text) {
+            PhiError::OutOfMemory { error_text }
+        } else {
+            PhiError::InitalizationError { error_text }
+        }
+    }
+
+    /// Errors raised as `PhiError` (e.g. by the text generator) are kept, anything else is
+    /// an `InferenceError`, or `OutOfMemory` where the message tells so.
+    pub(crate) fn inference_error(e: anyhow::Error) -> Self {
+        match e.downcast::<PhiError>() {
+            Ok(e) => e,
+            Err(e) if is_out_of_memory(&e.to_string()) => PhiError::OutOfMemory {
+                error_text: e.to_string(),
+            },
+            Err(e) => PhiError::InferenceError {
+                error_text: e.to_string(),
+            },
+        }
+    }
+
+    pub(crate) fn io_error(path: &std::path::Path, e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => PhiError::FileNotFound {
+                path: path.display().to_string(),
+            },
+            std::io::ErrorKind::OutOfMemory => PhiError::OutOfMemory {
+                error_text: e.to_string(),
+            },
+            _ => PhiError::InitalizationError {
+                error_text: format!("Error reading {}: {}", path.display(), e),
+            },
+        }
+    }
+
+    pub(crate) fn event_handler_error(callback: &str, e: PhiError) -> Self {
+        PhiError::EventHandlerError {
+            callback: callback.to_string(),
+            error_text: e.to_string(),
+        }
+    }
+}
+
+// candle reports allocation failures as plain error messages, e.g. CUDA_ERROR_OUT_OF_MEMORY, or the
+// strerror of ENOMEM when memory mapping fails
+fn is_out_of_memory(error_text: &str) -> bool {
+    let error_text = error_text.to_lowercase();
+    ["out of memory", "out_of_memory", "cannot allocate memory", "failed to allocate"]
+        .iter()
+        .any(|pattern| error_text.contains(pattern))
 }
 
 // candle does not support Metal on iOS yet
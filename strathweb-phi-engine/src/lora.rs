@@ -0,0 +1,904 @@
+use crate::causal_lm::{BatchedCausalLm, CausalLm, KvCache};
+use crate::quantize::gguf_tensor_name;
+use anyhow::{Error as E, Result};
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Module, Shape, Tensor, D};
+use candle_nn::{Embedding, Linear, RmsNorm, VarBuilder};
+use candle_transformers::models::phi3::{Config as Phi3Config, RotaryEmbedding};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::debug;
+
+const ADAPTER_CONFIG_FILE: &str = "adapter_config.json";
+const ADAPTER_WEIGHTS_FILE: &str = "adapter_model.safetensors";
+// PEFT saves the weights of the wrapped model under this prefix
+const PEFT_PREFIX: &str = "base_model.model.";
+
+struct AdapterConfig {
+    r: usize,
+    lora_alpha: f64,
+    use_rslora: bool,
+    fan_in_fan_out: bool,
+    // per module overrides of lora_alpha, keyed by a suffix of the module name
+    alpha_pattern: HashMap<String, f64>,
+}
+
+impl AdapterConfig {
+    fn parse(json: &[u8]) -> Result<Self> {
+        let json: serde_json::Value = serde_json::from_slice(json)?;
+        let r = json["r"]
+            .as_u64()
+            .ok_or_else(|| E::msg(format!("{ADAPTER_CONFIG_FILE} has no rank (r)")))? as usize;
+        let lora_alpha = json["lora_alpha"]
+            .as_f64()
+            .ok_or_else(|| E::msg(format!("{ADAPTER_CONFIG_FILE} has no lora_alpha")))?;
+        let alpha_pattern = json["alpha_pattern"]
+            .as_object()
+            .map(|patterns| {
+                patterns
+                    .iter()
+                    .filter_map(|(pattern, alpha)| Some((pattern.clone(), alpha.as_f64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Self {
+            r,
+            lora_alpha,
+            use_rslora: json["use_rslora"].as_bool().unwrap_or(false),
+            fan_in_fan_out: json["fan_in_fan_out"].as_bool().unwrap_or(false),
+            alpha_pattern,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoraWeights {
+    a: Linear,
+    b: Linear,
+    scale: f64,
+}
+
+/// A LoRA adapter in the PEFT format: a folder with `adapter_config.json` and `adapter_model.safetensors`.
+pub struct LoraAdapter {
+    pub name: String,
+    // keyed by the path of the adapted module, e.g. `model.layers.0.self_attn.qkv_proj`
+    weights: HashMap<String, LoraWeights>,
+}
+
+impl LoraAdapter {
+    pub fn load(name: &str, adapter_dir: &Path, dtype: DType, device: &Device) -> Result<Self> {
+        let config = AdapterConfig::parse(&std::fs::read(adapter_dir.join(ADAPTER_CONFIG_FILE))?)?;
+        if config.fan_in_fan_out {
+            anyhow::bail!("adapter {name} uses fan_in_fan_out, which is not supported by Phi-3 models");
+        }
+
+        let tensors = candle_core::safetensors::load(adapter_dir.join(ADAPTER_WEIGHTS_FILE), device)?;
+        let mut pairs: HashMap<String, (Option<Tensor>, Option<Tensor>)> = HashMap::new();
+        for (tensor_name, tensor) in tensors {
+            let module_name = tensor_name.strip_prefix(PEFT_PREFIX).unwrap_or(&tensor_name);
+            let tensor = tensor.to_dtype(dtype)?;
+            if let Some(module) = module_name.strip_suffix(".lora_A.weight") {
+                pairs.entry(module.to_string()).or_default().0 = Some(tensor);
+            } else if let Some(module) = module_name.strip_suffix(".lora_B.weight") {
+                pairs.entry(module.to_string()).or_default().1 = Some(tensor);
+            } else {
+                // e.g. lora_embedding_A or modules_to_save, which would replace weights of the base model
+                anyhow::bail!("adapter {name} contains unsupported tensor {tensor_name}");
+            }
+        }
+
+        let mut weights = HashMap::new();
+        for (module, pair) in pairs {
+            let (Some(a), Some(b)) = pair else {
+                anyhow::bail!("adapter {name} is missing lora_A or lora_B for {module}");
+            };
+            // the rank can be overridden per module (rank_pattern), the tensors always have the actual one
+            let rank = a.dim(0)?;
+            let alpha = config
+                .alpha_pattern
+                .iter()
+                .find(|(pattern, _)| module.ends_with(pattern.as_str()))
+                .map(|(_, alpha)| *alpha)
+                .unwrap_or(config.lora_alpha);
+            let scale = if config.use_rslora {
+                alpha / (rank as f64).sqrt()
+            } else {
+                alpha / rank as f64
+            };
+            weights.insert(
+                module,
+                LoraWeights {
+                    a: Linear::new(a, None),
+                    b: Linear::new(b, None),
+                    scale,
+                },
+            );
+        }
+        debug!(
+            " --> Loaded LoRA adapter {} (rank {}) adapting {} modules",
+            name,
+            config.r,
+            weights.len()
+        );
+        Ok(Self {
+            name: name.to_string(),
+            weights,
+        })
+    }
+}
+
+// the weights of the base model, which come from safetensors files or from a GGUF file
+enum BaseWeights<'a> {
+    Safetensors(VarBuilder<'a>),
+    // keyed by the GGUF tensor name, taken out as the layers are built
+    Gguf(HashMap<String, QTensor>, Device),
+}
+
+impl BaseWeights<'_> {
+    // `name` is the name of the tensor in the safetensors files, e.g. `model.norm.weight`
+    fn tensor<S: Into<Shape>>(&mut self, name: &str, shape: S) -> Result<Tensor> {
+        match self {
+            BaseWeights::Safetensors(vb) => Ok(vb.get(shape, name)?),
+            BaseWeights::Gguf(tensors, device) => {
+                let qtensor = Self::take_gguf(tensors, name, shape.into())?;
+                Ok(qtensor.dequantize(device)?)
+            }
+        }
+    }
+
+    // the linear layers of GGUF models stay quantized, like in candle's quantized_phi3
+    fn linear(&mut self, module: &str, in_dim: usize, out_dim: usize) -> Result<BaseLinear> {
+        let name = format!("{module}.weight");
+        match self {
+            BaseWeights::Safetensors(vb) => Ok(BaseLinear::Dense(Linear::new(vb.get((out_dim, in_dim), &name)?, None))),
+            BaseWeights::Gguf(tensors, _) => {
+                let qtensor = Self::take_gguf(tensors, &name, (out_dim, in_dim).into())?;
+                Ok(BaseLinear::Quantized(QMatMul::from_qtensor(qtensor)?))
+            }
+        }
+    }
+
+    fn take_gguf(tensors: &mut HashMap<String, QTensor>, name: &str, shape: Shape) -> Result<QTensor> {
+        let gguf_name = gguf_tensor_name(name).ok_or_else(|| E::msg(format!("{name} has no GGUF equivalent")))?;
+        let qtensor = tensors
+            .remove(&gguf_name)
+            .ok_or_else(|| E::msg(format!("the GGUF file has no tensor {gguf_name}")))?;
+        if qtensor.shape() != &shape {
+            anyhow::bail!("{} is {:?} but the model expects {:?}", gguf_name, qtensor.shape(), shape);
+        }
+        Ok(qtensor)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum BaseLinear {
+    Dense(Linear),
+    Quantized(QMatMul),
+}
+
+impl Module for BaseLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            BaseLinear::Dense(linear) => linear.forward(xs),
+            BaseLinear::Quantized(linear) => linear.forward(xs),
+        }
+    }
+}
+
+// a linear layer of the base model, plus the low rank updates of every adapter targeting it
+#[derive(Debug, Clone)]
+struct LoraLinear {
+    base: BaseLinear,
+    adapters: HashMap<String, LoraWeights>,
+}
+
+impl LoraLinear {
+    fn new(
+        in_dim: usize,
+        out_dim: usize,
+        weights: &mut BaseWeights,
+        module: &str,
+        adapters: &[LoraAdapter],
+        used: &mut HashSet<(String, String)>,
+    ) -> Result<Self> {
+        let base = weights.linear(module, in_dim, out_dim)?;
+        Self::from_base(base, in_dim, out_dim, module, adapters, used)
+    }
+
+    fn from_base(
+        base: BaseLinear,
+        in_dim: usize,
+        out_dim: usize,
+        module: &str,
+        adapters: &[LoraAdapter],
+        used: &mut HashSet<(String, String)>,
+    ) -> Result<Self> {
+        let mut layer_adapters = HashMap::new();
+        for adapter in adapters {
+            if let Some(weights) = adapter.weights.get(module) {
+                let (_, a_in) = weights.a.weight().dims2()?;
+                let (b_out, _) = weights.b.weight().dims2()?;
+                if a_in != in_dim || b_out != out_dim {
+                    anyhow::bail!(
+                        "adapter {} does not match the model: {} is ({}, {}) but the adapter expects ({}, {})",
+                        adapter.name,
+                        module,
+                        out_dim,
+                        in_dim,
+                        b_out,
+                        a_in
+                    );
+                }
+                used.insert((adapter.name.clone(), module.to_string()));
+                layer_adapters.insert(adapter.name.clone(), weights.clone());
+            }
+        }
+        Ok(Self {
+            base,
+            adapters: layer_adapters,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, adapter: Option<&str>) -> Result<Tensor> {
+        let ys = self.base.forward(xs)?;
+        match adapter.and_then(|adapter| self.adapters.get(adapter)) {
+            Some(lora) => Ok((ys + (xs.apply(&lora.a)?.apply(&lora.b)? * lora.scale)?)?),
+            None => Ok(ys),
+        }
+    }
+}
+
+// the layers below follow candle_transformers::models::phi3, with the linear layers replaced by LoraLinear
+#[derive(Debug, Clone)]
+struct Attention {
+    qkv_proj: LoraLinear,
+    o_proj: LoraLinear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Phi3Config,
+        weights: &mut BaseWeights,
+        prefix: &str,
+        adapters: &[LoraAdapter],
+        used: &mut HashSet<(String, String)>,
+    ) -> Result<Self> {
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let head_dim = cfg.head_dim();
+        let op_size = num_heads * head_dim + 2 * num_kv_heads * head_dim;
+        let qkv_proj = LoraLinear::new(
+            cfg.hidden_size,
+            op_size,
+            weights,
+            &format!("{prefix}.qkv_proj"),
+            adapters,
+            used,
+        )?;
+        let o_proj = LoraLinear::new(
+            num_heads * head_dim,
+            cfg.hidden_size,
+            weights,
+            &format!("{prefix}.o_proj"),
+            adapters,
+            used,
+        )?;
+        Ok(Self {
+            qkv_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups: num_heads / num_kv_heads,
+            head_dim,
+            rotary_emb,
+        })
+    }
+
+    // the projections run on the whole batch, while rotary embeddings and attention are applied
+    // per sequence, as every sequence has its own position and cache
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_masks: &[Option<Tensor>],
+        caches: &mut [&mut KvCache],
+        layer_idx: usize,
+        adapter: Option<&str>,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let qkv = self.qkv_proj.forward(xs, adapter)?;
+        let query_pos = self.num_heads * self.head_dim;
+        let kv_size = self.num_kv_heads * self.head_dim;
+        let query_states = qkv
+            .narrow(D::Minus1, 0, query_pos)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = qkv
+            .narrow(D::Minus1, query_pos, kv_size)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = qkv
+            .narrow(D::Minus1, query_pos + kv_size, kv_size)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let mut attn_outputs = Vec::with_capacity(b_sz);
+        for (idx, (cache, attention_mask)) in caches.iter_mut().zip(attention_masks).enumerate() {
+            let layer_cache = cache.layer(layer_idx);
+            let seqlen_offset = layer_cache.as_ref().map_or(0, |(prev_k, _)| prev_k.dim(2).unwrap_or(0));
+            let (query_states, key_states) = self.rotary_emb.apply_rotary_emb_qkv(
+                &query_states.narrow(0, idx, 1)?,
+                &key_states.narrow(0, idx, 1)?,
+                seqlen_offset,
+            )?;
+            let value_states = value_states.narrow(0, idx, 1)?;
+
+            let (key_states, value_states) = match layer_cache.take() {
+                None => (key_states, value_states),
+                Some((prev_k, prev_v)) => (
+                    Tensor::cat(&[&prev_k, &key_states], 2)?,
+                    Tensor::cat(&[&prev_v, &value_states], 2)?,
+                ),
+            };
+            *layer_cache = Some((key_states.clone(), value_states.clone()));
+
+            let key_states =
+                candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?.contiguous()?;
+            let value_states =
+                candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?.contiguous()?;
+
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_outputs.push(
+                attn_weights
+                    .matmul(&value_states)?
+                    .transpose(1, 2)?
+                    .reshape((1, q_len, ()))?,
+            );
+        }
+        let attn_output = Tensor::cat(&attn_outputs, 0)?;
+        self.o_proj.forward(&attn_output, adapter)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    gate_up_proj: LoraLinear,
+    down_proj: LoraLinear,
+    act_fn: candle_nn::Activation,
+    i_size: usize,
+}
+
+impl Mlp {
+    fn new(
+        cfg: &Phi3Config,
+        weights: &mut BaseWeights,
+        prefix: &str,
+        adapters: &[LoraAdapter],
+        used: &mut HashSet<(String, String)>,
+    ) -> Result<Self> {
+        let i_size = cfg.intermediate_size;
+        let gate_up_proj = LoraLinear::new(
+            cfg.hidden_size,
+            2 * i_size,
+            weights,
+            &format!("{prefix}.gate_up_proj"),
+            adapters,
+            used,
+        )?;
+        let down_proj = LoraLinear::new(
+            i_size,
+            cfg.hidden_size,
+            weights,
+            &format!("{prefix}.down_proj"),
+            adapters,
+            used,
+        )?;
+        Ok(Self {
+            gate_up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+            i_size,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, adapter: Option<&str>) -> Result<Tensor> {
+        let up_states = self.gate_up_proj.forward(xs, adapter)?;
+        let gate = up_states.narrow(D::Minus1, 0, self.i_size)?;
+        let up_states = up_states.narrow(D::Minus1, self.i_size, self.i_size)?;
+        let up_states = (up_states * gate.apply(&self.act_fn))?;
+        self.down_proj.forward(&up_states, adapter)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Phi3Config,
+        weights: &mut BaseWeights,
+        prefix: &str,
+        adapters: &[LoraAdapter],
+        used: &mut HashSet<(String, String)>,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, weights, &format!("{prefix}.self_attn"), adapters, used)?;
+        let mlp = Mlp::new(cfg, weights, &format!("{prefix}.mlp"), adapters, used)?;
+        let input_layernorm = RmsNorm::new(
+            weights.tensor(&format!("{prefix}.input_layernorm.weight"), cfg.hidden_size)?,
+            cfg.rms_norm_eps,
+        );
+        let post_attention_layernorm = RmsNorm::new(
+            weights.tensor(&format!("{prefix}.post_attention_layernorm.weight"), cfg.hidden_size)?,
+            cfg.rms_norm_eps,
+        );
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_masks: &[Option<Tensor>],
+        caches: &mut [&mut KvCache],
+        layer_idx: usize,
+        adapter: Option<&str>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self
+            .self_attn
+            .forward(&xs, attention_masks, caches, layer_idx, adapter)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = self.mlp.forward(&xs.apply(&self.post_attention_layernorm)?, adapter)?;
+        Ok((residual + xs)?)
+    }
+}
+
+/// A Phi-3 model which applies LoRA adapters at runtime, on top of the unmodified base weights.
+///
+/// All adapters are loaded with the model, and each forward pass uses the one selected with
+/// `set_lora_adapter` (or none). Clones share the base and adapter weights, so every request
+/// can use a different adapter.
+///
+/// The KV caches are held outside of the layers, which lets the model also run batches of
+/// sequences at different positions (`BatchedCausalLm`), with or without adapters. The base
+/// weights come from safetensors files (`new`) or a GGUF file (`from_gguf`).
+#[derive(Debug, Clone)]
+pub struct LoraPhi3Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: LoraLinear,
+    adapter_names: Vec<String>,
+    active_adapter: Option<String>,
+    // used by the single sequence `CausalLm` forward passes
+    kv_cache: KvCache,
+    vocab_size: usize,
+    max_context: usize,
+    device: Device,
+    dtype: DType,
+}
+
+impl LoraPhi3Model {
+    pub fn new(cfg: &Phi3Config, vb: VarBuilder, adapters: &[LoraAdapter]) -> Result<Self> {
+        let (dtype, device) = (vb.dtype(), vb.device().clone());
+        Self::load(cfg, BaseWeights::Safetensors(vb), adapters, dtype, &device)
+    }
+
+    /// Loads a GGUF model, with the configuration read from its metadata like candle's
+    /// quantized_phi3 does. The linear layers stay quantized, everything else is computed in f32.
+    pub fn from_gguf<R: Read + Seek>(
+        content: &gguf_file::Content,
+        reader: &mut R,
+        adapters: &[LoraAdapter],
+        device: &Device,
+    ) -> Result<Self> {
+        let tensors = content
+            .tensor_infos
+            .keys()
+            .map(|name| Ok((name.clone(), content.tensor(reader, name, device)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Self::from_gguf_tensors(content, tensors, adapters, device)
+    }
+
+    /// Loads a GGUF model from tensors which have already been loaded from the file of `content`,
+    /// for example backed by a memory mapping of it.
+    pub fn from_gguf_tensors(
+        content: &gguf_file::Content,
+        tensors: HashMap<String, QTensor>,
+        adapters: &[LoraAdapter],
+        device: &Device,
+    ) -> Result<Self> {
+        let metadata = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .ok_or_else(|| E::msg(format!("the GGUF file has no {key} metadata")))
+        };
+        let hidden_size = metadata("phi3.embedding_length")?.to_u32()? as usize;
+        let num_attention_heads = metadata("phi3.attention.head_count")?.to_u32()? as usize;
+        let rope_dim = metadata("phi3.rope.dimension_count")?.to_u32()? as usize;
+        let head_dim = hidden_size / num_attention_heads;
+        let vocab_size = content
+            .tensor_infos
+            .get("token_embd.weight")
+            .ok_or_else(|| E::msg("the GGUF file has no tensor token_embd.weight"))?
+            .shape
+            .dims()[0];
+        let cfg = Phi3Config {
+            vocab_size,
+            hidden_act: candle_nn::Activation::Silu,
+            hidden_size,
+            intermediate_size: metadata("phi3.feed_forward_length")?.to_u32()? as usize,
+            num_hidden_layers: metadata("phi3.block_count")?.to_u32()? as usize,
+            num_attention_heads,
+            num_key_value_heads: metadata("phi3.attention.head_count_kv")?.to_u32()? as usize,
+            rms_norm_eps: metadata("phi3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+            // quantized_phi3 ignores phi3.rope.freq_base and the rope scaling factors
+            rope_theta: 10_000.,
+            bos_token_id: None,
+            eos_token_id: None,
+            rope_scaling: None,
+            max_position_embeddings: metadata("phi3.context_length")?.to_u32()? as usize,
+            original_max_position_embeddings: None,
+            partial_rotary_factor: (rope_dim != head_dim).then(|| rope_dim as f64 / head_dim as f64),
+            // GGUF files always have the output weights
+            tie_word_embeddings: false,
+        };
+        Self::load(&cfg, BaseWeights::Gguf(tensors, device.clone()), adapters, DType::F32, device)
+    }
+
+    fn load(
+        cfg: &Phi3Config,
+        mut weights: BaseWeights,
+        adapters: &[LoraAdapter],
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut used = HashSet::new();
+        let embeddings = weights.tensor("model.embed_tokens.weight", (cfg.vocab_size, cfg.hidden_size))?;
+        let embed_tokens = Embedding::new(embeddings, cfg.hidden_size);
+        let rotary_emb = Arc::new(RotaryEmbedding::new(dtype, cfg, device)?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for layer_idx in 0..cfg.num_hidden_layers {
+            layers.push(DecoderLayer::new(
+                rotary_emb.clone(),
+                cfg,
+                &mut weights,
+                &format!("model.layers.{layer_idx}"),
+                adapters,
+                &mut used,
+            )?);
+        }
+        let norm = RmsNorm::new(weights.tensor("model.norm.weight", cfg.hidden_size)?, cfg.rms_norm_eps);
+        let lm_head = if cfg.tie_word_embeddings {
+            let base = BaseLinear::Dense(Linear::new(embed_tokens.embeddings().clone(), None));
+            LoraLinear::from_base(base, cfg.hidden_size, cfg.vocab_size, "lm_head", adapters, &mut used)?
+        } else {
+            LoraLinear::new(cfg.hidden_size, cfg.vocab_size, &mut weights, "lm_head", adapters, &mut used)?
+        };
+
+        // adapters targeting modules the model does not have were trained for a different architecture
+        for adapter in adapters {
+            if let Some(module) = adapter
+                .weights
+                .keys()
+                .find(|module| !used.contains(&(adapter.name.clone(), module.to_string())))
+            {
+                anyhow::bail!("adapter {} targets {}, which is not a linear layer of the model", adapter.name, module);
+            }
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            adapter_names: adapters.iter().map(|adapter| adapter.name.clone()).collect(),
+            active_adapter: None,
+            kv_cache: KvCache::default(),
+            vocab_size: cfg.vocab_size,
+            max_context: cfg.max_position_embeddings,
+            device: device.clone(),
+            dtype,
+        })
+    }
+
+    fn prepare_decoder_attention_mask(&self, tgt_len: usize, seqlen_offset: usize) -> Result<Tensor> {
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| (0..tgt_len).map(move |j| if i < j { f32::NEG_INFINITY } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        Ok(mask
+            .expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)?)
+    }
+}
+
+impl BatchedCausalLm for LoraPhi3Model {
+    fn forward_batch(&self, input: &[&[u32]], caches: &mut [&mut KvCache], adapter: Option<&str>) -> Result<Tensor> {
+        if input.is_empty() || input.len() != caches.len() || input.iter().any(|tokens| tokens.is_empty()) {
+            anyhow::bail!("a batch needs one cache per sequence, and sequences of at least one token");
+        }
+        let seq_len = input.iter().map(|tokens| tokens.len()).max().unwrap_or(1);
+        let cache_lens = caches.iter().map(|cache| cache.len()).collect::<Vec<_>>();
+        let attention_masks = cache_lens
+            .iter()
+            .map(|cache_len| match seq_len {
+                1 => Ok(None),
+                _ => self.prepare_decoder_attention_mask(seq_len, *cache_len).map(Some),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // shorter sequences are padded at the end, where the causal mask hides the padding from their tokens
+        let padded = input
+            .iter()
+            .flat_map(|tokens| tokens.iter().copied().chain(std::iter::repeat_n(0, seq_len - tokens.len())))
+            .collect::<Vec<_>>();
+        let padded = Tensor::new(padded, &self.device)?.reshape((input.len(), seq_len))?;
+        let mut xs = self.embed_tokens.forward(&padded)?;
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            xs = layer.forward(&xs, &attention_masks, caches, layer_idx, adapter)?;
+        }
+        for ((cache, tokens), cache_len) in caches.iter_mut().zip(input).zip(cache_lens) {
+            if tokens.len() < seq_len {
+                cache.truncate(cache_len + tokens.len())?;
+            }
+        }
+        let last_tokens = input
+            .iter()
+            .enumerate()
+            .map(|(idx, tokens)| xs.i((idx..idx + 1, tokens.len() - 1..tokens.len())))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let xs = Tensor::cat(&last_tokens, 0)?.apply(&self.norm)?;
+        let logits = self
+            .lm_head
+            .forward(&xs, adapter)?
+            .i((.., 0, ..))?
+            .to_dtype(DType::F32)?;
+        Ok(logits)
+    }
+
+    fn max_context(&self) -> usize {
+        self.max_context
+    }
+}
+
+impl CausalLm for LoraPhi3Model {
+    // the position is tracked by the cache, which `clear_kv_cache` resets for every new prompt
+    fn forward(&mut self, input: &[u32], _position: usize) -> Result<Tensor> {
+        let mut kv_cache = std::mem::take(&mut self.kv_cache);
+        let logits = self.forward_batch(&[input], &mut [&mut kv_cache], self.active_adapter.as_deref());
+        self.kv_cache = kv_cache;
+        Ok(logits?.squeeze(0)?)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = KvCache::default();
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn max_context(&self) -> usize {
+        self.max_context
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn set_lora_adapter(&mut self, adapter: Option<&str>) -> Result<()> {
+        if let Some(adapter) = adapter {
+            if !self.adapter_names.iter().any(|name| name == adapter) {
+                return Err(E::msg(format!(
+                    "unknown LoRA adapter {}, the engine was built with: {}",
+                    adapter,
+                    self.adapter_names.join(", ")
+                )));
+            }
+        }
+        self.active_adapter = adapter.map(|adapter| adapter.to_string());
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn CausalLm> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::causal_lm::{Phi3Model, QuantizedPhi3Model};
+    use crate::test_util;
+    use candle_core::quantized::GgmlDType;
+    use serde_json::json;
+
+    fn models() -> (Phi3Model, LoraPhi3Model) {
+        let device = Device::Cpu;
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &device);
+        let vb = VarBuilder::from_tensors(weights, DType::F32, &device);
+        let reference = candle_transformers::models::phi3::Model::new(&config, vb.clone()).unwrap();
+        let model = LoraPhi3Model::new(&config, vb, &[]).unwrap();
+        (Phi3Model::new(reference, &config, &device), model)
+    }
+
+    #[test]
+    fn logits_match_candle_phi3_without_adapters() {
+        let (mut reference, mut model) = models();
+        let prompt = [1, 5, 17, 42, 8, 63, 30];
+        let expected = reference.forward(&prompt, 0).unwrap();
+        let actual = model.forward(&prompt, 0).unwrap();
+        assert!(test_util::max_difference(&expected, &actual) < 1e-4);
+
+        // decoding with the cache filled by the prompt
+        for (position, token) in [(7, 12), (8, 3), (9, 50)] {
+            let expected = reference.forward(&[token], position).unwrap();
+            let actual = model.forward(&[token], position).unwrap();
+            assert!(test_util::max_difference(&expected, &actual) < 1e-4, "position {}", position);
+        }
+    }
+
+    #[test]
+    fn batched_logits_match_single_sequences() {
+        let (_, mut model) = models();
+        let prompts: [&[u32]; 3] = [&[1, 5, 17, 42], &[1, 9], &[1, 33, 2, 7, 60, 11]];
+        let expected = prompts
+            .iter()
+            .map(|prompt| {
+                model.clear_kv_cache();
+                model.forward(prompt, 0).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut caches = prompts.iter().map(|_| KvCache::default()).collect::<Vec<_>>();
+        let mut cache_refs = caches.iter_mut().collect::<Vec<_>>();
+        let logits = model.forward_batch(&prompts, &mut cache_refs, None).unwrap();
+        for (idx, expected) in expected.iter().enumerate() {
+            assert!(test_util::max_difference(expected, &logits.get(idx).unwrap()) < 1e-4, "sequence {}", idx);
+        }
+        // the padding of the shorter prompts is not left in their caches
+        let cache_lens = caches.iter().map(|cache| cache.len()).collect::<Vec<_>>();
+        assert_eq!(cache_lens, prompts.iter().map(|prompt| prompt.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn gguf_logits_match_candle_quantized_phi3() {
+        let device = Device::Cpu;
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &device);
+        let mut file = test_util::phi3_gguf(&config, &weights, GgmlDType::Q8_0);
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let mut model = LoraPhi3Model::from_gguf(&content, &mut file, &[], &device).unwrap();
+        let mut reference = QuantizedPhi3Model::from_gguf(false, content, &mut file, &device).unwrap();
+        assert_eq!(model.vocab_size(), config.vocab_size);
+        assert_eq!(CausalLm::max_context(&model), config.max_position_embeddings);
+
+        let prompt = [1, 5, 17, 42, 8, 63, 30];
+        let expected = reference.forward(&prompt, 0).unwrap();
+        let actual = model.forward(&prompt, 0).unwrap();
+        assert!(test_util::max_difference(&expected, &actual) < 1e-4);
+        for (position, token) in [(7, 12), (8, 3), (9, 50)] {
+            let expected = reference.forward(&[token], position).unwrap();
+            let actual = model.forward(&[token], position).unwrap();
+            assert!(test_util::max_difference(&expected, &actual) < 1e-4, "position {}", position);
+        }
+    }
+
+    #[test]
+    fn adapter_logits_match_merged_weights() {
+        let device = Device::Cpu;
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &device);
+        let dir = test_util::temp_dir("lora-merged");
+        let (plain_dir, rslora_dir) = (dir.join("plain"), dir.join("rslora"));
+        std::fs::create_dir_all(&plain_dir).unwrap();
+        std::fs::create_dir_all(&rslora_dir).unwrap();
+        // scaled by lora_alpha / r, with lora_alpha overridden for o_proj
+        let plain = test_util::write_lora_adapter(
+            &plain_dir,
+            json!({ "r": 4, "lora_alpha": 8, "alpha_pattern": { "self_attn.o_proj": 2 } }),
+            &weights,
+            &[
+                ("model.layers.0.self_attn.qkv_proj", 4),
+                ("model.layers.1.self_attn.o_proj", 4),
+                ("model.layers.0.mlp.down_proj", 4),
+                ("lm_head", 4),
+            ],
+        );
+        // scaled by lora_alpha / sqrt(r), where gate_up_proj has a rank of its own
+        let rslora = test_util::write_lora_adapter(
+            &rslora_dir,
+            json!({ "r": 4, "lora_alpha": 8, "use_rslora": true, "rank_pattern": { "mlp.gate_up_proj": 2 } }),
+            &weights,
+            &[("model.layers.1.self_attn.qkv_proj", 4), ("model.layers.0.mlp.gate_up_proj", 2)],
+        );
+        let adapters = [
+            LoraAdapter::load("plain", &plain_dir, DType::F32, &device).unwrap(),
+            LoraAdapter::load("rslora", &rslora_dir, DType::F32, &device).unwrap(),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+        let vb = VarBuilder::from_tensors(weights.clone(), DType::F32, &device);
+        let mut model = LoraPhi3Model::new(&config, vb, &adapters).unwrap();
+
+        let cases = [
+            (
+                Some("plain"),
+                test_util::merge_lora(
+                    &weights,
+                    &plain,
+                    &[
+                        ("model.layers.0.self_attn.qkv_proj", 2.),
+                        ("model.layers.1.self_attn.o_proj", 0.5),
+                        ("model.layers.0.mlp.down_proj", 2.),
+                        ("lm_head", 2.),
+                    ],
+                ),
+            ),
+            (
+                Some("rslora"),
+                test_util::merge_lora(
+                    &weights,
+                    &rslora,
+                    &[
+                        ("model.layers.1.self_attn.qkv_proj", 4.),
+                        ("model.layers.0.mlp.gate_up_proj", 8. / 2f64.sqrt()),
+                    ],
+                ),
+            ),
+            (None, weights.clone()),
+        ];
+        let prompt = [1, 5, 17, 42, 8, 63, 30];
+        let base_logits = models().0.forward(&prompt, 0).unwrap();
+        for (adapter, merged) in cases {
+            let vb = VarBuilder::from_tensors(merged, DType::F32, &device);
+            let reference = candle_transformers::models::phi3::Model::new(&config, vb).unwrap();
+            let mut reference = Phi3Model::new(reference, &config, &device);
+            model.clear_kv_cache();
+            model.set_lora_adapter(adapter).unwrap();
+
+            let expected = reference.forward(&prompt, 0).unwrap();
+            let actual = model.forward(&prompt, 0).unwrap();
+            assert!(test_util::max_difference(&expected, &actual) < 1e-4, "adapter {:?}", adapter);
+            if adapter.is_some() {
+                assert!(test_util::max_difference(&expected, &base_logits) > 1e-2, "adapter {:?}", adapter);
+            }
+            let expected = reference.forward(&[12], prompt.len()).unwrap();
+            let actual = model.forward(&[12], prompt.len()).unwrap();
+            assert!(test_util::max_difference(&expected, &actual) < 1e-4, "adapter {:?}", adapter);
+        }
+    }
+}
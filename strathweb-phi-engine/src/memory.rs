@@ -0,0 +1,237 @@
+use candle_core::quantized::gguf_file;
+use candle_core::quantized::k_quants::{
+    self, BlockQ2K, BlockQ3K, BlockQ4K, BlockQ4_0, BlockQ4_1, BlockQ5K, BlockQ5_0, BlockQ5_1, BlockQ6K, BlockQ8K,
+    BlockQ8_0, BlockQ8_1, GgmlType,
+};
+use candle_core::quantized::{GgmlDType, QStorage, QTensor, QuantizedType};
+use candle_core::{CpuStorage, Result};
+use half::{bf16, f16};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Peak resident set size of the current process in bytes, if the platform reports it.
+pub fn peak_memory_usage() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+        // Apple platforms report bytes, other unix systems kilobytes
+        if cfg!(any(target_os = "macos", target_os = "ios")) {
+            Some(max_rss)
+        } else {
+            Some(max_rss * 1024)
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+pub fn format_memory_usage(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Quantized blocks of a tensor which stay in the memory mapped GGUF file, instead of being
+/// copied into a buffer of their own like candle's GGUF loader does.
+struct MappedBlocks<T> {
+    mmap: Arc<Mmap>,
+    offset: usize,
+    block_count: usize,
+    block_type: PhantomData<T>,
+}
+
+impl<T: GgmlType> MappedBlocks<T> {
+    fn new(mmap: Arc<Mmap>, offset: usize, block_count: usize) -> Option<Self> {
+        let end = block_count
+            .checked_mul(std::mem::size_of::<T>())
+            .and_then(|size| size.checked_add(offset))?;
+        // the mapping is page aligned, and GGUF aligns the data of every tensor
+        let aligned = (mmap.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>());
+        (end <= mmap.len() && aligned).then_some(Self {
+            mmap,
+            offset,
+            block_count,
+            block_type: PhantomData,
+        })
+    }
+
+    fn blocks(&self) -> &[T] {
+        // the range and the alignment have been checked in new, and the blocks are plain data
+        unsafe {
+            std::slice::from_raw_parts(self.mmap.as_ptr().add(self.offset) as *const T, self.block_count)
+        }
+    }
+}
+
+impl<T: GgmlType + Send + Sync> QuantizedType for MappedBlocks<T> {
+    fn dtype(&self) -> GgmlDType {
+        T::DTYPE
+    }
+
+    fn matmul_t(&self, mkn: (usize, usize, usize), lhs: &[f32], dst: &mut [f32]) -> Result<()> {
+        k_quants::matmul(mkn, lhs, self.blocks(), dst)
+    }
+
+    fn matmul_t_f16(&self, mkn: (usize, usize, usize), lhs: &[f16], dst: &mut [f16]) -> Result<()> {
+        k_quants::matmul_f16(mkn, lhs, self.blocks(), dst)
+    }
+
+    fn dequantize(&self, elem_count: usize) -> Result<CpuStorage> {
+        let mut ys = vec![0.0f32; elem_count];
+        T::to_float(self.blocks(), &mut ys);
+        Ok(CpuStorage::F32(ys))
+    }
+
+    fn storage_size_in_bytes(&self) -> usize {
+        self.block_count * std::mem::size_of::<T>()
+    }
+
+    fn as_ptr(&self) -> *const u8 {
+        self.blocks().as_ptr() as *const u8
+    }
+
+    fn block_size(&self) -> usize {
+        T::BLCK_SIZE
+    }
+
+    fn from_float(&mut self, _xs: &[f32]) {
+        panic!("memory mapped tensors are read only")
+    }
+
+    fn from_float_imatrix(&mut self, _xs: &[f32], _imatrix_weights: &[f32], _n_per_row: usize) {
+        panic!("memory mapped tensors are read only")
+    }
+
+    fn size(&self) -> usize {
+        self.storage_size_in_bytes()
+    }
+}
+
+fn mapped_storage<T: GgmlType + Send + Sync + 'static>(
+    mmap: &Arc<Mmap>,
+    offset: usize,
+    block_count: usize,
+) -> Option<Box<dyn QuantizedType>> {
+    MappedBlocks::<T>::new(mmap.clone(), offset, block_count).map(|blocks| Box::new(blocks) as Box<dyn QuantizedType>)
+}
+
+/// Memory maps the GGUF file at `path` and returns its tensors, on the CPU, backed by the mapping.
+/// The weights are then paged in from the file as they are used, rather than all read into memory
+/// up front.
+pub(crate) fn mmaped_gguf_tensors(path: &Path, content: &gguf_file::Content) -> Result<HashMap<String, QTensor>> {
+    let file = File::open(path)?;
+    let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+    content
+        .tensor_infos
+        .iter()
+        .map(|(name, info)| {
+            let elem_count = info.shape.elem_count();
+            let block_size = info.ggml_dtype.block_size();
+            if !elem_count.is_multiple_of(block_size) {
+                candle_core::bail!("the elements of tensor {name} do not fill its {:?} blocks", info.ggml_dtype)
+            }
+            let block_count = elem_count / block_size;
+            let offset = content.tensor_data_offset as usize + info.offset as usize;
+            let storage = match info.ggml_dtype {
+                GgmlDType::F32 => mapped_storage::<f32>(&mmap, offset, block_count),
+                GgmlDType::F16 => mapped_storage::<f16>(&mmap, offset, block_count),
+                GgmlDType::BF16 => mapped_storage::<bf16>(&mmap, offset, block_count),
+                GgmlDType::Q4_0 => mapped_storage::<BlockQ4_0>(&mmap, offset, block_count),
+                GgmlDType::Q4_1 => mapped_storage::<BlockQ4_1>(&mmap, offset, block_count),
+                GgmlDType::Q5_0 => mapped_storage::<BlockQ5_0>(&mmap, offset, block_count),
+                GgmlDType::Q5_1 => mapped_storage::<BlockQ5_1>(&mmap, offset, block_count),
+                GgmlDType::Q8_0 => mapped_storage::<BlockQ8_0>(&mmap, offset, block_count),
+                GgmlDType::Q8_1 => mapped_storage::<BlockQ8_1>(&mmap, offset, block_count),
+                GgmlDType::Q2K => mapped_storage::<BlockQ2K>(&mmap, offset, block_count),
+                GgmlDType::Q3K => mapped_storage::<BlockQ3K>(&mmap, offset, block_count),
+                GgmlDType::Q4K => mapped_storage::<BlockQ4K>(&mmap, offset, block_count),
+                GgmlDType::Q5K => mapped_storage::<BlockQ5K>(&mmap, offset, block_count),
+                GgmlDType::Q6K => mapped_storage::<BlockQ6K>(&mmap, offset, block_count),
+                GgmlDType::Q8K => mapped_storage::<BlockQ8K>(&mmap, offset, block_count),
+            }
+            .ok_or_else(|| candle_core::Error::Msg(format!("tensor {name} lies outside of the GGUF file or is misaligned")))?;
+            Ok((name.clone(), QTensor::new(QStorage::Cpu(storage), info.shape.clone())?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::causal_lm::CausalLm;
+    use crate::lora::LoraPhi3Model;
+    use crate::test_util;
+    use candle_core::Device;
+
+    fn write_model(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("phi-engine-test-{}-{}.gguf", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn mmaped_gguf_tensors_are_used_from_the_mapping() {
+        let device = Device::Cpu;
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &device);
+        let mut file = test_util::phi3_gguf(&config, &weights, GgmlDType::Q8_0);
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let path = write_model("mapped", file.get_ref());
+        let tensors = mmaped_gguf_tensors(&path, &content).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(tensors.len(), content.tensor_infos.len());
+        let data_start = content.tensor_data_offset as usize;
+        let (first_name, first_info) = content.tensor_infos.iter().min_by_key(|(_, info)| info.offset).unwrap();
+        let first_address = tensors[first_name].data().unwrap().as_ptr() as usize;
+        for (name, info) in &content.tensor_infos {
+            let tensor = &tensors[name];
+            assert_eq!(tensor.dtype(), info.ggml_dtype);
+            assert_eq!(tensor.shape(), &info.shape);
+            let data = tensor.data().unwrap();
+            let start = data_start + info.offset as usize;
+            assert_eq!(&data[..], &file.get_ref()[start..start + data.len()], "tensor {}", name);
+            // every tensor points into the one mapping of the file, at its offset, rather than into a copy
+            assert_eq!(
+                data.as_ptr() as usize - first_address,
+                (info.offset - first_info.offset) as usize,
+                "tensor {}",
+                name
+            );
+        }
+
+        let mut mapped = LoraPhi3Model::from_gguf_tensors(&content, tensors, &[], &device).unwrap();
+        let mut loaded = LoraPhi3Model::from_gguf(&content, &mut file, &[], &device).unwrap();
+        let prompt = [1, 5, 17, 42, 8, 63, 30];
+        let expected = loaded.forward(&prompt, 0).unwrap();
+        let actual = mapped.forward(&prompt, 0).unwrap();
+        assert_eq!(test_util::max_difference(&expected, &actual), 0.);
+        let expected = loaded.forward(&[12], prompt.len()).unwrap();
+        let actual = mapped.forward(&[12], prompt.len()).unwrap();
+        assert_eq!(test_util::max_difference(&expected, &actual), 0.);
+    }
+
+    #[test]
+    fn mmaped_gguf_tensors_reject_a_truncated_file() {
+        let config = test_util::phi3_config();
+        let weights = test_util::phi3_weights(&config, &Device::Cpu);
+        let mut file = test_util::phi3_gguf(&config, &weights, GgmlDType::Q8_0);
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let data = file.into_inner();
+        let path = write_model("truncated", &data[..data.len() - 1]);
+        let error = mmaped_gguf_tensors(&path, &content).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("lies outside of the GGUF file"), "{}", error);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::engine::{resolve_safetensors_model, PhiModelProvider};
+use crate::gguf_tokenizer::tokenizer_to_gguf_metadata;
+use crate::PhiError;
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::phi3::Config as Phi3Config;
+use std::fs::File;
+use std::io::BufWriter;
+use tracing::debug;
+
+// bumped by ggml whenever the layout of the quantized blocks changes, candle writes the current one
+const GGUF_QUANTIZATION_VERSION: u32 = 2;
+
+/// The GGUF data types a model can be quantized to, named after their `GgmlDType` counterparts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum QuantizationType {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q2K,
+    Q3K,
+    Q4K,
+    Q5K,
+    Q6K,
+}
+
+impl QuantizationType {
+    pub fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            QuantizationType::F32 => GgmlDType::F32,
+            QuantizationType::F16 => GgmlDType::F16,
+            QuantizationType::Q4_0 => GgmlDType::Q4_0,
+            QuantizationType::Q4_1 => GgmlDType::Q4_1,
+            QuantizationType::Q5_0 => GgmlDType::Q5_0,
+            QuantizationType::Q5_1 => GgmlDType::Q5_1,
+            QuantizationType::Q8_0 => GgmlDType::Q8_0,
+            QuantizationType::Q2K => GgmlDType::Q2K,
+            QuantizationType::Q3K => GgmlDType::Q3K,
+            QuantizationType::Q4K => GgmlDType::Q4K,
+            QuantizationType::Q5K => GgmlDType::Q5K,
+            QuantizationType::Q6K => GgmlDType::Q6K,
+        }
+    }
+}
+
+impl std::str::FromStr for QuantizationType {
+    type Err = PhiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let quantization_type = match s.to_ascii_lowercase().replace('_', "").as_str() {
+            "f32" => QuantizationType::F32,
+            "f16" => QuantizationType::F16,
+            "q40" => QuantizationType::Q4_0,
+            "q41" => QuantizationType::Q4_1,
+            "q50" => QuantizationType::Q5_0,
+            "q51" => QuantizationType::Q5_1,
+            "q80" => QuantizationType::Q8_0,
+            "q2k" => QuantizationType::Q2K,
+            "q3k" => QuantizationType::Q3K,
+            "q4k" => QuantizationType::Q4K,
+            "q5k" => QuantizationType::Q5K,
+            "q6k" => QuantizationType::Q6K,
+            _ => {
+                return Err(PhiError::InitalizationError {
+                    error_text: format!("Unknown quantization type: {}", s),
+                })
+            }
+        };
+        Ok(quantization_type)
+    }
+}
+
+/// Quantizes a safetensors Phi-3 model (`PhiModelProvider::FileSystem`) and writes it, together with
+/// its tokenizer, to a GGUF file which can be loaded with `PhiModelProvider::FileSystemGguf`.
+///
+/// Weight matrices are quantized to `quantization_type`, norms are kept as f32. Tensors are loaded and
+/// quantized one at a time, so only the quantized model has to fit into memory, not the original one.
+pub fn quantize_model(
+    model_provider: PhiModelProvider,
+    output_path: String,
+    quantization_type: QuantizationType,
+) -> Result<(), PhiError> {
+    let (index_path, config_path, model_sha256) = match model_provider {
+        PhiModelProvider::FileSystem {
+            index_path,
+            config_path,
+            model_sha256,
+        } => (index_path, config_path, model_sha256),
+        _ => {
+            return Err(PhiError::InitalizationError {
+                error_text: "Only PhiModelProvider::FileSystem models can be quantized".to_string(),
+            })
+        }
+    };
+    let model = resolve_safetensors_model(&index_path, &config_path, &model_sha256)?;
+    let config = &model.config;
+    let dtype = quantization_type.ggml_dtype();
+    debug!(" --> Quantizing {} to {:?}", model.name, dtype);
+
+    let safetensors = unsafe { MmapedSafetensors::multi(&model.files) }.map_err(quantization_error)?;
+    let mut tensors = Vec::new();
+    for (name, _) in safetensors.tensors() {
+        let Some(gguf_name) = gguf_tensor_name(&name) else {
+            debug!(" --> Skipping tensor {}, it is not used by the GGUF model", name);
+            continue;
+        };
+        let tensor = safetensors.load(&name, &Device::Cpu).map_err(quantization_error)?;
+        tensors.push((gguf_name, quantize_tensor(&tensor, dtype)?));
+    }
+    // models with tied embeddings have no lm_head, the GGUF model still needs its output weights
+    if !tensors.iter().any(|(name, _)| name == "output.weight") {
+        let embeddings = safetensors
+            .load("model.embed_tokens.weight", &Device::Cpu)
+            .map_err(quantization_error)?;
+        tensors.push(("output.weight".to_string(), quantize_tensor(&embeddings, dtype)?));
+    }
+    tensors.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut metadata = model_metadata(&model.name, config);
+    metadata.extend(
+        tokenizer_to_gguf_metadata(&model.tokenizer_json, config.bos_token_id, config.eos_token_id).map_err(|e| {
+            PhiError::InitalizationError {
+                error_text: format!("Error converting the tokenizer: {}", e),
+            }
+        })?,
+    );
+
+    // written next to the output first, so that an interrupted run does not leave a broken model behind
+    let part_path = format!("{}.part", output_path);
+    let write = || {
+        let mut writer = BufWriter::new(File::create(&part_path).map_err(io_error)?);
+        let metadata_refs = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect::<Vec<_>>();
+        let tensor_refs = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect::<Vec<_>>();
+        gguf_file::write(&mut writer, &metadata_refs, &tensor_refs).map_err(quantization_error)?;
+        writer.into_inner().map_err(|e| io_error(e.into_error()))?;
+        std::fs::rename(&part_path, &output_path).map_err(io_error)
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    debug!(" --> Wrote {} tensors to {}", tensors.len(), output_path);
+    Ok(())
+}
+
+// maps the Hugging Face tensor names to the ones `quantized_phi3` expects
+pub(crate) fn gguf_tensor_name(name: &str) -> Option<String> {
+    let gguf_name = match name {
+        "model.embed_tokens.weight" => "token_embd.weight".to_string(),
+        "model.norm.weight" => "output_norm.weight".to_string(),
+        "lm_head.weight" => "output.weight".to_string(),
+        _ => {
+            let rest = name.strip_prefix("model.layers.")?;
+            let (layer, rest) = rest.split_once('.')?;
+            let (tensor, suffix) = rest.rsplit_once('.')?;
+            let tensor = match tensor {
+                "self_attn.qkv_proj" => "attn_qkv",
+                "self_attn.o_proj" => "attn_output",
+                "mlp.gate_up_proj" => "ffn_up",
+                "mlp.down_proj" => "ffn_down",
+                "input_layernorm" => "attn_norm",
+                "post_attention_layernorm" => "ffn_norm",
+                _ => return None,
+            };
+            format!("blk.{}.{}.{}", layer, tensor, suffix)
+        }
+    };
+    Some(gguf_name)
+}
+
+pub(crate) fn quantize_tensor(tensor: &Tensor, dtype: GgmlDType) -> Result<QTensor, PhiError> {
+    let tensor = tensor.to_dtype(DType::F32).map_err(quantization_error)?;
+    // norms are tiny and sensitive to precision, and block quantization only works along rows
+    // whose length is a multiple of the block size
+    let dtype = match tensor.dims() {
+        [_] => GgmlDType::F32,
+        [.., columns] if columns % dtype.block_size() != 0 => {
+            debug!(
+                " --> Tensor of shape {:?} cannot be quantized to {:?}, using f16",
+                tensor.dims(),
+                dtype
+            );
+            GgmlDType::F16
+        }
+        _ => dtype,
+    };
+    QTensor::quantize(&tensor, dtype).map_err(quantization_error)
+}
+
+pub(crate) fn model_metadata(name: &str, config: &Phi3Config) -> Vec<(String, gguf_file::Value)> {
+    use gguf_file::Value;
+
+    // quantized_phi3 does not apply rope scaling, so long context models are limited to their original context
+    let context_length = match (&config.rope_scaling, config.original_max_position_embeddings) {
+        (Some(_), Some(original_max_position_embeddings)) => original_max_position_embeddings,
+        _ => config.max_position_embeddings,
+    };
+    let rope_dimension_count = (config.head_dim() as f64 * config.partial_rotary_factor.unwrap_or(1.0)) as u32;
+
+    vec![
+        ("general.architecture".to_string(), Value::String("phi3".to_string())),
+        ("general.name".to_string(), Value::String(name.to_string())),
+        (
+            "general.quantization_version".to_string(),
+            Value::U32(GGUF_QUANTIZATION_VERSION),
+        ),
+        ("phi3.context_length".to_string(), Value::U32(context_length as u32)),
+        ("phi3.embedding_length".to_string(), Value::U32(config.hidden_size as u32)),
+        (
+            "phi3.feed_forward_length".to_string(),
+            Value::U32(config.intermediate_size as u32),
+        ),
+        ("phi3.block_count".to_string(), Value::U32(config.num_hidden_layers as u32)),
+        (
+            "phi3.attention.head_count".to_string(),
+            Value::U32(config.num_attention_heads as u32),
+        ),
+        (
+            "phi3.attention.head_count_kv".to_string(),
+            Value::U32(config.num_key_value_heads as u32),
+        ),
+        (
+            "phi3.attention.layer_norm_rms_epsilon".to_string(),
+            Value::F32(config.rms_norm_eps as f32),
+        ),
+        ("phi3.rope.dimension_count".to_string(), Value::U32(rope_dimension_count)),
+        ("phi3.rope.freq_base".to_string(), Value::F32(config.rope_theta as f32)),
+    ]
+}
+
+fn quantization_error(e: candle_core::Error) -> PhiError {
+    PhiError::InitalizationError {
+        error_text: format!("Error quantizing the model: {}", e),
+    }
+}
+
+fn io_error(e: std::io::Error) -> PhiError {
+    PhiError::InitalizationError {
+        error_text: format!("Error writing the quantized model: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::causal_lm::{CausalLm, Phi3Model, QuantizedPhi3Model};
+    use crate::engine::{PhiEngineBuilder, TokenizerProvider};
+    use crate::test_util;
+    use candle_nn::VarBuilder;
+    use std::collections::HashMap;
+
+    // the norm of the difference of the logits, relative to the norm of the expected logits
+    fn relative_difference(expected: &Tensor, actual: &Tensor) -> f32 {
+        let norm = |tensor: &Tensor| tensor.sqr().unwrap().sum_all().unwrap().to_scalar::<f32>().unwrap().sqrt();
+        norm(&(expected - actual).unwrap()) / norm(expected)
+    }
+
+    #[test]
+    fn quantized_models_load_with_their_metadata_and_close_logits() {
+        let device = Device::Cpu;
+        let mut config_json = test_util::phi3_config_json();
+        // the tokenizer goes into the GGUF file, so the model has its vocabulary
+        config_json["vocab_size"] = test_util::tokenizer().get_vocab_size(true).into();
+        let config: Phi3Config = serde_json::from_value(config_json.clone()).unwrap();
+        // smaller weights keep the random model from amplifying the rounding of the quantization
+        let weights = test_util::phi3_weights(&config, &device)
+            .into_iter()
+            .map(|(name, tensor)| {
+                let tensor = if name.ends_with("norm.weight") { tensor } else { (tensor * 0.25).unwrap() };
+                (name, tensor)
+            })
+            .collect::<HashMap<_, _>>();
+        let dir = test_util::temp_dir("quantize");
+        let model_dir = dir.join("tiny-phi3");
+        std::fs::create_dir_all(&model_dir).unwrap();
+        test_util::write_phi3_model(&model_dir, &config_json, &weights);
+
+        let vb = VarBuilder::from_tensors(weights.clone(), DType::F32, &device);
+        let reference = candle_transformers::models::phi3::Model::new(&config, vb).unwrap();
+        let mut reference = Phi3Model::new(reference, &config, &device);
+        let prompt = [1, 5, 17, 42, 8, 63, 30, 270];
+        let expected = reference.forward(&prompt, 0).unwrap();
+
+        for (quantization_type, tolerance) in [(QuantizationType::Q8_0, 0.03), (QuantizationType::Q4_0, 0.4)] {
+            let output_path = dir.join(format!("{:?}.gguf", quantization_type)).display().to_string();
+            let model_provider = PhiModelProvider::FileSystem {
+                index_path: model_dir.join("model.safetensors.index.json").display().to_string(),
+                config_path: model_dir.join("config.json").display().to_string(),
+                model_sha256: HashMap::new(),
+            };
+            quantize_model(model_provider, output_path.clone(), quantization_type).unwrap();
+            assert!(!std::path::Path::new(&format!("{}.part", output_path)).exists());
+
+            let mut file = File::open(&output_path).unwrap();
+            let content = gguf_file::Content::read(&mut file).unwrap();
+            let metadata = |key: &str| content.metadata[key].clone();
+            assert_eq!(metadata("general.architecture").to_string().unwrap(), "phi3");
+            assert_eq!(metadata("general.name").to_string().unwrap(), "tiny-phi3");
+            assert_eq!(metadata("phi3.block_count").to_u32().unwrap(), 2);
+            assert_eq!(metadata("phi3.embedding_length").to_u32().unwrap(), 32);
+            assert_eq!(metadata("phi3.attention.head_count_kv").to_u32().unwrap(), 2);
+            assert_eq!(metadata("phi3.context_length").to_u32().unwrap(), 128);
+            assert_eq!(metadata("tokenizer.ggml.model").to_string().unwrap(), "llama");
+            assert_eq!(content.tensor_infos.len(), weights.len());
+            let dtype_of = |name: &str| content.tensor_infos[name].ggml_dtype;
+            assert_eq!(dtype_of("blk.0.attn_qkv.weight"), quantization_type.ggml_dtype());
+            assert_eq!(dtype_of("token_embd.weight"), quantization_type.ggml_dtype());
+            // rows of 48 values do not fill blocks of 32, norms stay f32
+            assert_eq!(dtype_of("blk.0.ffn_down.weight"), GgmlDType::F16);
+            assert_eq!(dtype_of("blk.0.attn_norm.weight"), GgmlDType::F32);
+
+            // the loader used by PhiModelProvider::FileSystemGguf
+            let mut model = QuantizedPhi3Model::from_gguf(false, content, &mut file, &device).unwrap();
+            let actual = model.forward(&prompt, 0).unwrap();
+            let difference = relative_difference(&expected, &actual);
+            assert!(difference < tolerance, "{:?} differs by {}", quantization_type, difference);
+
+            let builder = PhiEngineBuilder::new();
+            builder
+                .with_model_provider(PhiModelProvider::FileSystemGguf {
+                    model_path: output_path.clone(),
+                    model_sha256: None,
+                })
+                .unwrap();
+            builder.with_tokenizer_provider(TokenizerProvider::FromModel).unwrap();
+            let engine = builder.build(dir.join("cache").display().to_string()).unwrap();
+            let model_info = engine.get_model_info();
+            assert_eq!(model_info.architecture, "phi3");
+            assert_eq!(model_info.quantization, Some(format!("{:?}", quantization_type.ggml_dtype())));
+            assert_eq!(
+                model_info.parameter_count,
+                weights.values().map(|tensor| tensor.elem_count() as u64).sum::<u64>()
+            );
+            assert_eq!(model_info.vocab_size, config.vocab_size as u64);
+            assert_eq!(model_info.max_context, 128);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+use crate::causal_lm::{BatchedCausalLm, KvCache};
+use crate::engine::InferenceResult;
+use crate::text_generator::TextGenerator;
+use crate::PhiError;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::debug;
+
+// a request waiting for admission, or taking part in the batched forward passes
+struct Sequence {
+    generator: TextGenerator,
+    prompt: String,
+    sample_len: u16,
+    adapter: Option<String>,
+    cache: KvCache,
+    next_token: u32,
+    result: mpsc::Sender<Result<InferenceResult, PhiError>>,
+}
+
+impl Sequence {
+    fn new(
+        generator: TextGenerator,
+        prompt: String,
+        sample_len: u16,
+        adapter: Option<String>,
+        result: mpsc::Sender<Result<InferenceResult, PhiError>>,
+    ) -> Self {
+        Self {
+            generator,
+            prompt,
+            sample_len,
+            adapter,
+            cache: KvCache::default(),
+            next_token: 0,
+            result,
+        }
+    }
+
+    // keeps the sequence running while the generator asks for more tokens, completes it otherwise
+    fn advance(mut self, next_token: Result<Option<u32>>) -> Option<Self> {
+        match next_token {
+            Ok(Some(next_token)) => {
+                self.next_token = next_token;
+                Some(self)
+            }
+            Ok(None) => {
+                let result = self.generator.finish().map_err(PhiError::inference_error);
+                // the receiver is gone if the caller stopped waiting, there is nobody to tell
+                let _ = self.result.send(result);
+                None
+            }
+            Err(e) => {
+                self.fail(PhiError::inference_error(e));
+                None
+            }
+        }
+    }
+
+    fn fail(self, e: PhiError) {
+        let _ = self.result.send(Err(e));
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    waiting: VecDeque<Sequence>,
+    shutdown: bool,
+}
+
+struct SchedulerShared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+/// Runs the inferences of an engine with continuous batching: the decode steps of all running
+/// requests share a single forward pass of one model instance, and requests join and leave the
+/// batch between steps.
+///
+/// Requests are admitted in the order they arrive, as long as fewer than `max_batch_size` are
+/// running. While requests are generating, at most one is admitted per step, so that processing a
+/// long prompt does not hold them up for more than one step. An idle scheduler admits as many as
+/// fit, and processes their prompts together.
+pub(crate) struct Scheduler {
+    shared: Arc<SchedulerShared>,
+}
+
+impl Scheduler {
+    pub fn start(
+        model: Arc<dyn BatchedCausalLm>,
+        max_batch_size: usize,
+        pool: Option<Arc<rayon::ThreadPool>>,
+    ) -> Result<Self, PhiError> {
+        let shared = Arc::new(SchedulerShared {
+            queue: Mutex::new(Queue::default()),
+            available: Condvar::new(),
+        });
+        let scheduler_shared = shared.clone();
+        std::thread::Builder::new()
+            .name("phi-engine-scheduler".to_string())
+            .spawn(move || schedule(&scheduler_shared, model.as_ref(), max_batch_size, pool))
+            .map_err(|e| PhiError::InitalizationError {
+                error_text: format!("Error starting the scheduler thread: {}", e),
+            })?;
+        debug!(" --> Started the scheduler with a maximum batch size of {}", max_batch_size);
+        Ok(Self { shared })
+    }
+
+    /// Queues the request and blocks until it has completed.
+    pub fn run(
+        &self,
+        generator: TextGenerator,
+        prompt: String,
+        sample_len: u16,
+        adapter: Option<String>,
+    ) -> Result<InferenceResult, PhiError> {
+        let mut results = self.run_batch(vec![(generator, prompt)], sample_len, adapter);
+        results.pop().unwrap_or_else(|| Err(scheduler_stopped()))
+    }
+
+    /// Queues the requests together, so that they are admitted one after another, and blocks until
+    /// all of them have completed. Returns their results in order, a failing request does not
+    /// affect the others.
+    pub fn run_batch(
+        &self,
+        requests: Vec<(TextGenerator, String)>,
+        sample_len: u16,
+        adapter: Option<String>,
+    ) -> Vec<Result<InferenceResult, PhiError>> {
+        let (sequences, receivers): (Vec<_>, Vec<_>) = requests
+            .into_iter()
+            .map(|(generator, prompt)| {
+                let (sender, receiver) = mpsc::channel();
+                let sequence = Sequence::new(generator, prompt, sample_len, adapter.clone(), sender);
+                (sequence, receiver)
+            })
+            .unzip();
+        match self.shared.queue.lock() {
+            Ok(mut queue) => queue.waiting.extend(sequences),
+            Err(e) => {
+                let e = PhiError::LockingError {
+                    error_text: e.to_string(),
+                };
+                return receivers.iter().map(|_| Err(e.clone())).collect();
+            }
+        }
+        self.shared.available.notify_one();
+        receivers
+            .into_iter()
+            .map(|receiver| receiver.recv().unwrap_or_else(|_| Err(scheduler_stopped())))
+            .collect()
+    }
+}
+
+fn scheduler_stopped() -> PhiError {
+    PhiError::InferenceError {
+        error_text: "The scheduler stopped before the inference completed".to_string(),
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.shared.queue.lock() {
+            queue.shutdown = true;
+        }
+        self.shared.available.notify_one();
+    }
+}
+
+fn schedule(
+    shared: &SchedulerShared,
+    model: &dyn BatchedCausalLm,
+    max_batch_size: usize,
+    pool: Option<Arc<rayon::ThreadPool>>,
+) {
+    let mut running: Vec<Sequence> = Vec::new();
+    loop {
+        let admitted = {
+            let Ok(mut queue) = shared.queue.lock() else {
+                break;
+            };
+            while running.is_empty() && queue.waiting.is_empty() && !queue.shutdown {
+                queue = match shared.available.wait(queue) {
+                    Ok(queue) => queue,
+                    Err(_) => return,
+                };
+            }
+            if queue.shutdown {
+                break;
+            }
+            let free = max_batch_size.saturating_sub(running.len());
+            let admit = if running.is_empty() { free } else { free.min(1) };
+            let admit = admit.min(queue.waiting.len());
+            queue.waiting.drain(..admit).collect::<Vec<_>>()
+        };
+        for sequence in &admitted {
+            debug!(
+                " --> Admitting request {} to a batch of {}",
+                sequence.generator.request_id(),
+                running.len()
+            );
+        }
+
+        let step = || {
+            prefill(model, admitted, &mut running);
+            decode(model, &mut running);
+        };
+        // a panic fails the requests of the batch, by dropping their result senders, but not the scheduler
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match &pool {
+            Some(pool) => pool.install(step),
+            None => step(),
+        }));
+        if outcome.is_err() {
+            debug!(" --> The scheduler step panicked, failing {} requests", running.len());
+            running.clear();
+        }
+    }
+    debug!(" --> Scheduler stopped");
+}
+
+// processes the prompts of newly admitted requests together, and samples their first tokens
+fn prefill(model: &dyn BatchedCausalLm, sequences: Vec<Sequence>, running: &mut Vec<Sequence>) {
+    for (adapter, group) in adapter_groups(sequences) {
+        let mut started = Vec::with_capacity(group.len());
+        let mut input = Vec::with_capacity(group.len());
+        for mut sequence in group {
+            let tokens = sequence
+                .generator
+                .start(&sequence.prompt, sequence.sample_len, model.max_context())
+                .and_then(|tokens| sequence.generator.check_cancelled().map(|_| tokens));
+            match tokens {
+                Ok(tokens) => {
+                    input.push(tokens);
+                    started.push(sequence);
+                }
+                Err(e) => sequence.fail(PhiError::inference_error(e)),
+            }
+        }
+        forward(model, adapter.as_deref(), started, input, running);
+    }
+}
+
+// runs one decode step for all running requests
+fn decode(model: &dyn BatchedCausalLm, running: &mut Vec<Sequence>) {
+    for (adapter, group) in adapter_groups(std::mem::take(running)) {
+        // cancelled requests leave the batch before the forward pass
+        let group = group
+            .into_iter()
+            .filter_map(|sequence| match sequence.generator.check_cancelled() {
+                Ok(()) => Some(sequence),
+                Err(e) => {
+                    sequence.fail(PhiError::inference_error(e));
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let input = group.iter().map(|sequence| vec![sequence.next_token]).collect();
+        forward(model, adapter.as_deref(), group, input, running);
+    }
+}
+
+// every LoRA adapter in use gets its own forward pass
+fn adapter_groups(mut sequences: Vec<Sequence>) -> Vec<(Option<String>, Vec<Sequence>)> {
+    let mut groups = Vec::new();
+    while let Some(adapter) = sequences.first().map(|sequence| sequence.adapter.clone()) {
+        let (group, rest): (Vec<_>, Vec<_>) = sequences
+            .into_iter()
+            .partition(|sequence| sequence.adapter == adapter);
+        sequences = rest;
+        groups.push((adapter, group));
+    }
+    groups
+}
+
+// runs the input of every sequence in one forward pass, samples their next tokens and keeps the
+// sequences which have not completed in `running`
+fn forward(
+    model: &dyn BatchedCausalLm,
+    adapter: Option<&str>,
+    mut group: Vec<Sequence>,
+    input: Vec<Vec<u32>>,
+    running: &mut Vec<Sequence>,
+) {
+    if group.is_empty() {
+        return;
+    }
+    let input = input.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    let mut caches = group.iter_mut().map(|sequence| &mut sequence.cache).collect::<Vec<_>>();
+    match model.forward_batch(&input, &mut caches, adapter) {
+        Ok(logits) => {
+            for (idx, mut sequence) in group.into_iter().enumerate() {
+                let next_token = logits
+                    .get(idx)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|logits| sequence.generator.step(&logits));
+                if let Some(sequence) = sequence.advance(next_token) {
+                    running.push(sequence);
+                }
+            }
+        }
+        Err(e) => {
+            let e = PhiError::inference_error(e);
+            for sequence in group {
+                sequence.fail(e.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{TokenChunk, TokenChunkSink};
+    use crate::test_util;
+    use candle_core::Tensor;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // always predicts `a`, and records the sequences of every forward pass
+    struct MockModel {
+        max_context: usize,
+        batches: Mutex<Vec<Vec<Vec<u32>>>>,
+        // forward passes running at the same time
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+        // set once this many forward passes have run
+        cancel_after: Option<(usize, Arc<AtomicBool>)>,
+    }
+
+    impl MockModel {
+        fn new(max_context: usize) -> Self {
+            Self {
+                max_context,
+                batches: Mutex::new(Vec::new()),
+                running: AtomicUsize::new(0),
+                max_running: AtomicUsize::new(0),
+                cancel_after: None,
+            }
+        }
+
+        fn batches(&self) -> Vec<Vec<Vec<u32>>> {
+            self.batches.lock().unwrap().clone()
+        }
+    }
+
+    impl BatchedCausalLm for MockModel {
+        fn forward_batch(&self, input: &[&[u32]], _caches: &mut [&mut KvCache], _adapter: Option<&str>) -> Result<Tensor> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            let mut batches = self.batches.lock().unwrap();
+            batches.push(input.iter().map(|tokens| tokens.to_vec()).collect());
+            if let Some((after, cancelled)) = &self.cancel_after {
+                if batches.len() == *after {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+            }
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            let tokenizer = test_util::tokenizer();
+            let a = tokenizer.token_to_id("a").unwrap();
+            Ok(test_util::logits_for(a, input.len(), tokenizer.get_vocab_size(true)))
+        }
+
+        fn max_context(&self) -> usize {
+            self.max_context
+        }
+    }
+
+    struct FailingSink;
+
+    impl TokenChunkSink for FailingSink {
+        fn on_chunk(&self, _chunk: TokenChunk) -> Result<(), PhiError> {
+            Err(PhiError::InferenceError {
+                error_text: "the client went away".to_string(),
+            })
+        }
+    }
+
+    fn generator(request_id: &str) -> TextGenerator {
+        let options = test_util::greedy_options(0);
+        TextGenerator::new(test_util::tokenizer(), &options, request_id.to_string(), None)
+    }
+
+    fn requests(prompts: &[&str]) -> Vec<(TextGenerator, String)> {
+        prompts
+            .iter()
+            .enumerate()
+            .map(|(idx, prompt)| (generator(&format!("request-{}", idx)), prompt.to_string()))
+            .collect()
+    }
+
+    fn encode(prompt: &str) -> Vec<u32> {
+        test_util::tokenizer().encode(prompt, true).unwrap().get_ids().to_vec()
+    }
+
+    fn start(model: &Arc<MockModel>, max_batch_size: usize) -> Scheduler {
+        Scheduler::start(model.clone(), max_batch_size, None).unwrap()
+    }
+
+    #[test]
+    fn admits_requests_in_arrival_order() {
+        let model = Arc::new(MockModel::new(64));
+        let scheduler = start(&model, 2);
+        let prompts = ["hello", "ab", "hello ab", "ba", "cab"];
+        let results = scheduler.run_batch(requests(&prompts), 3, None);
+
+        for (idx, result) in results.into_iter().enumerate() {
+            let result = result.unwrap();
+            assert_eq!(result.request_id, format!("request-{}", idx));
+            assert_eq!(result.result_text, "aaa");
+        }
+        let batches = model.batches();
+        assert!(batches.iter().all(|batch| batch.len() <= 2));
+        // decode steps feed a single token per sequence, prefills the whole prompt
+        let prefilled = batches
+            .into_iter()
+            .flatten()
+            .filter(|tokens| tokens.len() > 1)
+            .collect::<Vec<_>>();
+        assert_eq!(prefilled, prompts.map(encode));
+    }
+
+    #[test]
+    fn concurrent_batches_share_the_scheduler() {
+        let model = Arc::new(MockModel::new(64));
+        let scheduler = start(&model, 3);
+        std::thread::scope(|scope| {
+            let single = (0..4)
+                .map(|idx| {
+                    let scheduler = &scheduler;
+                    scope.spawn(move || scheduler.run(generator(&format!("single-{}", idx)), "hello".to_string(), 4, None))
+                })
+                .collect::<Vec<_>>();
+            let batch = scheduler.run_batch(requests(&["ab", "ba", "cab", "hello ab"]), 4, None);
+            assert!(batch.into_iter().all(|result| result.is_ok()));
+            assert!(single.into_iter().all(|handle| handle.join().unwrap().is_ok()));
+        });
+        // a single model instance, never running more than `max_batch_size` sequences
+        assert_eq!(model.max_running.load(Ordering::SeqCst), 1);
+        assert!(model.batches().iter().all(|batch| batch.len() <= 3));
+    }
+
+    #[test]
+    fn cancelled_requests_leave_the_batch() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let model = Arc::new(MockModel {
+            cancel_after: Some((3, cancelled.clone())),
+            ..MockModel::new(64)
+        });
+        let scheduler = start(&model, 3);
+        let mut requests = requests(&["hello", "ab", "ba"]);
+        requests[1].0 = generator("request-1").with_cancellation(cancelled);
+        let results = scheduler.run_batch(requests, 8, None);
+
+        assert!(matches!(results[1], Err(PhiError::Cancelled)));
+        for idx in [0, 2] {
+            assert_eq!(results[idx].as_ref().unwrap().token_count, 8);
+        }
+        let batch_sizes = model.batches().iter().map(Vec::len).collect::<Vec<_>>();
+        assert_eq!(batch_sizes[..3], [3, 3, 3]);
+        assert!(batch_sizes[3..].iter().all(|size| *size == 2));
+    }
+
+    #[test]
+    fn failing_requests_do_not_affect_the_others() {
+        let model = Arc::new(MockModel::new(8));
+        let scheduler = start(&model, 4);
+        let mut requests = requests(&["hello", "hello hello hello hello hello hello hello hello", "ab", "ba"]);
+        requests[2].0 = generator("request-2").with_chunk_sink(Arc::new(FailingSink));
+        let results = scheduler.run_batch(requests, 4, None);
+
+        assert_eq!(results[0].as_ref().unwrap().result_text, "aaaa");
+        assert!(matches!(results[1], Err(PhiError::ContextOverflow { max_context: 8, .. })));
+        assert!(matches!(&results[2], Err(PhiError::InferenceError { error_text }) if error_text == "the client went away"));
+        assert_eq!(results[3].as_ref().unwrap().result_text, "aaaa");
+    }
+
+    #[test]
+    fn sequences_are_grouped_by_adapter_in_order() {
+        let adapters = [None, Some("sql"), None, Some("poems"), Some("sql")];
+        let sequences = adapters
+            .iter()
+            .enumerate()
+            .map(|(idx, adapter)| {
+                let (sender, _) = mpsc::channel();
+                let adapter = adapter.map(str::to_string);
+                Sequence::new(generator(&idx.to_string()), String::new(), 1, adapter, sender)
+            })
+            .collect();
+        let groups = adapter_groups(sequences)
+            .into_iter()
+            .map(|(adapter, group)| {
+                let request_ids = group.iter().map(|sequence| sequence.generator.request_id().to_string());
+                (adapter, request_ids.collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            [
+                (None, vec!["0".to_string(), "2".to_string()]),
+                (Some("sql".to_string()), vec!["1".to_string(), "4".to_string()]),
+                (Some("poems".to_string()), vec!["3".to_string()]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use crate::engine::InferenceResult;
+use crate::PhiError;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use tracing::debug;
+
+/// A piece of the generated text, as produced by the token stream.
+///
+/// A chunk usually holds a single token, but tokens which do not decode to valid text on their own
+/// (e.g. parts of a multi-byte character) are held back and delivered together with the next one.
+#[derive(Debug, Clone)]
+pub struct TokenChunk {
+    pub token_ids: Vec<u32>,
+    pub text: String,
+    /// The offset of `text`, in bytes, into the generated text (`InferenceResult::result_text`).
+    pub byte_offset: u64,
+    /// The sum of the log probabilities of the chunk's tokens, set when
+    /// `InferenceOptionsBuilder::with_logprobs` is enabled.
+    pub logprob: Option<f64>,
+    /// The position of the chunk in the stream, starting at 0.
+    pub index: u32,
+    /// Special tokens, such as the end-of-text token stopping the generation, have their own chunk
+    /// with an empty `text`.
+    pub is_special: bool,
+}
+
+/// Receives the chunks of an inference as they are generated.
+pub(crate) trait TokenChunkSink: Send + Sync {
+    fn on_chunk(&self, chunk: TokenChunk) -> Result<(), PhiError>;
+}
+
+#[derive(Default)]
+struct StreamState {
+    chunks: VecDeque<TokenChunk>,
+    // set once the inference has ended, after all its chunks were queued
+    outcome: Option<Result<InferenceResult, PhiError>>,
+    waker: Option<Waker>,
+}
+
+struct StreamShared {
+    state: Mutex<StreamState>,
+    available: Condvar,
+}
+
+impl StreamShared {
+    fn update(&self, update: impl FnOnce(&mut StreamState)) {
+        // the lock is only poisoned if a consumer panicked, in which case nobody is reading anymore
+        if let Ok(mut state) = self.state.lock() {
+            update(&mut state);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+        self.available.notify_all();
+    }
+}
+
+/// The chunks of a running inference, pulled by the caller one at a time.
+///
+/// `next` blocks until a chunk is available, while `next_async` suspends the calling task instead,
+/// which makes it a good fit for Swift `AsyncSequence`, Kotlin `Flow` or C# `IAsyncEnumerable`.
+/// Both return `None` once the inference has ended, after which `get_result` has the statistics of
+/// the whole run. Cancelling, or dropping the stream, stops the inference before the next token.
+pub struct InferenceStream {
+    shared: Arc<StreamShared>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl InferenceStream {
+    /// Starts `job` on a dedicated worker thread. The job receives the sink feeding the stream and
+    /// the cancellation flag.
+    pub(crate) fn spawn<F>(job: F) -> Arc<Self>
+    where
+        F: FnOnce(Arc<dyn TokenChunkSink>, Arc<AtomicBool>) -> Result<InferenceResult, PhiError>
+            + Send
+            + 'static,
+    {
+        let shared = Arc::new(StreamShared {
+            state: Mutex::new(StreamState::default()),
+            available: Condvar::new(),
+        });
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let sink: Arc<dyn TokenChunkSink> = Arc::new(StreamChunkSink {
+            shared: shared.clone(),
+        });
+        let worker_shared = shared.clone();
+        let worker_cancelled = cancelled.clone();
+        let spawned = std::thread::Builder::new()
+            .name("phi-engine-stream".to_string())
+            .spawn(move || {
+                let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    job(sink, worker_cancelled)
+                }))
+                .unwrap_or_else(|_| {
+                    Err(PhiError::InferenceError {
+                        error_text: "The inference worker thread panicked".to_string(),
+                    })
+                });
+                worker_shared.update(|state| state.outcome = Some(outcome));
+            });
+        if let Err(e) = spawned {
+            shared.update(|state| {
+                state.outcome = Some(Err(PhiError::InferenceError {
+                    error_text: format!("Error starting the inference worker thread: {}", e),
+                }))
+            });
+        }
+
+        Arc::new(Self { shared, cancelled })
+    }
+
+    pub fn next(&self) -> Result<Option<TokenChunk>, PhiError> {
+        let mut state = self.lock()?;
+        loop {
+            if let Some(next) = Self::take_next(&mut state) {
+                return next;
+            }
+            state = self
+                .shared
+                .available
+                .wait(state)
+                .map_err(|e| PhiError::LockingError {
+                    error_text: e.to_string(),
+                })?;
+        }
+    }
+
+    pub async fn next_async(&self) -> Result<Option<TokenChunk>, PhiError> {
+        NextChunk { stream: self }.await
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// The result of the inference once the stream has ended, `None` while it is still running or
+    /// if it failed.
+    pub fn get_result(&self) -> Option<InferenceResult> {
+        let state = self.lock().ok()?;
+        match &state.outcome {
+            Some(Ok(result)) if state.chunks.is_empty() => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, StreamState>, PhiError> {
+        self.shared.state.lock().map_err(|e| PhiError::LockingError {
+            error_text: e.to_string(),
+        })
+    }
+
+    // chunks are handed out before the end of the stream, errors are reported on every call after it
+    fn take_next(state: &mut StreamState) -> Option<Result<Option<TokenChunk>, PhiError>> {
+        if let Some(chunk) = state.chunks.pop_front() {
+            return Some(Ok(Some(chunk)));
+        }
+        match &state.outcome {
+            Some(Ok(_)) => Some(Ok(None)),
+            Some(Err(e)) => Some(Err(e.clone())),
+            None => None,
+        }
+    }
+}
+
+impl Drop for InferenceStream {
+    fn drop(&mut self) {
+        let running = self
+            .shared
+            .state
+            .lock()
+            .map(|state| state.outcome.is_none())
+            .unwrap_or(false);
+        if running {
+            debug!(" --> Inference stream was dropped before the inference ended, cancelling it");
+            self.cancel();
+        }
+    }
+}
+
+struct NextChunk<'a> {
+    stream: &'a InferenceStream,
+}
+
+impl Future for NextChunk<'_> {
+    type Output = Result<Option<TokenChunk>, PhiError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match self.stream.lock() {
+            Ok(state) => state,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        match InferenceStream::take_next(&mut state) {
+            Some(next) => Poll::Ready(next),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// receives the chunks of the inference on the worker thread and queues them for the stream
+struct StreamChunkSink {
+    shared: Arc<StreamShared>,
+}
+
+impl TokenChunkSink for StreamChunkSink {
+    fn on_chunk(&self, chunk: TokenChunk) -> Result<(), PhiError> {
+        self.shared.update(|state| state.chunks.push_back(chunk));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::FinishReason;
+    use crate::test_util::{self, no_context, ScriptedModel};
+
+    fn stream(model: ScriptedModel) -> (Arc<crate::engine::PhiEngine>, Arc<InferenceStream>) {
+        let engine = test_util::scripted_engine(model);
+        let stream = engine
+            .clone()
+            .stream_inference("hello".to_string(), no_context(), test_util::greedy_options(50));
+        (engine, stream)
+    }
+
+    #[test]
+    fn chunks_add_up_to_the_result_text() {
+        let (_, stream) = stream(ScriptedModel::new(&["▁hello", "▁a", "b", "<|end|>"]));
+        let mut chunks = vec![];
+        while let Some(chunk) = stream.next().unwrap() {
+            chunks.push(chunk);
+        }
+        assert!(stream.next().unwrap().is_none());
+
+        let result = stream.get_result().unwrap();
+        assert_eq!(result.result_text, "hello ab");
+        assert_eq!(result.finish_reason, FinishReason::Stop);
+        assert_eq!(chunks.iter().map(|chunk| chunk.text.as_str()).collect::<String>(), result.result_text);
+        let mut byte_offset = 0;
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.index, index as u32);
+            assert_eq!(chunk.byte_offset, byte_offset);
+            byte_offset += chunk.text.len() as u64;
+        }
+        // the end-of-text token stopping the generation
+        let last = chunks.last().unwrap();
+        assert!(last.is_special);
+        assert_eq!(last.text, "");
+    }
+
+    #[test]
+    fn async_chunks_add_up_to_the_result_text() {
+        let (_, stream) = stream(ScriptedModel::new(&["▁hello", "▁a", "b", "<|end|>"]));
+        let text = test_util::block_on(async {
+            let mut text = String::new();
+            while let Some(chunk) = stream.next_async().await.unwrap() {
+                text.push_str(&chunk.text);
+            }
+            text
+        });
+        assert_eq!(text, "hello ab");
+        assert_eq!(stream.get_result().unwrap().result_text, text);
+    }
+
+    #[test]
+    fn cancelled_stream_ends_with_an_error() {
+        let (model, third_pass) = ScriptedModel::new(&["a"]).paused_at(3);
+        let calls = model.calls.clone();
+        let (engine, stream) = stream(model);
+        third_pass.wait();
+        assert!(stream.get_result().is_none());
+        stream.cancel();
+        third_pass.resume();
+
+        // the tokens of the forward passes which ran are still delivered
+        let mut text = String::new();
+        let error = loop {
+            match stream.next() {
+                Ok(Some(chunk)) => text.push_str(&chunk.text),
+                Ok(None) => panic!("the stream ended without an error"),
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, PhiError::Cancelled));
+        assert!(matches!(stream.next(), Err(PhiError::Cancelled)));
+        assert!(stream.get_result().is_none());
+        assert_eq!(text, "aaa");
+        test_util::wait_for_workers(&engine);
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn dropped_stream_stops_the_generation() {
+        let (model, third_pass) = ScriptedModel::new(&["a"]).paused_at(3);
+        let calls = model.calls.clone();
+        let (engine, stream) = stream(model);
+        third_pass.wait();
+        drop(stream);
+        third_pass.resume();
+
+        test_util::wait_for_workers(&engine);
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,461 @@
+//! Fixtures shared by the unit tests.
+
+use crate::causal_lm::CausalLm;
+use crate::engine::{ConversationContext, InferenceOptions, InferenceOptionsBuilder, PhiEngine, PhiEventHandler};
+use crate::PhiError;
+use crate::quantize::{gguf_tensor_name, model_metadata, quantize_tensor};
+use candle_core::quantized::{gguf_file, GgmlDType};
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::phi3::Config as Phi3Config;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::Thread;
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
+
+pub const SPECIAL_TOKENS: [&str; 5] = ["<|endoftext|>", "<|end|>", "<|assistant|>", "<|user|>", "<|system|>"];
+
+/// A tiny SentencePiece style BPE tokenizer shaped like the one of Phi-3: byte fallback tokens,
+/// a few letters and merges, and the chat template's special tokens.
+pub fn tokenizer_json() -> Vec<u8> {
+    let mut vocab = vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()];
+    vocab.extend((0..=255).map(|byte| format!("<0x{:02X}>", byte)));
+    vocab.extend(
+        ["▁", "a", "b", "c", "h", "e", "i", "l", "o", "ab", "▁a", "he", "ll", "hell", "hello", "▁hello"]
+            .map(String::from),
+    );
+    let first_special = vocab.len();
+    let vocab = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+
+    let added_token = |id: usize, content: &str, rstrip: bool| {
+        json!({
+            "id": id, "content": content, "single_word": false, "lstrip": false, "rstrip": rstrip,
+            "normalized": false, "special": true,
+        })
+    };
+    let added_tokens = ["<unk>", "<s>", "</s>"]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| added_token(id, token, false))
+        .chain(
+            SPECIAL_TOKENS
+                .iter()
+                .enumerate()
+                // like in Phi-3, the chat template's tokens swallow the whitespace after them
+                .map(|(id, token)| added_token(first_special + id, token, *token != "<|endoftext|>")),
+        )
+        .collect::<Vec<_>>();
+
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": {
+            "type": "Sequence",
+            "normalizers": [
+                { "type": "Prepend", "prepend": "▁" },
+                { "type": "Replace", "pattern": { "String": " " }, "content": "▁" },
+            ],
+        },
+        "pre_tokenizer": null,
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [{ "SpecialToken": { "id": "<s>", "type_id": 0 } }, { "Sequence": { "id": "A", "type_id": 0 } }],
+            "pair": [{ "Sequence": { "id": "A", "type_id": 0 } }, { "Sequence": { "id": "B", "type_id": 1 } }],
+            "special_tokens": { "<s>": { "id": "<s>", "ids": [1], "tokens": ["<s>"] } },
+        },
+        "decoder": {
+            "type": "Sequence",
+            "decoders": [
+                { "type": "Replace", "pattern": { "String": "▁" }, "content": " " },
+                { "type": "ByteFallback" },
+                { "type": "Fuse" },
+                { "type": "Strip", "content": " ", "start": 1, "stop": 0 },
+            ],
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": "<unk>",
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": true,
+            "byte_fallback": true,
+            "ignore_merges": false,
+            "vocab": vocab,
+            "merges": [["a", "b"], ["▁", "a"], ["h", "e"], ["l", "l"], ["he", "ll"], ["hell", "o"], ["▁", "hello"]],
+        },
+    });
+    serde_json::to_vec(&tokenizer).unwrap()
+}
+
+pub fn tokenizer() -> Tokenizer {
+    Tokenizer::from_bytes(tokenizer_json()).unwrap()
+}
+
+/// Options which sample greedily, so that the logits alone decide the generated tokens.
+pub fn greedy_options(token_count: u16) -> InferenceOptions {
+    let options = InferenceOptionsBuilder::new();
+    options.with_temperature(0.).unwrap();
+    options.with_token_count(token_count).unwrap();
+    options.build().unwrap()
+}
+
+/// Logits of shape (`rows`, `vocab_size`) which make greedy sampling pick `token` in every row.
+pub fn logits_for(token: u32, rows: usize, vocab_size: usize) -> Tensor {
+    let mut logits = vec![0f32; vocab_size];
+    logits[token as usize] = 10.;
+    Tensor::new(logits, &Device::Cpu).unwrap().unsqueeze(0).unwrap().repeat((rows, 1)).unwrap()
+}
+
+/// The input and position of a forward pass.
+pub type ForwardPass = (Vec<u32>, usize);
+
+/// Plays back a script of tokens, one per forward pass, and records the forward passes.
+#[derive(Clone)]
+pub struct ScriptedModel {
+    pub script: Vec<u32>,
+    pub max_context: usize,
+    pub device: Device,
+    pub calls: Arc<Mutex<Vec<ForwardPass>>>,
+    /// Called with the number of forward passes so far at the end of every forward pass.
+    pub on_forward: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+}
+
+impl ScriptedModel {
+    pub fn new(script: &[&str]) -> Self {
+        let tokenizer = tokenizer();
+        Self {
+            script: script.iter().map(|token| tokenizer.token_to_id(token).unwrap()).collect(),
+            max_context: 64,
+            device: Device::Cpu,
+            calls: Arc::default(),
+            on_forward: None,
+        }
+    }
+
+    pub fn calls(&self) -> Vec<ForwardPass> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Makes the forward pass number `pass` (counting from 1) wait until `PausedForwardPass::resume`.
+    pub fn paused_at(self, pass: usize) -> (Self, PausedForwardPass) {
+        let (reached, reached_receiver) = mpsc::channel();
+        let (resume_sender, resume) = mpsc::channel();
+        let resume = Mutex::new(resume);
+        let model = Self {
+            on_forward: Some(Arc::new(move |passes| {
+                if passes == pass {
+                    reached.send(()).unwrap();
+                    resume.lock().unwrap().recv().unwrap();
+                }
+            })),
+            ..self
+        };
+        let paused = PausedForwardPass {
+            reached: reached_receiver,
+            resume: resume_sender,
+        };
+        (model, paused)
+    }
+}
+
+/// A forward pass of a `ScriptedModel` held until the test resumes it.
+pub struct PausedForwardPass {
+    reached: Receiver<()>,
+    resume: Sender<()>,
+}
+
+impl PausedForwardPass {
+    /// Blocks until the generation has reached the forward pass.
+    pub fn wait(&self) {
+        self.reached.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    pub fn resume(&self) {
+        self.resume.send(()).unwrap();
+    }
+}
+
+impl CausalLm for ScriptedModel {
+    fn forward(&mut self, input: &[u32], position: usize) -> anyhow::Result<Tensor> {
+        let passes = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((input.to_vec(), position));
+            calls.len()
+        };
+        if let Some(on_forward) = &self.on_forward {
+            on_forward(passes);
+        }
+        let token = self.script[(passes - 1).min(self.script.len() - 1)];
+        Ok(logits_for(token, 1, self.vocab_size()).squeeze(0)?)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    fn vocab_size(&self) -> usize {
+        tokenizer().get_vocab_size(true)
+    }
+
+    fn max_context(&self) -> usize {
+        self.max_context
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn box_clone(&self) -> Box<dyn CausalLm> {
+        Box::new(self.clone())
+    }
+}
+
+pub fn no_context() -> ConversationContext {
+    ConversationContext {
+        system_instruction: None,
+        messages: vec![],
+    }
+}
+
+/// An engine generating with `model` and the test tokenizer.
+pub fn scripted_engine(model: ScriptedModel) -> Arc<PhiEngine> {
+    Arc::new(PhiEngine::from_model(Box::new(model), tokenizer()))
+}
+
+/// Waits for the worker threads of `engine` to end, which hold a reference to it while they run.
+pub fn wait_for_workers(engine: &Arc<PhiEngine>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Arc::strong_count(engine) > 1 {
+        assert!(Instant::now() < deadline, "the worker thread did not end");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// wakes the thread blocked on a future
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread until it completes, like the executor of a foreign language would.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// A Phi-3 configuration small enough to run in tests, with grouped query attention.
+pub fn phi3_config() -> Phi3Config {
+    serde_json::from_value(phi3_config_json()).unwrap()
+}
+
+/// `phi3_config` as the `config.json` of a model.
+pub fn phi3_config_json() -> serde_json::Value {
+    json!({
+        "vocab_size": 64,
+        "hidden_act": "silu",
+        "hidden_size": 32,
+        "intermediate_size": 48,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "bos_token_id": 1,
+        "eos_token_id": 2,
+        "rope_scaling": null,
+        "max_position_embeddings": 128,
+    })
+}
+
+/// Random weights for `config`, named as in the safetensors files of Phi-3.
+pub fn phi3_weights(config: &Phi3Config, device: &Device) -> HashMap<String, Tensor> {
+    let random = |shape: &[usize]| Tensor::randn(0f32, 0.2, shape, device).unwrap();
+    let norm = || (Tensor::ones(config.hidden_size, DType::F32, device).unwrap() + random(&[config.hidden_size])).unwrap();
+    let (hidden, intermediate) = (config.hidden_size, config.intermediate_size);
+    let qkv = (config.num_attention_heads + 2 * config.num_key_value_heads) * config.head_dim();
+    let mut weights = HashMap::from([
+        ("model.embed_tokens.weight".to_string(), random(&[config.vocab_size, hidden])),
+        ("model.norm.weight".to_string(), norm()),
+        ("lm_head.weight".to_string(), random(&[config.vocab_size, hidden])),
+    ]);
+    for layer in 0..config.num_hidden_layers {
+        let prefix = format!("model.layers.{}", layer);
+        weights.extend([
+            (format!("{}.self_attn.qkv_proj.weight", prefix), random(&[qkv, hidden])),
+            (format!("{}.self_attn.o_proj.weight", prefix), random(&[hidden, config.num_attention_heads * config.head_dim()])),
+            (format!("{}.mlp.gate_up_proj.weight", prefix), random(&[2 * intermediate, hidden])),
+            (format!("{}.mlp.down_proj.weight", prefix), random(&[hidden, intermediate])),
+            (format!("{}.input_layernorm.weight", prefix), norm()),
+            (format!("{}.post_attention_layernorm.weight", prefix), norm()),
+        ]);
+    }
+    weights
+}
+
+/// `weights` in a GGUF file written like `quantize_model` does, with the linear layers quantized to `dtype`.
+pub fn phi3_gguf(config: &Phi3Config, weights: &HashMap<String, Tensor>, dtype: GgmlDType) -> Cursor<Vec<u8>> {
+    let tensors = weights
+        .iter()
+        .map(|(name, tensor)| (gguf_tensor_name(name).unwrap(), quantize_tensor(tensor, dtype).unwrap()))
+        .collect::<Vec<_>>();
+    let metadata = model_metadata("test", config);
+    let metadata_refs = metadata.iter().map(|(k, v)| (k.as_str(), v)).collect::<Vec<_>>();
+    let tensor_refs = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect::<Vec<_>>();
+    let mut file = Cursor::new(Vec::new());
+    gguf_file::write(&mut file, &metadata_refs, &tensor_refs).unwrap();
+    file.set_position(0);
+    file
+}
+
+/// The largest absolute difference between two tensors of the same shape.
+pub fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap()
+}
+
+/// An empty directory for the files of a test, unique to the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("phi-engine-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes a safetensors model to `dir` like it is published on the Hugging Face Hub: `config.json`,
+/// a single `model.safetensors` with its index, and `tokenizer.json`.
+pub fn write_phi3_model(dir: &Path, config: &serde_json::Value, weights: &HashMap<String, Tensor>) {
+    std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+    candle_core::safetensors::save(weights, dir.join("model.safetensors")).unwrap();
+    let weight_map = weights
+        .keys()
+        .map(|name| (name.clone(), json!("model.safetensors")))
+        .collect::<serde_json::Map<_, _>>();
+    let index = json!({ "metadata": {}, "weight_map": weight_map });
+    std::fs::write(dir.join("model.safetensors.index.json"), index.to_string()).unwrap();
+    std::fs::write(dir.join("tokenizer.json"), tokenizer_json()).unwrap();
+}
+
+/// The low rank matrices A and B of the update of one module.
+pub type LoraUpdate = (Tensor, Tensor);
+
+/// Writes a PEFT LoRA adapter to `dir` with random updates of the given `(module, rank)` pairs,
+/// sized after the module's weight in `weights`. Returns the updates keyed by module.
+pub fn write_lora_adapter(
+    dir: &Path,
+    adapter_config: serde_json::Value,
+    weights: &HashMap<String, Tensor>,
+    modules: &[(&str, usize)],
+) -> HashMap<String, LoraUpdate> {
+    let device = Device::Cpu;
+    let mut updates = HashMap::new();
+    let mut tensors = HashMap::new();
+    for (module, rank) in modules {
+        let (out_dim, in_dim) = weights[&format!("{module}.weight")].dims2().unwrap();
+        let a = Tensor::randn(0f32, 0.2, (*rank, in_dim), &device).unwrap();
+        let b = Tensor::randn(0f32, 0.2, (out_dim, *rank), &device).unwrap();
+        // PEFT saves the modules of the wrapped model under base_model.model
+        tensors.insert(format!("base_model.model.{module}.lora_A.weight"), a.clone());
+        tensors.insert(format!("base_model.model.{module}.lora_B.weight"), b.clone());
+        updates.insert(module.to_string(), (a, b));
+    }
+    std::fs::write(dir.join("adapter_config.json"), adapter_config.to_string()).unwrap();
+    candle_core::safetensors::save(&tensors, dir.join("adapter_model.safetensors")).unwrap();
+    updates
+}
+
+/// `weights` with the LoRA `updates` merged into them: W + scale·B·A, with the scale of every module.
+pub fn merge_lora(
+    weights: &HashMap<String, Tensor>,
+    updates: &HashMap<String, LoraUpdate>,
+    scales: &[(&str, f64)],
+) -> HashMap<String, Tensor> {
+    let mut merged = weights.clone();
+    for (module, scale) in scales {
+        let (a, b) = &updates[*module];
+        let name = format!("{module}.weight");
+        let update = (b.matmul(a).unwrap() * *scale).unwrap();
+        merged.insert(name.clone(), (&weights[&name] + update).unwrap());
+    }
+    assert_eq!(scales.len(), updates.len(), "every update needs a scale");
+    merged
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    ModelLoaded,
+    InferenceStarted(String),
+    InferenceEnded(String),
+    InferenceToken(String, String),
+    DownloadStarted(String, u64),
+    DownloadProgress(String, u64),
+    DownloadCompleted(String),
+}
+
+/// A `PhiEventHandler` which records the events it receives, in order.
+#[derive(Default)]
+pub struct RecordingEventHandler {
+    pub events: Mutex<Vec<Event>>,
+}
+
+impl RecordingEventHandler {
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: Event) -> Result<(), PhiError> {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+impl PhiEventHandler for RecordingEventHandler {
+    fn on_model_loaded(&self) -> Result<(), PhiError> {
+        self.record(Event::ModelLoaded)
+    }
+
+    fn on_inference_started(&self, request_id: String) -> Result<(), PhiError> {
+        self.record(Event::InferenceStarted(request_id))
+    }
+
+    fn on_inference_ended(&self, request_id: String) -> Result<(), PhiError> {
+        self.record(Event::InferenceEnded(request_id))
+    }
+
+    fn on_inference_token(&self, request_id: String, token: String) -> Result<(), PhiError> {
+        self.record(Event::InferenceToken(request_id, token))
+    }
+
+    fn on_download_started(&self, file_name: String, total_bytes: u64) -> Result<(), PhiError> {
+        self.record(Event::DownloadStarted(file_name, total_bytes))
+    }
+
+    fn on_download_progress(&self, file_name: String, downloaded_bytes: u64) -> Result<(), PhiError> {
+        self.record(Event::DownloadProgress(file_name, downloaded_bytes))
+    }
+
+    fn on_download_completed(&self, file_name: String) -> Result<(), PhiError> {
+        self.record(Event::DownloadCompleted(file_name))
+    }
+}
@@ -1,29 +1,137 @@
 use anyhow::{Error as E, Result};
-use candle_core::{DType, Device, IndexOp, Tensor};
+use candle_core::{Tensor, D};
 use candle_transformers::generation::{LogitsProcessor, Sampling};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokenizers::Tokenizer;
 use tracing::{debug, info};
 
-use crate::engine::{InferenceOptions, InferenceResult, Model, PhiEventHandler};
+use crate::causal_lm::CausalLm;
+use crate::engine::{FinishReason, InferenceOptions, InferenceResult, PhiEventHandler};
+use crate::stream::{TokenChunk, TokenChunkSink};
 use crate::token_stream::TokenOutputStream;
 use crate::PhiError;
 
+/// The state of a single generation. The model is passed in by the caller, so that a scheduler
+/// can drive several generations with one model.
 pub(crate) struct TextGenerator {
-    pub model: Model,
-    device: Device,
     tokenizer: Tokenizer,
+    tos: TokenOutputStream,
     logits_processor: LogitsProcessor,
     inference_options: InferenceOptions,
+    request_id: String,
     event_handler: Option<Arc<dyn PhiEventHandler>>,
+    chunk_sink: Option<Arc<dyn TokenChunkSink>>,
+    cancelled: Option<Arc<AtomicBool>>,
+    // set by `start`
+    stop_tokens: Vec<u32>,
+    special_tokens: HashSet<u32>,
+    prompt_len: usize,
+    to_sample: usize,
+    // updated by every `step`
+    sampled: usize,
+    all_tokens: Vec<u32>,
+    chunks: ChunkState,
+    stop_chunk: Option<(u32, Option<f64>)>,
+    stop_sequences: StopSequences,
+    // where the stop sequence found starts in the result text
+    stop_offset: Option<usize>,
+    start_post_prompt: Option<std::time::Instant>,
+}
+
+// the sampled tokens which have not been delivered in a chunk yet
+#[derive(Default)]
+struct ChunkState {
+    token_ids: Vec<u32>,
+    logprob: Option<f64>,
+    byte_offset: u64,
+    index: u32,
+}
+
+impl ChunkState {
+    fn push(&mut self, token: u32, logprob: Option<f64>) {
+        self.token_ids.push(token);
+        if let Some(logprob) = logprob {
+            self.logprob = Some(self.logprob.unwrap_or(0.) + logprob);
+        }
+    }
+
+    fn take(&mut self, text: String, is_special: bool) -> TokenChunk {
+        let chunk = TokenChunk {
+            token_ids: std::mem::take(&mut self.token_ids),
+            byte_offset: self.byte_offset,
+            logprob: self.logprob.take(),
+            index: self.index,
+            is_special,
+            text,
+        };
+        self.byte_offset += chunk.text.len() as u64;
+        self.index += 1;
+        chunk
+    }
+
+    // special tokens get a chunk of their own, without waiting for the pending tokens
+    fn take_special(&mut self, token: u32, logprob: Option<f64>) -> TokenChunk {
+        let pending = (std::mem::take(&mut self.token_ids), self.logprob.take());
+        self.push(token, logprob);
+        let chunk = self.take(String::new(), true);
+        (self.token_ids, self.logprob) = pending;
+        chunk
+    }
+}
+
+// decoded text which could be the start of a stop sequence, held back until the next tokens show
+// whether the rest of it follows
+struct StopSequences {
+    stop_sequences: Vec<String>,
+    held_text: String,
+}
+
+impl StopSequences {
+    fn new(stop_sequences: &[String]) -> Self {
+        Self {
+            stop_sequences: stop_sequences.iter().filter(|s| !s.is_empty()).cloned().collect(),
+            held_text: String::new(),
+        }
+    }
+
+    // returns the text which can be released, and whether a stop sequence was found (in which case
+    // the text ends before it). With `last`, nothing more is coming so no text is held back
+    fn push(&mut self, text: &str, last: bool) -> (String, bool) {
+        self.held_text.push_str(text);
+        let stop_at = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop_sequence| self.held_text.find(stop_sequence.as_str()))
+            .min();
+        let release = match stop_at {
+            Some(stop_at) => stop_at,
+            None if last => self.held_text.len(),
+            None => self
+                .held_text
+                .char_indices()
+                .map(|(idx, _)| idx)
+                .find(|idx| {
+                    self.stop_sequences
+                        .iter()
+                        .any(|stop_sequence| stop_sequence.starts_with(&self.held_text[*idx..]))
+                })
+                .unwrap_or(self.held_text.len()),
+        };
+        let text = self.held_text.drain(..release).collect::<String>();
+        if stop_at.is_some() {
+            self.held_text.clear();
+        }
+        (text, stop_at.is_some())
+    }
 }
 
 impl TextGenerator {
     pub fn new(
-        model: &Model,
         tokenizer: Tokenizer,
         inference_options: &InferenceOptions,
-        device: &Device,
+        request_id: String,
         event_handler: Option<Arc<dyn PhiEventHandler>>,
     ) -> Self {
         let logits_processor = {
@@ -48,170 +156,408 @@ impl TextGenerator {
             LogitsProcessor::from_sampling(inference_options.seed, sampling)
         };
         Self {
-            model: model.clone(),
+            tos: TokenOutputStream::new(tokenizer.clone()),
             tokenizer,
             logits_processor,
             inference_options: inference_options.clone(),
-            device: device.clone(),
-            event_handler: event_handler,
+            request_id,
+            event_handler,
+            chunk_sink: None,
+            cancelled: None,
+            stop_tokens: Vec::new(),
+            special_tokens: HashSet::new(),
+            prompt_len: 0,
+            to_sample: 0,
+            sampled: 0,
+            all_tokens: Vec::new(),
+            chunks: ChunkState::default(),
+            stop_chunk: None,
+            stop_sequences: StopSequences::new(&inference_options.stop_sequences),
+            stop_offset: None,
+            start_post_prompt: None,
         }
     }
 
-    // inference code adapted from https://github.com/huggingface/candle/blob/main/candle-examples
-    pub fn run(&mut self, prompt: &str, sample_len: u16) -> Result<InferenceResult> {
-        if let Some(event_handler) = &self.event_handler {
-            event_handler
-                .on_inference_started()
-                .map_err(|e| PhiError::InferenceError {
-                    error_text: e.to_string(),
-                })?;
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Stops the generation before the next forward pass once `cancelled` is set.
+    pub fn with_cancellation(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Delivers the generated tokens, grouped into `TokenChunk`s, to `chunk_sink`.
+    pub fn with_chunk_sink(mut self, chunk_sink: Arc<dyn TokenChunkSink>) -> Self {
+        self.chunk_sink = Some(chunk_sink);
+        self
+    }
+
+    fn send_chunk(&self, chunk: TokenChunk) -> Result<()> {
+        if let Some(chunk_sink) = &self.chunk_sink {
+            chunk_sink.on_chunk(chunk)?;
         }
+        Ok(())
+    }
 
-        let mut tos = TokenOutputStream::new(self.tokenizer.clone());
-        let tokens = tos.tokenizer().encode(prompt, true).map_err(E::msg)?;
-        let tokens = tokens.get_ids();
+    // delivers decoded text to the chunk sink and the event handler, holding back the end which
+    // could be the start of a stop sequence. Returns true when a stop sequence was found, only the
+    // text before it is delivered
+    fn deliver(&mut self, text: String, last: bool) -> Result<bool> {
+        let (text, stopped) = self.stop_sequences.push(&text, last);
+        if stopped {
+            self.stop_offset = Some(self.chunks.byte_offset as usize + text.len());
+        }
 
-        let quantized = match &self.model {
-            Model::Standard(_) => false,
-            Model::Quantized(_) => true,
-        };
-        debug!("Model is quantized: {}", quantized);
-
-        let mut all_tokens = vec![];
-        let mut next_token = if quantized {
-            let mut next_token = 0;
-            for (pos, token) in tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &self.device)?.unsqueeze(0)?;
-                let logits = match &mut self.model {
-                    Model::Standard(m) => m
-                        .forward(&input, pos)?
-                        .i((.., 0, ..))?
-                        .squeeze(0)?
-                        .to_dtype(DType::F32)?,
-                    Model::Quantized(m) => m.forward(&input, pos)?.squeeze(0)?,
-                };
-                next_token = self.logits_processor.sample(&logits)?;
+        if !text.is_empty() || (last && !self.chunks.token_ids.is_empty()) {
+            let chunk = self.chunks.take(text.clone(), false);
+            self.send_chunk(chunk)?;
+        }
+        if !text.is_empty() {
+            if let Some(event_handler) = &self.event_handler {
+                event_handler
+                    .on_inference_token(self.request_id.clone(), text)
+                    .map_err(|e| PhiError::event_handler_error("on_inference_token", e))?;
             }
-            next_token
-        } else {
-            0
-        };
+        }
+        Ok(stopped)
+    }
 
-        if quantized {
-            all_tokens.push(next_token);
-            if let Some(t) = tos.next_token(next_token)? {
-                if let Some(event_handler) = &self.event_handler {
-                    event_handler.on_inference_token(t).map_err(|e| {
-                        PhiError::InferenceError {
-                            error_text: e.to_string(),
-                        }
-                    })?;
-                }
+    // the log probability of `token` under the model's distribution, before temperature and top-k/top-p
+    fn token_logprob(&self, logits: &Tensor, token: u32) -> Result<Option<f64>> {
+        if !self.inference_options.logprobs || self.chunk_sink.is_none() {
+            return Ok(None);
+        }
+        let logprobs = candle_nn::ops::log_softmax(logits, D::Minus1)?;
+        Ok(Some(logprobs.get(token as usize)?.to_scalar::<f32>()? as f64))
+    }
+
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self
+            .cancelled
+            .as_ref()
+            .is_some_and(|cancelled| cancelled.load(Ordering::SeqCst))
+        {
+            return Err(PhiError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Runs the whole generation on `model`, one forward pass per token.
+    // inference code adapted from https://github.com/huggingface/candle/blob/main/candle-examples
+    pub fn run(&mut self, model: &mut dyn CausalLm, prompt: &str, sample_len: u16) -> Result<InferenceResult> {
+        let tokens = self.start(prompt, sample_len, model.max_context())?;
+
+        // process the whole prompt in one forward pass, then feed back one token at a time
+        model.clear_kv_cache();
+        self.check_cancelled()?;
+        let mut logits = model.forward(&tokens, 0)?;
+        let mut pos = tokens.len();
+        while let Some(next_token) = self.step(&logits)? {
+            self.check_cancelled()?;
+            logits = model.forward(&[next_token], pos)?;
+            pos += 1;
+        }
+        self.finish()
+    }
+
+    /// Starts the generation and returns the tokens of the prompt, which have to be fed to the model
+    /// before the first `step`.
+    pub fn start(&mut self, prompt: &str, sample_len: u16, max_context: usize) -> Result<Vec<u32>> {
+        if let Some(event_handler) = &self.event_handler {
+            event_handler
+                .on_inference_started(self.request_id.clone())
+                .map_err(|e| PhiError::event_handler_error("on_inference_started", e))?;
+        }
+
+        let tokens = self.tos.tokenizer().encode(prompt, true).map_err(E::msg)?;
+        let tokens = tokens.get_ids().to_vec();
+        if tokens.len() >= max_context {
+            return Err(PhiError::ContextOverflow {
+                prompt_tokens: tokens.len() as u64,
+                max_context: max_context as u64,
             }
+            .into());
         }
+        self.to_sample = (sample_len as usize).min(max_context - tokens.len());
+        self.prompt_len = tokens.len();
 
         let binding = self.tokenizer.get_vocab(true);
         let endoftext_token = binding
             .get("<|endoftext|>")
             .ok_or_else(|| anyhow::Error::msg("No <|endoftext|> found"))?;
-        let end_token = binding.get("<|end|>");
-        let assistant_token = binding.get("<|assistant|>");
+        self.stop_tokens = [Some(endoftext_token), binding.get("<|end|>"), binding.get("<|assistant|>")]
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        self.special_tokens = self
+            .tokenizer
+            .get_added_tokens_decoder()
+            .into_iter()
+            .filter(|(_, token)| token.special)
+            .map(|(id, _)| id)
+            .collect();
+        Ok(tokens)
+    }
 
-        let start_post_prompt = std::time::Instant::now();
-        let mut sampled = 0;
-        let to_sample = if quantized {
-            sample_len.saturating_sub(1) as usize
+    /// Samples the next token from the logits of the last forward pass. Returns the token to feed
+    /// to the model next, or `None` once the generation has ended.
+    pub fn step(&mut self, logits: &Tensor) -> Result<Option<u32>> {
+        if self.sampled >= self.to_sample {
+            return Ok(None);
+        }
+        // the time to first token is not part of the measured throughput
+        self.start_post_prompt.get_or_insert_with(std::time::Instant::now);
+        let logits = if self.inference_options.repeat_penalty == 1.0 {
+            logits.clone()
         } else {
-            sample_len as usize
+            let start_at = self
+                .all_tokens
+                .len()
+                .saturating_sub(self.inference_options.repeat_last_n.into());
+            candle_transformers::utils::apply_repeat_penalty(
+                logits,
+                self.inference_options.repeat_penalty,
+                &self.all_tokens[start_at..],
+            )?
         };
 
-        let prompt_len = tokens.len();
-        let mut tokens = tokens.to_vec();
-        let mut pos = 0;
-        for index in 0..to_sample {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let logits = match &mut self.model {
-                Model::Quantized(m) => {
-                    let input = Tensor::new(&[next_token], &self.device)?.unsqueeze(0)?;
-                    m.forward(&input, prompt_len + index)?.squeeze(0)?
-                }
-                Model::Standard(m) => {
-                    let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-                    let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-                    m.forward(&input, pos)?
-                        .i((.., 0, ..))?
-                        .squeeze(0)?
-                        .to_dtype(DType::F32)?
-                }
-            };
-            let logits = if self.inference_options.repeat_penalty == 1.0 {
-                logits
-            } else {
-                let start_at = all_tokens
-                    .len()
-                    .saturating_sub(self.inference_options.repeat_last_n.into());
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.inference_options.repeat_penalty,
-                    &all_tokens[start_at..],
-                )?
-            };
-
-            next_token = self.logits_processor.sample(&logits)?;
+        let next_token = self.logits_processor.sample(&logits)?;
+        self.all_tokens.push(next_token);
+        let logprob = self.token_logprob(&logits, next_token)?;
 
-            if !quantized {
-                tokens.push(next_token);
-            }
-
-            all_tokens.push(next_token);
-
-            if &next_token == endoftext_token
-            || end_token.map_or(false, |token| &next_token == token)
-            || assistant_token.map_or(false, |token| &next_token == token)
-            {
-                info!("Breaking due to end token: {}", next_token);
-                break;
-            }
+        if self.stop_tokens.contains(&next_token) {
+            info!("Breaking due to end token: {}", next_token);
+            // delivered after the rest of the text
+            self.stop_chunk = Some((next_token, logprob));
+            self.to_sample = self.sampled;
+            return Ok(None);
+        }
 
-            if let Some(t) = tos.next_token(next_token)? {
-                if let Some(event_handler) = &self.event_handler {
-                    event_handler.on_inference_token(t).map_err(|e| {
-                        PhiError::InferenceError {
-                            error_text: e.to_string(),
-                        }
-                    })?;
+        // special tokens are not part of the decoded text
+        if self.special_tokens.contains(&next_token) {
+            let chunk = self.chunks.take_special(next_token, logprob);
+            self.send_chunk(chunk)?;
+        } else {
+            self.chunks.push(next_token, logprob);
+            if let Some(t) = self.tos.next_token(next_token)? {
+                if self.deliver(t, false)? {
+                    info!("Breaking due to stop sequence");
+                    self.sampled += 1;
+                    self.to_sample = self.sampled;
+                    return Ok(None);
                 }
             }
-            sampled += 1;
-            pos += context_size;
         }
+        self.sampled += 1;
+
+        if self.sampled >= self.to_sample {
+            return Ok(None);
+        }
+        Ok(Some(next_token))
+    }
+
+    /// Delivers what is left of the text and ends the generation.
+    pub fn finish(&mut self) -> Result<InferenceResult> {
+        debug!("Sampled {} tokens after a {} token prompt", self.sampled, self.prompt_len);
+        let finish_reason = match self.stop_chunk.is_some() || self.stop_offset.is_some() {
+            true => FinishReason::Stop,
+            false => FinishReason::Length,
+        };
 
         // we have ended to inference already, so try to still call the callback for the last token
-        if let Some(last_token) = tos.decode_rest()? {
-            if let Some(event_handler) = &self.event_handler {
-                event_handler
-                    .on_inference_token(last_token)
-                    .map_err(|e| PhiError::InferenceError {
-                        error_text: e.to_string(),
-                    })?;
-            }
+        if self.stop_offset.is_none() {
+            let rest = self.tos.decode_rest()?;
+            self.deliver(rest.unwrap_or_default(), true)?;
+        }
+        if let Some((stop_token, logprob)) = self.stop_chunk.take() {
+            let chunk = self.chunks.take_special(stop_token, logprob);
+            self.send_chunk(chunk)?;
         }
 
         if let Some(event_handler) = &self.event_handler {
             event_handler
-                .on_inference_ended()
-                .map_err(|e| PhiError::InferenceError {
-                    error_text: e.to_string(),
-                })?;
+                .on_inference_ended(self.request_id.clone())
+                .map_err(|e| PhiError::event_handler_error("on_inference_ended", e))?;
         }
 
-        let dt = start_post_prompt.elapsed();
+        let dt = self
+            .start_post_prompt
+            .map(|start_post_prompt| start_post_prompt.elapsed())
+            .unwrap_or_default();
+        let sampled = self.sampled as u16;
+        let mut result_text = self.tos.decode_all().map_err(E::msg)?;
+        if let Some(stop_offset) = self.stop_offset {
+            if result_text.is_char_boundary(stop_offset) {
+                result_text.truncate(stop_offset);
+            }
+        }
         let inference_result = InferenceResult {
+            request_id: self.request_id.clone(),
             token_count: sampled,
-            result_text: tos.decode_all().map_err(E::msg)?,
+            prompt_token_count: self.prompt_len as u32,
+            finish_reason,
+            result_text,
             duration: dt.as_secs_f64(),
             tokens_per_second: sampled as f64 / dt.as_secs_f64(),
         };
         Ok(inference_result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, ScriptedModel};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct CollectingSink {
+        chunks: Mutex<Vec<TokenChunk>>,
+    }
+
+    impl TokenChunkSink for CollectingSink {
+        fn on_chunk(&self, chunk: TokenChunk) -> std::result::Result<(), PhiError> {
+            self.chunks.lock().unwrap().push(chunk);
+            Ok(())
+        }
+    }
+
+    fn generator(options: &InferenceOptions) -> TextGenerator {
+        TextGenerator::new(test_util::tokenizer(), options, "request".to_string(), None)
+    }
+
+    fn encode(text: &str) -> Vec<u32> {
+        test_util::tokenizer().encode(text, true).unwrap().get_ids().to_vec()
+    }
+
+    #[test]
+    fn generates_with_any_causal_lm() {
+        let mut model = ScriptedModel::new(&["▁hello", "▁a", "b", "<|end|>"]);
+        let result = generator(&test_util::greedy_options(10))
+            .run(&mut model, "hello", 10)
+            .unwrap();
+
+        assert_eq!(result.result_text, "hello ab");
+        assert_eq!(result.token_count, 3);
+        assert_eq!(result.prompt_token_count, 2);
+        assert_eq!(result.finish_reason, FinishReason::Stop);
+        // the prompt in one forward pass, then every sampled token at the next position
+        let tokens = encode("hello");
+        let [hello, a, b, _] = model.script[..] else { unreachable!() };
+        assert_eq!(model.calls(), [(tokens, 0), (vec![hello], 2), (vec![a], 3), (vec![b], 4)]);
+    }
+
+    #[test]
+    fn generation_ends_at_the_token_count() {
+        let mut model = ScriptedModel::new(&["a"]);
+        let result = generator(&test_util::greedy_options(4)).run(&mut model, "hello", 4).unwrap();
+        assert_eq!(result.result_text, "aaaa");
+        assert_eq!(result.finish_reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn stop_sequences_are_held_back_from_the_chunks() {
+        let mut options = test_util::greedy_options(10);
+        options.stop_sequences = vec!["o a".to_string()];
+        let sink = Arc::new(CollectingSink::default());
+        let mut model = ScriptedModel::new(&["hell", "o", "▁a", "b", "c"]);
+        let result = generator(&options)
+            .with_chunk_sink(sink.clone())
+            .run(&mut model, "ab", 10)
+            .unwrap();
+
+        assert_eq!(result.result_text, "hell");
+        assert_eq!(result.finish_reason, FinishReason::Stop);
+        // "o" could have been the start of the stop sequence, so it was never delivered
+        let chunks = sink.chunks.lock().unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.text.as_str()).collect::<String>(), "hell");
+        assert_eq!(model.calls().len(), 3);
+    }
+
+    #[test]
+    fn prompts_filling_the_context_overflow() {
+        let mut model = ScriptedModel {
+            max_context: 4,
+            ..ScriptedModel::new(&["a"])
+        };
+        let error = generator(&test_util::greedy_options(10))
+            .run(&mut model, "hello hello hello", 10)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast::<PhiError>(),
+            Ok(PhiError::ContextOverflow {
+                prompt_tokens: 4,
+                max_context: 4
+            })
+        ));
+        assert!(model.calls().is_empty());
+    }
+
+    #[test]
+    fn cancelled_generation_stops_before_the_next_forward_pass() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let model_cancelled = cancelled.clone();
+        let mut model = ScriptedModel {
+            on_forward: Some(Arc::new(move |passes| {
+                if passes == 3 {
+                    model_cancelled.store(true, Ordering::SeqCst);
+                }
+            })),
+            ..ScriptedModel::new(&["a"])
+        };
+        let error = generator(&test_util::greedy_options(10))
+            .with_cancellation(cancelled)
+            .run(&mut model, "hello", 10)
+            .unwrap_err();
+        assert!(matches!(error.downcast::<PhiError>(), Ok(PhiError::Cancelled)));
+        assert_eq!(model.calls().len(), 3);
+    }
+
+    fn stop_sequences(stop_sequences: &[&str]) -> StopSequences {
+        StopSequences::new(&stop_sequences.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn stop_sequence_spanning_two_chunks_is_held_back() {
+        let mut filter = stop_sequences(&["</answer>"]);
+        assert_eq!(filter.push("The answer is 42</ans", false), ("The answer is 42".to_string(), false));
+        assert_eq!(filter.push("wer> and more", false), (String::new(), true));
+    }
+
+    #[test]
+    fn held_back_text_is_released_when_the_stop_sequence_does_not_follow() {
+        let mut filter = stop_sequences(&["\n\nUser:"]);
+        assert_eq!(filter.push("one\n", false), ("one".to_string(), false));
+        assert_eq!(filter.push("\nUs", false), (String::new(), false));
+        assert_eq!(filter.push("ually", false), ("\n\nUsually".to_string(), false));
+    }
+
+    #[test]
+    fn held_back_text_is_released_at_the_end() {
+        let mut filter = stop_sequences(&["STOP"]);
+        assert_eq!(filter.push("ends with ST", false), ("ends with ".to_string(), false));
+        assert_eq!(filter.push("", true), ("ST".to_string(), false));
+    }
+
+    #[test]
+    fn earliest_stop_sequence_wins() {
+        let mut filter = stop_sequences(&["c", "b"]);
+        assert_eq!(filter.push("abc", false), ("a".to_string(), true));
+    }
+
+    #[test]
+    fn empty_stop_sequences_are_ignored() {
+        let mut filter = stop_sequences(&[""]);
+        assert_eq!(filter.push("text", false), ("text".to_string(), false));
+    }
+
+    #[test]
+    fn multi_byte_characters_are_not_split() {
+        let mut filter = stop_sequences(&["é!"]);
+        assert_eq!(filter.push("café", false), ("caf".to_string(), false));
+        assert_eq!(filter.push("s", false), ("és".to_string(), false));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::PhiError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tracing::debug;
+
+// the result of the job, and the waker of the task waiting for it
+struct Shared<T> {
+    result: Option<Result<T, PhiError>>,
+    waker: Option<Waker>,
+}
+
+/// Resolves with the result of a job running on a worker thread.
+///
+/// The futures of the async API are polled by the executor of the foreign language (e.g. Swift
+/// or Kotlin coroutines), so the work itself can't run on it. Dropping the future before the job
+/// completes, which is what happens when the foreign task is cancelled, sets the cancellation flag
+/// handed to the job.
+pub(crate) struct WorkerFuture<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    cancelled: Arc<AtomicBool>,
+    done: bool,
+}
+
+/// Runs `job` on a new, dedicated thread. The job receives the cancellation flag and should stop
+/// as soon as possible once it is set.
+pub(crate) fn spawn_worker<T, F>(name: &str, job: F) -> WorkerFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Arc<AtomicBool>) -> Result<T, PhiError> + Send + 'static,
+{
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    let worker_shared = shared.clone();
+    let worker_cancelled = cancelled.clone();
+    let spawned = std::thread::Builder::new().name(name.to_string()).spawn(move || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| job(worker_cancelled)))
+            .unwrap_or_else(|_| {
+                Err(PhiError::InferenceError {
+                    error_text: "The inference worker thread panicked".to_string(),
+                })
+            });
+        complete(&worker_shared, result);
+    });
+    if let Err(e) = spawned {
+        complete(
+            &shared,
+            Err(PhiError::InferenceError {
+                error_text: format!("Error starting the inference worker thread: {}", e),
+            }),
+        );
+    }
+
+    WorkerFuture {
+        shared,
+        cancelled,
+        done: false,
+    }
+}
+
+fn complete<T>(shared: &Mutex<Shared<T>>, result: Result<T, PhiError>) {
+    // a poisoned lock means the waiting task is gone, there is nobody to hand the result to
+    if let Ok(mut shared) = shared.lock() {
+        shared.result = Some(result);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for WorkerFuture<T> {
+    type Output = Result<T, PhiError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut shared = match this.shared.lock() {
+            Ok(shared) => shared,
+            Err(e) => {
+                return Poll::Ready(Err(PhiError::LockingError {
+                    error_text: e.to_string(),
+                }))
+            }
+        };
+        match shared.result.take() {
+            Some(result) => {
+                this.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for WorkerFuture<T> {
+    fn drop(&mut self) {
+        if !self.done {
+            debug!(" --> Async inference was dropped before it completed, cancelling it");
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, no_context, ScriptedModel};
+
+    #[test]
+    fn dropping_the_future_stops_the_generation_before_the_next_forward_pass() {
+        // the third forward pass waits until the test has dropped the future
+        let (model, third_pass) = ScriptedModel::new(&["a"]).paused_at(3);
+        let calls = model.calls.clone();
+        let engine = test_util::scripted_engine(model);
+
+        let mut future = Box::pin(engine.clone().run_inference_async(
+            "hello".to_string(),
+            no_context(),
+            test_util::greedy_options(50),
+            None,
+        ));
+        assert!(future.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+        third_pass.wait();
+        drop(future);
+        third_pass.resume();
+
+        test_util::wait_for_workers(&engine);
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn async_inference_resolves_to_the_result_of_the_blocking_one() {
+        let engine = test_util::scripted_engine(ScriptedModel::new(&["▁hello", "▁a", "b", "<|end|>"]));
+        let mut options = test_util::greedy_options(10);
+        options.request_id = Some("request".to_string());
+
+        let expected = engine.run_inference("hello", &no_context(), &options, None).unwrap();
+        let actual = test_util::block_on(engine.clone().run_inference_async(
+            "hello".to_string(),
+            no_context(),
+            options,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(actual.request_id, expected.request_id);
+        assert_eq!(actual.result_text, "hello ab");
+        assert_eq!(actual.result_text, expected.result_text);
+        assert_eq!(actual.token_count, expected.token_count);
+        assert_eq!(actual.prompt_token_count, expected.prompt_token_count);
+        assert_eq!(actual.finish_reason, expected.finish_reason);
+    }
+}